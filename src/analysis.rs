@@ -0,0 +1,146 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Ui};
+
+use crate::terrain::TerrainSettings;
+
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum AnalysisKind {
+    Slope,
+    Aspect,
+    ProfileCurvature,
+    PlanCurvature
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct AnalysisSettings {
+    pub kind: AnalysisKind,
+    pub overlay: bool,
+    pub opacity: f32,
+    pub sixteen_bit: bool
+}
+
+
+impl AnalysisSettings {
+    pub fn default() -> Self {
+        Self {
+            kind: AnalysisKind::Slope,
+            overlay: false,
+            opacity: 0.6,
+            sixteen_bit: false
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal_wrapped(|ui| {
+            ui.radio_value(&mut self.kind, AnalysisKind::Slope, "Slope");
+            ui.radio_value(&mut self.kind, AnalysisKind::Aspect, "Aspect");
+            ui.radio_value(&mut self.kind, AnalysisKind::ProfileCurvature, "Profile Curv.");
+            ui.radio_value(&mut self.kind, AnalysisKind::PlanCurvature, "Plan Curv.");
+        });
+        ui.checkbox(&mut self.overlay, "Canvas Overlay");
+        ui.add(egui::Slider::new(&mut self.opacity, RangeInclusive::new(0.0, 1.0)).text("Opacity"));
+        ui.horizontal(|ui| {
+            ui.label("PNG Depth");
+            ui.radio_value(&mut self.sixteen_bit, false, "8 bit");
+            ui.radio_value(&mut self.sixteen_bit, true, "16 bit");
+        });
+    }
+}
+
+
+// Derived map over every pixel of the height drawing.
+// Slope and aspect are in degrees (aspect is a compass bearing of the downhill direction, north is the top of the canvas),
+// curvatures are in 1/m with positive values on convex terrain.
+pub fn compute(settings: &TerrainSettings, img: &ColorImage, kind: AnalysisKind) -> Vec<f32> {
+    let (w, h) = (img.width(), img.height());
+    let elevations = settings.elevations(img);
+    let at = |x: i64, y: i64| elevations[(y.clamp(0, h as i64 - 1) as usize) * w + x.clamp(0, w as i64 - 1) as usize];
+
+    let dx = settings.width / w as f32;
+    let dy = settings.length / h as f32;
+
+    let mut values = vec![0.0; w * h];
+    for y in 0..h as i64 {
+        for x in 0..w as i64 {
+            let z = at(x, y);
+            // first and second derivatives from the 3x3 neighbourhood, +y points south
+            let p = (at(x + 1, y) - at(x - 1, y)) / (2.0 * dx);
+            let q = (at(x, y + 1) - at(x, y - 1)) / (2.0 * dy);
+            let r = (at(x + 1, y) - 2.0 * z + at(x - 1, y)) / (dx * dx);
+            let t = (at(x, y + 1) - 2.0 * z + at(x, y - 1)) / (dy * dy);
+            let s = (at(x + 1, y + 1) - at(x - 1, y + 1) - at(x + 1, y - 1) + at(x - 1, y - 1)) / (4.0 * dx * dy);
+
+            let g2 = p * p + q * q;
+
+            values[y as usize * w + x as usize] = match kind {
+                AnalysisKind::Slope => g2.sqrt().atan().to_degrees(),
+                AnalysisKind::Aspect => {
+                    if g2 < 1e-12 {
+                        0.0
+                    } else {
+                        // downhill is -gradient, east is +x and north is -y
+                        (-p).atan2(q).to_degrees().rem_euclid(360.0)
+                    }
+                },
+                AnalysisKind::ProfileCurvature => {
+                    if g2 < 1e-12 { 0.0 } else { -(r * p * p + 2.0 * s * p * q + t * q * q) / (g2 * (1.0 + g2).powf(1.5)) }
+                },
+                AnalysisKind::PlanCurvature => {
+                    if g2 < 1e-12 { 0.0 } else { -(t * p * p - 2.0 * s * p * q + r * q * q) / g2.powf(1.5) }
+                },
+            };
+        }
+    }
+
+    values
+}
+
+
+// Maps values into [0, 1] for display and export. Curvature is centered on 0.5 and scaled by its largest magnitude
+pub fn normalize(kind: AnalysisKind, values: &[f32]) -> Vec<f32> {
+    match kind {
+        AnalysisKind::Slope => values.iter().map(|v| (v / 90.0).clamp(0.0, 1.0)).collect(),
+        AnalysisKind::Aspect => values.iter().map(|v| (v / 360.0).clamp(0.0, 1.0)).collect(),
+        AnalysisKind::ProfileCurvature | AnalysisKind::PlanCurvature => {
+            let max = values.iter().fold(0.0_f32, |m, v| m.max(v.abs())).max(1e-6);
+            values.iter().map(|v| (0.5 + 0.5 * v / max).clamp(0.0, 1.0)).collect()
+        },
+    }
+}
+
+
+pub fn false_color(kind: AnalysisKind, t: f32) -> Color32 {
+    match kind {
+        // flat green through yellow to steep red
+        AnalysisKind::Slope => {
+            if t < 0.5 {
+                Color32::from_rgb(40, 160, 60).lerp_to_gamma(Color32::from_rgb(240, 220, 60), t * 2.0)
+            } else {
+                Color32::from_rgb(240, 220, 60).lerp_to_gamma(Color32::from_rgb(200, 40, 30), (t - 0.5) * 2.0)
+            }
+        },
+        // cyclic hue wheel so north on both ends matches
+        AnalysisKind::Aspect => egui::ecolor::Hsva::new(t, 0.8, 0.9, 1.0).into(),
+        // diverging blue (concave) white red (convex)
+        AnalysisKind::ProfileCurvature | AnalysisKind::PlanCurvature => {
+            if t < 0.5 {
+                Color32::from_rgb(40, 80, 200).lerp_to_gamma(Color32::WHITE, t * 2.0)
+            } else {
+                Color32::WHITE.lerp_to_gamma(Color32::from_rgb(200, 40, 30), (t - 0.5) * 2.0)
+            }
+        },
+    }
+}
+
+
+pub fn analysis_image(settings: &TerrainSettings, img: &ColorImage, kind: AnalysisKind) -> ColorImage {
+    let normalized = normalize(kind, &compute(settings, img, kind));
+
+    ColorImage {
+        size: img.size,
+        pixels: normalized.iter().map(|t| false_color(kind, *t)).collect()
+    }
+}
@@ -0,0 +1,311 @@
+use std::{ops::RangeInclusive, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex}, thread::JoinHandle};
+
+use egui::{Color32, ColorImage, Ui};
+
+use crate::terrain::TerrainSettings;
+
+
+// One full image pass, gets the job's input, the previous pass' output and the texel to compute
+pub type Pass = Box<dyn Fn(&[f32], &[f32], usize, usize) -> f32 + Send + Sync>;
+
+
+// Per texel bake running on a background thread, rows are shared out to one worker per core
+pub struct BakeJob {
+    cancel: Arc<AtomicBool>,
+    rows_done: Arc<AtomicUsize>,
+    rows: usize,
+    handle: Option<JoinHandle<Option<Vec<f32>>>>
+}
+
+
+impl BakeJob {
+    pub fn spawn(size: [usize; 2], texel: impl Fn(usize, usize) -> f32 + Send + Sync + 'static) -> Self {
+        Self::spawn_passes(size, Vec::new(), vec![Box::new(move |_, _, x, y| texel(x, y))])
+    }
+
+    // Runs the passes one after the other, the first one sees input as the previous output
+    pub fn spawn_passes(size: [usize; 2], input: Vec<f32>, passes: Vec<Pass>) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let rows_done = Arc::new(AtomicUsize::new(0));
+        let (w, h) = (size[0], size[1]);
+        let rows = h * passes.len();
+
+        let handle = {
+            let cancel = cancel.clone();
+            let rows_done = rows_done.clone();
+            std::thread::spawn(move || {
+                let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+                let mut previous = input.clone();
+
+                for texel in passes.iter() {
+                    let values = Mutex::new(vec![0.0; w * h]);
+                    let next_row = AtomicUsize::new(0);
+
+                    std::thread::scope(|scope| {
+                        for _ in 0..workers {
+                            scope.spawn(|| {
+                                let mut row = vec![0.0; w];
+                                loop {
+                                    let y = next_row.fetch_add(1, Ordering::Relaxed);
+                                    if y >= h || cancel.load(Ordering::Relaxed) {
+                                        break;
+                                    }
+                                    for (x, v) in row.iter_mut().enumerate() {
+                                        *v = texel(&input, &previous, x, y);
+                                    }
+                                    values.lock().unwrap()[y * w..(y + 1) * w].copy_from_slice(&row);
+                                    rows_done.fetch_add(1, Ordering::Relaxed);
+                                }
+                            });
+                        }
+                    });
+                    previous = values.into_inner().unwrap();
+                }
+
+                (!cancel.load(Ordering::Relaxed)).then_some(previous)
+            })
+        };
+
+        Self {
+            cancel,
+            rows_done,
+            rows,
+            handle: Some(handle)
+        }
+    }
+
+    pub fn progress(&self) -> f32 {
+        self.rows_done.load(Ordering::Relaxed) as f32 / self.rows.max(1) as f32
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.as_ref().is_none_or(|h| h.is_finished())
+    }
+
+    // Result of a finished bake, None while running or if it was canceled
+    pub fn take_result(&mut self) -> Option<Vec<f32>> {
+        if !self.is_finished() {
+            return None;
+        }
+        self.handle.take()?.join().ok().flatten()
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct AoSettings {
+    pub directions: u32,
+    pub radius: f32,
+    pub strength: f32,
+    pub overlay: bool,
+    pub sixteen_bit: bool
+}
+
+
+impl AoSettings {
+    pub fn default() -> Self {
+        Self {
+            directions: 16,
+            radius: 2.0,
+            strength: 1.0,
+            overlay: false,
+            sixteen_bit: false
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new("AO Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Directions");
+            ui.add(egui::Slider::new(&mut self.directions, RangeInclusive::new(4, 64)));
+            ui.end_row();
+
+            ui.label("Radius");
+            ui.add(egui::DragValue::new(&mut self.radius).speed(0.05).range(RangeInclusive::new(0.01, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Strength");
+            ui.add(egui::Slider::new(&mut self.strength, RangeInclusive::new(0.0, 1.0)));
+            ui.end_row();
+        });
+        ui.checkbox(&mut self.overlay, "Canvas Overlay");
+        ui.horizontal(|ui| {
+            ui.label("PNG Depth");
+            ui.radio_value(&mut self.sixteen_bit, false, "8 bit");
+            ui.radio_value(&mut self.sixteen_bit, true, "16 bit");
+        });
+    }
+}
+
+
+// Bilinear lookup into a row major grid of elevations, clamped at the borders
+pub fn sample_grid(values: &[f32], w: usize, h: usize, x: f32, y: f32) -> f32 {
+    let x = x.clamp(0.0, w as f32 - 1.0);
+    let y = y.clamp(0.0, h as f32 - 1.0);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let top = values[y0 * w + x0] * (1.0 - fx) + values[y0 * w + x1] * fx;
+    let bottom = values[y1 * w + x0] * (1.0 - fx) + values[y1 * w + x1] * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+
+const HORIZON_SAMPLES: usize = 16;
+
+
+// Horizon based ambient occlusion, for every direction the highest elevation angle within the radius occludes
+// the sine of that angle. Returns the texel function for BakeJob, 1 is fully open sky.
+// Works on real elevations, the viewport exaggeration does not change the bake
+pub fn ambient_occlusion(settings: &TerrainSettings, img: &ColorImage, ao: AoSettings) -> impl Fn(usize, usize) -> f32 + Send + Sync + 'static {
+    let (w, h) = (img.width(), img.height());
+    let elevations = settings.elevations(img);
+    let (dx, dy) = (settings.width / w as f32, settings.length / h as f32);
+
+    let directions: Vec<(f32, f32)> = (0..ao.directions).map(|i| {
+        let a = i as f32 / ao.directions as f32 * std::f32::consts::TAU;
+        (a.cos(), a.sin())
+    }).collect();
+
+    move |x, y| {
+        let z0 = elevations[y * w + x];
+        let mut occlusion = 0.0;
+
+        for (cx, cy) in directions.iter() {
+            let mut max_tan = 0.0_f32;
+            for k in 0..HORIZON_SAMPLES {
+                // samples bunch up near the texel where small features matter most
+                let t = (k + 1) as f32 / HORIZON_SAMPLES as f32;
+                let d = (ao.radius * t * t).max(dx.min(dy));
+                let (px, py) = (x as f32 + cx * d / dx, y as f32 + cy * d / dy);
+                if px < 0.0 || py < 0.0 || px > (w - 1) as f32 || py > (h - 1) as f32 {
+                    break;
+                }
+                max_tan = max_tan.max((sample_grid(&elevations, w, h, px, py) - z0) / d);
+            }
+            occlusion += max_tan.atan().sin();
+        }
+
+        1.0 - ao.strength * occlusion / directions.len().max(1) as f32
+    }
+}
+
+
+pub fn grayscale_image(size: [usize; 2], values: &[f32]) -> ColorImage {
+    ColorImage {
+        size,
+        pixels: values.iter().map(|v| Color32::from_gray((v.clamp(0.0, 1.0) * 255.0) as u8)).collect()
+    }
+}
+
+
+// Multiplies a baked map into a color image of any size, alpha is left alone
+pub fn multiply_into(img: &mut ColorImage, size: [usize; 2], values: &[f32]) {
+    let (w, h) = (img.width(), img.height());
+    for y in 0..h {
+        for x in 0..w {
+            let v = values[(y * size[1] / h) * size[0] + x * size[0] / w].clamp(0.0, 1.0);
+            let col = img.pixels[y * w + x];
+            img.pixels[y * w + x] = Color32::from_rgba_unmultiplied(
+                (col.r() as f32 * v) as u8,
+                (col.g() as f32 * v) as u8,
+                (col.b() as f32 * v) as u8,
+                col.a()
+            );
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct LightmapSettings {
+    // compass bearing of the sun, north is the top of the canvas
+    pub azimuth: f32,
+    pub elevation: f32,
+    pub ambient: f32,
+    pub use_ao: bool,
+    pub sixteen_bit: bool
+}
+
+
+impl LightmapSettings {
+    pub fn default() -> Self {
+        Self {
+            azimuth: 135.0,
+            elevation: 35.0,
+            ambient: 0.25,
+            use_ao: true,
+            sixteen_bit: false
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new("Lightmap Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Sun Azimuth");
+            ui.add(egui::Slider::new(&mut self.azimuth, RangeInclusive::new(0.0, 360.0)).suffix("°"));
+            ui.end_row();
+
+            ui.label("Sun Elevation");
+            ui.add(egui::Slider::new(&mut self.elevation, RangeInclusive::new(1.0, 90.0)).suffix("°"));
+            ui.end_row();
+
+            ui.label("Ambient");
+            ui.add(egui::Slider::new(&mut self.ambient, RangeInclusive::new(0.0, 1.0)));
+            ui.end_row();
+        });
+        ui.checkbox(&mut self.use_ao, "Multiply Baked AO");
+        ui.horizontal(|ui| {
+            ui.label("PNG Depth");
+            ui.radio_value(&mut self.sixteen_bit, false, "8 bit");
+            ui.radio_value(&mut self.sixteen_bit, true, "16 bit");
+        });
+    }
+}
+
+
+// Lambert term times a cast shadow found by marching from the texel towards the sun, on top of an ambient floor.
+// ao has to match the size of img when given
+pub fn lightmap(settings: &TerrainSettings, img: &ColorImage, light: LightmapSettings, ao: Option<Vec<f32>>) -> impl Fn(usize, usize) -> f32 + Send + Sync + 'static {
+    let (w, h) = (img.width(), img.height());
+    let elevations = settings.elevations(img);
+    let (dx, dy) = (settings.width / w as f32, settings.length / h as f32);
+    let top = elevations.iter().fold(f32::MIN, |m, z| m.max(*z));
+
+    let (az, el) = (light.azimuth.to_radians(), light.elevation.to_radians());
+    // +x is east and +y is south on the canvas
+    let sun = (az.sin() * el.cos(), -az.cos() * el.cos(), el.sin());
+
+    let step = dx.min(dy);
+    let (step_x, step_y) = (az.sin() * step / dx, -az.cos() * step / dy);
+    let rise = el.tan() * step;
+
+    move |x, y| {
+        let at = |x: usize, y: usize| elevations[y * w + x];
+        let z0 = at(x, y);
+
+        let p = (at((x + 1).min(w - 1), y) - at(x.saturating_sub(1), y)) / (((x + 1).min(w - 1) - x.saturating_sub(1)) as f32 * dx);
+        let q = (at(x, (y + 1).min(h - 1)) - at(x, y.saturating_sub(1))) / (((y + 1).min(h - 1) - y.saturating_sub(1)) as f32 * dy);
+        let n = (-p, -q, 1.0);
+        let lambert = ((n.0 * sun.0 + n.1 * sun.1 + n.2 * sun.2) / (n.0 * n.0 + n.1 * n.1 + 1.0).sqrt()).max(0.0);
+
+        let mut lit = lambert > 0.0;
+        let (mut px, mut py, mut z) = (x as f32, y as f32, z0 + 0.01 * step);
+        while lit {
+            px += step_x;
+            py += step_y;
+            z += rise;
+            if z > top || px < 0.0 || py < 0.0 || px > (w - 1) as f32 || py > (h - 1) as f32 {
+                break;
+            }
+            lit = sample_grid(&elevations, w, h, px, py) <= z;
+        }
+
+        let direct = if lit { lambert } else { 0.0 };
+        let occlusion = ao.as_ref().map_or(1.0, |ao| ao[y * w + x]);
+        (light.ambient + (1.0 - light.ambient) * direct) * occlusion
+    }
+}
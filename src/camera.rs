@@ -1,11 +1,12 @@
-use nalgebra::{Matrix4, Orthographic3, Perspective3, Vector3};
+use nalgebra::{Matrix4, Orthographic3, Perspective3, Vector2, Vector3, Vector4};
 
 pub struct Camera {
     pub pos : Vector3<f32>,
     pub look : Vector3<f32>,
     pub right: Vector3<f32>,
     fov : f32,
-    pub aspect_ratio : f32
+    pub aspect_ratio : f32,
+    pub far : f32
 }   
 
 
@@ -16,7 +17,8 @@ impl Camera{
             look,
             right,
             fov,
-            aspect_ratio
+            aspect_ratio,
+            far: 100.0
         }
     }
 
@@ -35,8 +37,8 @@ impl Camera{
     }
 
     pub fn get_proj_view_mat(&self) -> Matrix4<f32> {
-        let persp = Perspective3::new(self.aspect_ratio, self.fov, 0.1, 100.0).to_homogeneous();
-        let _ortho = Orthographic3::from_fov(self.aspect_ratio, self.fov, 0.1, 100.0).to_homogeneous();
+        let persp = Perspective3::new(self.aspect_ratio, self.fov, 0.1, self.far).to_homogeneous();
+        let _ortho = Orthographic3::from_fov(self.aspect_ratio, self.fov, 0.1, self.far).to_homogeneous();
 
         let up = self.get_up_vec();
 
@@ -56,4 +58,25 @@ impl Camera{
 
         persp * (view_orient * view_translate)
     }
-}
\ No newline at end of file
+
+    // World space ray through a point in normalized device coordinates
+    pub fn screen_ray(&self, ndc: Vector2<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let inv = self.get_proj_view_mat().try_inverse().unwrap_or(Matrix4::identity());
+
+        let near = inv * Vector4::new(ndc.x, ndc.y, -1.0, 1.0);
+        let far = inv * Vector4::new(ndc.x, ndc.y, 1.0, 1.0);
+        let near = near.xyz() / near.w;
+        let far = far.xyz() / far.w;
+
+        (near, (far - near).normalize())
+    }
+
+    // Normalized device coordinates of a world position, None if it is behind the camera
+    pub fn project(&self, pos: Vector3<f32>) -> Option<Vector2<f32>> {
+        let clip = self.get_proj_view_mat() * Vector4::new(pos.x, pos.y, pos.z, 1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        Some(Vector2::new(clip.x / clip.w, clip.y / clip.w))
+    }
+}
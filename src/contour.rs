@@ -0,0 +1,259 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use egui::{Color32, ColorImage, Painter, Pos2, Rect, Stroke, Ui};
+use nalgebra::Vector2;
+
+use crate::terrain::TerrainSettings;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct ContourSettings {
+    pub enabled: bool,
+    pub interval: f32,
+    pub color: Color32
+}
+
+
+impl ContourSettings {
+    pub fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 0.25,
+            color: Color32::from_rgb(90, 50, 20)
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.enabled, "Show Contours");
+
+        egui::Grid::new("Contour Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Interval");
+            ui.add(egui::DragValue::new(&mut self.interval).speed(0.01).range(RangeInclusive::new(0.001, 100000.0)).suffix(" m"))
+                .on_hover_text(format!("At most {MAX_LEVELS} levels are drawn, finer intervals are raised"));
+            ui.end_row();
+
+            ui.label("Color");
+            ui.color_edit_button_srgba(&mut self.color);
+            ui.end_row();
+        });
+    }
+}
+
+
+// Finer intervals are raised to this many levels over the height range, every level costs a pass over the map
+pub const MAX_LEVELS: f32 = 1000.0;
+
+
+// Isoline at one elevation, points are drawing uvs through pixel centers
+pub struct Contour {
+    pub elevation: f32,
+    pub points: Vec<Vector2<f32>>
+}
+
+
+// Crossing points are keyed by the cell edge they lie on: (x, y, 0) is the horizontal
+// edge right of pixel (x, y), (x, y, 1) the vertical edge below it
+type EdgeKey = (usize, usize, u8);
+
+
+// Marching squares over the pixel centers of img, one pass for all levels
+pub fn extract_contours(settings: &TerrainSettings, img: &ColorImage, interval: f32) -> Vec<Contour> {
+    let (w, h) = (img.width(), img.height());
+    if interval <= 0.0 || w < 2 || h < 2 {
+        return Vec::new();
+    }
+
+    let elevations = settings.elevations(img);
+    let (lo, hi) = elevations.iter().fold((f32::MAX, f32::MIN), |(lo, hi), e| (lo.min(*e), hi.max(*e)));
+    let interval = interval.max((hi - lo) / MAX_LEVELS);
+    let at = |x: usize, y: usize| elevations[y * w + x];
+    let uv = |x: f32, y: f32| Vector2::new((x + 0.5) / w as f32, (y + 0.5) / h as f32);
+
+    // segments per level index
+    let mut levels: HashMap<i64, Vec<(EdgeKey, EdgeKey)>> = HashMap::new();
+    let mut points: HashMap<(i64, EdgeKey), Vector2<f32>> = HashMap::new();
+
+    for y in 0..h - 1 {
+        for x in 0..w - 1 {
+            let (a, b, c, d) = (at(x, y), at(x + 1, y), at(x + 1, y + 1), at(x, y + 1));
+            let lo = a.min(b).min(c).min(d);
+            let hi = a.max(b).max(c).max(d);
+
+            let first = (lo / interval).ceil() as i64;
+            let last = (hi / interval).floor() as i64;
+
+            for level in first..=last {
+                let iso = level as f32 * interval;
+                if iso <= lo || iso > hi {
+                    continue;
+                }
+
+                let case = (a >= iso) as u8 | ((b >= iso) as u8) << 1 | ((c >= iso) as u8) << 2 | ((d >= iso) as u8) << 3;
+
+                let top = (x, y, 0);
+                let right = (x + 1, y, 1);
+                let bottom = (x, y + 1, 0);
+                let left = (x, y, 1);
+
+                let mut crossing = |key: EdgeKey| {
+                    points.entry((level, key)).or_insert_with(|| {
+                        let (x0, y0) = (key.0 as f32, key.1 as f32);
+                        if key.2 == 0 {
+                            let (v0, v1) = (at(key.0, key.1), at(key.0 + 1, key.1));
+                            uv(x0 + (iso - v0) / (v1 - v0), y0)
+                        } else {
+                            let (v0, v1) = (at(key.0, key.1), at(key.0, key.1 + 1));
+                            uv(x0, y0 + (iso - v0) / (v1 - v0))
+                        }
+                    });
+                    key
+                };
+
+                let center_above = (a + b + c + d) / 4.0 >= iso;
+                let segments: &[(EdgeKey, EdgeKey)] = match case {
+                    1 | 14 => &[(left, top)],
+                    2 | 13 => &[(top, right)],
+                    3 | 12 => &[(left, right)],
+                    4 | 11 => &[(right, bottom)],
+                    6 | 9 => &[(top, bottom)],
+                    7 | 8 => &[(left, bottom)],
+                    // saddles are resolved by the cell average
+                    5 => if center_above { &[(left, bottom), (top, right)] } else { &[(left, top), (right, bottom)] },
+                    10 => if center_above { &[(left, top), (right, bottom)] } else { &[(left, bottom), (top, right)] },
+                    _ => &[],
+                };
+
+                for (p, q) in segments {
+                    levels.entry(level).or_default().push((crossing(*p), crossing(*q)));
+                }
+            }
+        }
+    }
+
+    let mut contours = Vec::new();
+    for (level, segments) in levels {
+        for chain in chain_segments(&segments) {
+            contours.push(Contour {
+                elevation: level as f32 * interval,
+                points: chain.iter().map(|key| points[&(level, *key)]).collect()
+            });
+        }
+    }
+
+    contours
+}
+
+
+// Joins segments that share an edge crossing into polylines, closed loops repeat their first point
+fn chain_segments(segments: &[(EdgeKey, EdgeKey)]) -> Vec<Vec<EdgeKey>> {
+    let mut adjacency: HashMap<EdgeKey, Vec<usize>> = HashMap::new();
+    for (i, (p, q)) in segments.iter().enumerate() {
+        adjacency.entry(*p).or_default().push(i);
+        adjacency.entry(*q).or_default().push(i);
+    }
+
+    let mut used = vec![false; segments.len()];
+    let mut chains = Vec::new();
+
+    let walk = |start: usize, from: EdgeKey, used: &mut Vec<bool>| {
+        let mut chain = vec![from];
+        let mut current = start;
+        let mut at = from;
+        loop {
+            used[current] = true;
+            let (p, q) = segments[current];
+            at = if p == at { q } else { p };
+            chain.push(at);
+
+            match adjacency[&at].iter().find(|i| !used[**i]) {
+                Some(next) => current = *next,
+                None => break,
+            }
+        }
+        chain
+    };
+
+    // open polylines first, starting from crossings that only have one segment (image border)
+    for (key, segs) in adjacency.iter() {
+        if segs.len() == 1 && !used[segs[0]] {
+            chains.push(walk(segs[0], *key, &mut used));
+        }
+    }
+    for i in 0..segments.len() {
+        if !used[i] {
+            chains.push(walk(i, segments[i].0, &mut used));
+        }
+    }
+
+    chains
+}
+
+
+// Overlay for the 2D canvas
+pub fn paint_contours(painter: &Painter, rect: Rect, contours: &[Contour], color: Color32) {
+    for contour in contours {
+        let points: Vec<Pos2> = contour.points.iter().map(|p| rect.min + egui::vec2(p.x * rect.width(), p.y * rect.height())).collect();
+        painter.add(egui::Shape::line(points, Stroke::new(1.0, color)));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drawing::height_to_col;
+
+    // default terrain, heights of 0 - 1 span 0 - 4 m
+    fn image(w: usize, h: usize, height: impl Fn(usize, usize) -> f32) -> ColorImage {
+        ColorImage {
+            size: [w, h],
+            pixels: (0..w * h).map(|i| height_to_col(height(i % w, i / w))).collect()
+        }
+    }
+
+    #[test]
+    fn ramp_gives_one_straight_line_per_level() {
+        let img = image(9, 5, |x, _| 0.9 * x as f32 / 8.0);
+        let mut contours = extract_contours(&TerrainSettings::default(), &img, 1.0);
+        contours.sort_by(|a, b| a.elevation.total_cmp(&b.elevation));
+
+        assert_eq!(contours.iter().map(|c| c.elevation).collect::<Vec<_>>(), vec![1.0, 2.0, 3.0]);
+        for contour in contours {
+            // crosses every row at the column where the ramp reaches the level
+            let x = (contour.elevation / 4.0 / 0.9 * 8.0 + 0.5) / 9.0;
+            assert_eq!(contour.points.len(), 5);
+            assert!(contour.points.iter().all(|p| (p.x - x).abs() < 0.01));
+            let (top, bottom) = (contour.points[0].y.min(contour.points[4].y), contour.points[0].y.max(contour.points[4].y));
+            assert!((top - 0.1).abs() < 1e-5 && (bottom - 0.9).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn hill_gives_closed_rings() {
+        let img = image(9, 9, |x, y| 0.8 - 0.1 * x.abs_diff(4).max(y.abs_diff(4)) as f32);
+        let contours = extract_contours(&TerrainSettings::default(), &img, 1.0);
+
+        assert_eq!(contours.len(), 2);
+        for contour in contours {
+            assert_eq!(contour.points.first(), contour.points.last());
+            // the ring sits where the hill crosses the level, the same distance out on every side
+            let d = (3.2 - contour.elevation) / 0.4;
+            let center = Vector2::new(0.5, 0.5);
+            assert!(contour.points.iter().all(|p| ((p - center).abs().max() * 9.0 - d).abs() < 0.05));
+        }
+    }
+
+    #[test]
+    fn fine_intervals_are_capped() {
+        let img = image(64, 2, |x, _| x as f32 / 63.0);
+        let contours = extract_contours(&TerrainSettings::default(), &img, 0.001);
+        assert!(!contours.is_empty() && contours.len() <= MAX_LEVELS as usize);
+    }
+
+    #[test]
+    fn flat_ground_and_no_interval_give_nothing() {
+        let terrain = TerrainSettings::default();
+        assert!(extract_contours(&terrain, &image(4, 4, |_, _| 0.5), 1.0).is_empty());
+        assert!(extract_contours(&terrain, &image(4, 4, |x, _| x as f32 / 3.0), 0.0).is_empty());
+    }
+}
@@ -1,117 +1,305 @@
-use core::{panic, prelude::v1};
+use core::panic;
 
-use egui::{load::SizedTexture, pos2, vec2, Color32, ColorImage, Image, Rect, Response, Ui};
-use nalgebra::{Vector2, Vector3, Vector4, U32};
+use egui::{load::SizedTexture, vec2, Color32, ColorImage, Image, Rect, Response, TextureHandle, TextureId, Ui};
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use crate::mask::Mask;
+
+// Inclusive pixel bounds of a region that changed since it was last consumed
+#[derive(Clone, Copy, Debug)]
+pub struct DirtyRect {
+    pub min_x: usize,
+    pub min_y: usize,
+    pub max_x: usize,
+    pub max_y: usize
+}
+
+impl DirtyRect {
+    pub fn union(self, other: DirtyRect) -> DirtyRect {
+        DirtyRect {
+            min_x: self.min_x.min(other.min_x),
+            min_y: self.min_y.min(other.min_y),
+            max_x: self.max_x.max(other.max_x),
+            max_y: self.max_y.max(other.max_y)
+        }
+    }
+
+    // Expands by n pixels on every side, clamped to an image of the given size
+    pub fn grow(self, n: usize, size: [usize; 2]) -> DirtyRect {
+        DirtyRect {
+            min_x: self.min_x.saturating_sub(n),
+            min_y: self.min_y.saturating_sub(n),
+            max_x: (self.max_x + n).min(size[0] - 1),
+            max_y: (self.max_y + n).min(size[1] - 1)
+        }
+    }
+}
+
+// Brush radius in drawing pixels
+pub const BRUSH_RADIUS: usize = 12;
 
 pub struct Drawing {
-    pub texture: ColorImage
+    pub texture: ColorImage,
+    dirty: Option<DirtyRect>,
+    handle: Option<TextureHandle>,
+    handle_stale: bool
 }
 
 
 
 impl Drawing {
-    pub fn draw(&self, ui: &mut Ui, ctx: &egui::Context) -> Response {
-        let img = &self.texture;
-
-        let tex = ctx.load_texture("Image", img.clone(), egui::TextureOptions::default());
-        let x : SizedTexture = (&tex).into();
+    pub fn draw(&mut self, ui: &mut Ui, ctx: &egui::Context) -> Response {
+        let tex = self.texture_handle(ctx);
+        let x : SizedTexture = tex.into();
         let img = Image::from_texture(x);
         ui.add(img)
     }
 
-    pub fn get_image(&self) -> ColorImage {
-        return self.texture.clone();
+    pub fn texture_id(&mut self, ctx: &egui::Context) -> TextureId {
+        self.texture_handle(ctx).id()
     }
 
+    // keep one egui texture alive and only re-upload it when the pixels changed
+    fn texture_handle(&mut self, ctx: &egui::Context) -> &TextureHandle {
+        match &mut self.handle {
+            Some(handle) => {
+                if self.handle_stale {
+                    handle.set(self.texture.clone(), egui::TextureOptions::default());
+                }
+            },
+            None => self.handle = Some(ctx.load_texture("Image", self.texture.clone(), egui::TextureOptions::default())),
+        }
+        self.handle_stale = false;
 
-    pub fn draw_update(&mut self, ctx: &egui::Context, img_rect: Rect) {
-        if ctx.input(|i| i.pointer.button_down(egui::PointerButton::Primary) && (i.pointer.delta().length() > 0.1 || i.pointer.press_start_time() == Some(0.0)) && img_rect.contains(i.pointer.interact_pos().unwrap())) {
-            let mouse_pos = (ctx.pointer_interact_pos().unwrap() - img_rect.left_top()) / vec2(img_rect.width(), img_rect.height());
-            // println!("{}", mouse_pos);
-            let pixel_pos = Vector2::<usize>::new((mouse_pos.x * 512.0) as usize, (mouse_pos.y * 512.0) as usize);
+        self.handle.as_ref().unwrap()
+    }
 
-            self.add_radius(pixel_pos, 12);
+    pub fn get_image(&self) -> ColorImage {
+        self.texture.clone()
+    }
+
+    pub fn set_image(&mut self, img: ColorImage) {
+        self.texture = img;
+        self.mark_dirty(DirtyRect { min_x: 0, min_y: 0, max_x: self.texture.width() - 1, max_y: self.texture.height() - 1 });
+    }
+
+    // Writes a region sized image into region
+    pub fn set_region(&mut self, img: &ColorImage, region: DirtyRect) {
+        let w = self.texture.width();
+        let rw = img.width();
+        for (row, y) in (region.min_y..=region.max_y).enumerate() {
+            self.texture.pixels[y * w + region.min_x..=y * w + region.max_x].copy_from_slice(&img.pixels[row * rw..(row + 1) * rw]);
         }
+        self.mark_dirty(region);
+    }
+
+    pub fn mark_dirty(&mut self, rect: DirtyRect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+        self.handle_stale = true;
     }
 
-    pub fn draw_update_color(&mut self, ctx: &egui::Context, img_rect: Rect, color: Color32) {
-        if ctx.input(|i| i.pointer.button_down(egui::PointerButton::Primary) && (i.pointer.delta().length() > 0.1 || i.pointer.press_start_time() == Some(0.0)) && img_rect.contains(i.pointer.interact_pos().unwrap())) {
-            let mouse_pos = (ctx.pointer_interact_pos().unwrap() - img_rect.left_top()) / vec2(img_rect.width(), img_rect.height());
-            // println!("{}", mouse_pos);
-            let pixel_pos = Vector2::<usize>::new((mouse_pos.x * 512.0) as usize, (mouse_pos.y * 512.0) as usize);
+    // Returns the region painted since the last call
+    pub fn take_dirty(&mut self) -> Option<DirtyRect> {
+        self.dirty.take()
+    }
+
+
+    pub fn draw_update(&mut self, ctx: &egui::Context, img_rect: Rect, mask: Option<&Mask>) {
+        if let Some(uv) = brush_uv(ctx, img_rect) {
+            self.add_radius(self.uv_pixel(uv), BRUSH_RADIUS, mask);
+        }
+    }
 
-            self.add_radius_color(pixel_pos, 12, color);
+    pub fn draw_update_color(&mut self, ctx: &egui::Context, img_rect: Rect, color: Color32, mask: Option<&Mask>) {
+        if let Some(uv) = brush_uv(ctx, img_rect) {
+            self.add_radius_color(self.uv_pixel(uv), BRUSH_RADIUS, color, mask);
         }
     }
 
+    fn uv_pixel(&self, uv: Vector2<f32>) -> Vector2<usize> {
+        Vector2::new((uv.x * self.texture.width() as f32) as usize, (uv.y * self.texture.height() as f32) as usize)
+    }
+
 
     pub fn new() -> Self {
         Self {
-            texture: egui::ColorImage::new([512, 512], Color32::BLACK)
+            texture: egui::ColorImage::new([512, 512], Color32::BLACK),
             // texture: colorimage_from_image("ur mom")
+            dirty: None,
+            handle: None,
+            handle_stale: false
         }
     }
 
 
-    fn add_box(&mut self, pos: Vector2<usize>, radius: usize) {
-        let min_x = ((pos.x as i32) - (radius as i32)).max(0) as usize;
-        let min_y = ((pos.y as i32) - (radius as i32)).max(0) as usize;
-        let max_x = ((pos.x as i32) + (radius as i32)).min(511) as usize;
-        let max_y = ((pos.y as i32) + (radius as i32)).min(511) as usize;
+    pub fn add_radius(&mut self, pos: Vector2<usize>, radius: usize, mask: Option<&Mask>) {
+        self.add_radius_color(pos, radius, Color32::from_rgb(26, 26, 26), mask);
+    }
 
-        for x in min_x..=max_x {
-            for y in min_y..=max_y {
-                self.texture.pixels[y * 512 + x] = Color32::RED;
+    // The mask scales how much of color is added at every pixel
+    pub fn add_radius_color(&mut self, pos: Vector2<usize>, radius: usize, color: Color32, mask: Option<&Mask>) {
+        self.dab(pos, radius, mask, false, |_, _, strength, mut v| {
+            let (dx, dy, dz) = (strength * color.r() as f32 / 255.0, strength * color.g() as f32 / 255.0, strength * color.b() as f32 / 255.0);
+
+            v.x += dx;
+            v.x = v.x.clamp(0.0, 1.0);
+            
+            v.y += dy;
+            v.y = v.y.clamp(0.0, 1.0);
+            
+            v.z += dz;
+            v.z = v.z.clamp(0.0, 1.0);
+
+            // painting makes transparent layer pixels visible
+            v.w = v.w.max(strength.min(1.0));
+            v
+        });
+    }
+
+    // Copies heights from source at pixel offset, blended with the brush falloff. Uncovered source pixels copy nothing
+    pub fn clone_radius_height(&mut self, pos: Vector2<usize>, radius: usize, source: &ColorImage, offset: Vector2<i64>, blend: HeightBlend, mask: Option<&Mask>) {
+        self.dab(pos, radius, mask, true, |x, y, weight, v| {
+            let Some(src) = offset_pixel(source, x, y, offset) else {
+                return v;
+            };
+            let weight = weight * src.a() as f32 / 255.0;
+            if weight <= 0.0 {
+                return v;
             }
-        }
-    } 
+            let h = blend.apply((v.x + v.y + v.z) / 3.0, layer_height(src), weight);
+            let mut out = col_to_vec4(height_to_col(h));
+            out.w = v.w.max(weight.min(1.0));
+            out
+        });
+    }
 
-    fn add_radius(&mut self, pos: Vector2<usize>, radius: usize) {
-        self.add_radius_color(pos, radius, Color32::from_rgb(26, 26, 26));
+    pub fn clone_radius_color(&mut self, pos: Vector2<usize>, radius: usize, source: &ColorImage, offset: Vector2<i64>, mask: Option<&Mask>) {
+        self.dab(pos, radius, mask, true, |x, y, weight, v| {
+            match offset_pixel(source, x, y, offset) {
+                Some(src) => v.lerp(&col_to_vec4(src), weight),
+                None => v,
+            }
+        });
     }
 
-    fn add_radius_color(&mut self, pos: Vector2<usize>, radius: usize, color: Color32) {
+    // Runs f on every pixel of the disc around pos with the brush weight, the mask scales the weight.
+    // Without falloff the disc is hard edged like the original brush
+    fn dab(&mut self, pos: Vector2<usize>, radius: usize, mask: Option<&Mask>, falloff: bool, mut f: impl FnMut(usize, usize, f32, Vector4<f32>) -> Vector4<f32>) {
         let min_x = ((pos.x as i32) - (radius as i32)).max(0) as usize;
         let min_y = ((pos.y as i32) - (radius as i32)).max(0) as usize;
-        let max_x = ((pos.x as i32) + (radius as i32)).min(511) as usize;
-        let max_y = ((pos.y as i32) + (radius as i32)).min(511) as usize;
+        let max_x = ((pos.x as i32) + (radius as i32)).min(self.texture.width() as i32 - 1) as usize;
+        let max_y = ((pos.y as i32) + (radius as i32)).min(self.texture.height() as i32 - 1) as usize;
+        let w = self.texture.width();
 
         for x in min_x..=max_x {
             for y in min_y..=max_y {
                 let dx = ((x as f32) - (pos.x as f32)).abs();
                 let dy = ((y as f32) - (pos.y as f32)).abs();
 
-                if (dx.powf(2.0) + dy.powf(2.0)).sqrt() > (radius as f32) {
+                let d = (dx.powf(2.0) + dy.powf(2.0)).sqrt();
+                if d > (radius as f32) {
                     continue;
                 }
-                let mut v = col_to_vec4(self.texture.pixels[y * 512 + x]);
+                let v = col_to_vec4(self.texture.pixels[y * w + x]);
 
-                let (dx, dy, dz) = (color.r() as f32 / 255.0, color.g() as f32 / 255.0, color.b() as f32 / 255.0);
+                let mut strength = mask.map_or(1.0, |mask| mask.at(x, y, self.texture.size));
+                if falloff {
+                    strength *= 1.0 - (d / radius.max(1) as f32).powi(2);
+                }
 
-                // println!("{}, {}, {}", dx, dy, dz);
+                self.texture.pixels[y * w + x] = vec4_to_col(f(x, y, strength, v));
+            }
+        }
 
-                v.x += dx;
-                v.x = v.x.clamp(0.0, 1.0);
-                
-                v.y += dy;
-                v.y = v.y.clamp(0.0, 1.0);
-                
-                v.z += dz;
-                v.z = v.z.clamp(0.0, 1.0);
+        self.mark_dirty(DirtyRect { min_x, min_y, max_x, max_y });
+    }
+}
 
-                self.texture.pixels[y * 512 + x] = vec4_to_col(v);
-            }
+
+// How tools that write heights (clone brush, stamps) combine them with the ones underneath
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeightBlend {
+    Replace,
+    Add,
+    Max,
+    Min
+}
+
+
+impl HeightBlend {
+    pub const ALL: [HeightBlend; 4] = [HeightBlend::Replace, HeightBlend::Add, HeightBlend::Max, HeightBlend::Min];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeightBlend::Replace => "Replace",
+            HeightBlend::Add => "Add",
+            HeightBlend::Max => "Max",
+            HeightBlend::Min => "Min",
+        }
+    }
+
+    // New height from the current one and the tool's, weight is the brush or stamp coverage
+    pub fn apply(&self, h: f32, top: f32, weight: f32) -> f32 {
+        let mixed = h + (top - h) * weight;
+        match self {
+            HeightBlend::Replace => mixed,
+            HeightBlend::Add => h + top * weight,
+            HeightBlend::Max => h.max(mixed),
+            HeightBlend::Min => h.min(mixed),
         }
     }
 }
 
 
+fn offset_pixel(source: &ColorImage, x: usize, y: usize, offset: Vector2<i64>) -> Option<Color32> {
+    let (sx, sy) = (x as i64 + offset.x, y as i64 + offset.y);
+    if sx < 0 || sy < 0 || sx >= source.width() as i64 || sy >= source.height() as i64 {
+        return None;
+    }
+    Some(source.pixels[sy as usize * source.width() + sx as usize])
+}
+
+
 pub fn col_to_vec4(col: Color32) -> Vector4<f32> {
-    let col : Vector4<u8> = [col.r(), col.g(), col.b(), col.a()].into();
+    let col : Vector4<u8> = col.to_srgba_unmultiplied().into();
     let col :  Vector4<f32> = col.map(|x| (x as f32) / 255.0);
     col
 }
 
+// Uv under the pointer while the primary button paints inside img_rect
+pub fn brush_uv(ctx: &egui::Context, img_rect: Rect) -> Option<Vector2<f32>> {
+    if ctx.input(|i| i.pointer.button_down(egui::PointerButton::Primary) && (i.pointer.delta().length() > 0.1 || i.pointer.press_start_time() == Some(0.0)) && img_rect.contains(i.pointer.interact_pos().unwrap())) {
+        let mouse_pos = (ctx.pointer_interact_pos().unwrap() - img_rect.left_top()) / vec2(img_rect.width(), img_rect.height());
+        return Some(Vector2::new(mouse_pos.x, mouse_pos.y));
+    }
+    None
+}
+
+// Normalized height in [0, 1] stored in a grayscale pixel
+pub fn col_to_height(col: Color32) -> f32 {
+    (col.r() as f32 + col.g() as f32 + col.b() as f32) / (3.0 * 255.0)
+}
+
+// col_to_height for translucent layer pixels, Color32 keeps its channels premultiplied
+pub fn layer_height(col: Color32) -> f32 {
+    let [r, g, b, _] = col.to_srgba_unmultiplied();
+    col_to_height(Color32::from_rgb(r, g, b))
+}
+
+// Inverse of col_to_height, spreads the value over the three channels for 765 levels instead of 256
+pub fn height_to_col(h: f32) -> Color32 {
+    let total = (h.clamp(0.0, 1.0) * 765.0).round() as u32;
+    let (base, rest) = (total / 3, total % 3);
+    Color32::from_rgb((base + (rest > 0) as u32) as u8, (base + (rest > 1) as u32) as u8, base as u8)
+}
+
+pub fn with_alpha(col: Color32, alpha: f32) -> Color32 {
+    Color32::from_rgba_unmultiplied(col.r(), col.g(), col.b(), (alpha.clamp(0.0, 1.0) * 255.0) as u8)
+}
+
 pub fn vec4_to_col(vec: Vector4<f32>) -> Color32 {
     vec.iter().for_each(|f| {
         if *f > 1.0 || *f < 0.0 {
@@ -119,63 +307,63 @@ pub fn vec4_to_col(vec: Vector4<f32>) -> Color32 {
         }
     });
     let col = vec.map(|x| (x * 255.0) as u8);
-    return Color32::from_rgba_unmultiplied(col.x, col.y, col.z, col.w);
+    Color32::from_rgba_unmultiplied(col.x, col.y, col.z, col.w)
 }
 
 
 
 
+// Cropped canvases are not square, each axis is scaled on its own
 pub fn bicubic_downsize(img: ColorImage, target_size: usize) -> ColorImage{
-    if img.size[0] != img.size[1] {
-        panic!("Supplied image is not square");
-    }
     // if img.size[0] < target_size {
     //     panic!("Attempting to upscale image, illegal");
     // }
 
-    let og_size = img.size[0];
-    let scale = (og_size as f32) / (target_size as f32);
+    let scale_x = (img.size[0] as f32) / (target_size as f32);
+    let scale_y = (img.size[1] as f32) / (target_size as f32);
 
     let mut new_image = ColorImage::new([target_size, target_size], Color32::BLACK);
 
     for y in 0..target_size {
         for x in 0..target_size {
-            let x = x as f32;
-            let y = y as f32;
+            let col = bicubic_sample(&img, x as f32 * scale_x, y as f32 * scale_y);
 
-            let src_x = x * scale;
-            let src_y = y * scale;
+            new_image[(x, y)] = col;
+        }
+    };
 
-            let x0 = src_x.floor() - 1.0;
-            let y0 = src_y.floor() - 1.0;
+    new_image
+}
 
-            let mut result = Vector3::new(0.0, 0.0, 0.0);
 
-            for i in 0..4 {
-                for j in 0..4 {
-                    let px = (x0 + j as f32).clamp(0.0, og_size as f32 -1.0);
-                    let py = (y0 + i as f32).clamp(0.0, og_size as f32 -1.0);
-                    let pixel = img[(px as usize, py as usize)];
 
-                    let wx = cubic_weight((x0 + (j as f32) - src_x).abs());
-                    let wy = cubic_weight((y0 + (i as f32) - src_y).abs());
+// Samples img at a fractional source position with a 4x4 Catmull-Rom kernel
+pub fn bicubic_sample(img: &ColorImage, src_x: f32, src_y: f32) -> Color32 {
+    let (w, h) = (img.width(), img.height());
 
-                    result += Vector3::new(pixel.r() as f32, pixel.g() as f32, pixel.b() as f32) * wx * wy;
-                }
-            }
+    let x0 = src_x.floor() - 1.0;
+    let y0 = src_y.floor() - 1.0;
+
+    let mut result = Vector3::new(0.0, 0.0, 0.0);
+
+    for i in 0..4 {
+        for j in 0..4 {
+            let px = (x0 + j as f32).clamp(0.0, w as f32 -1.0);
+            let py = (y0 + i as f32).clamp(0.0, h as f32 -1.0);
+            let pixel = img[(px as usize, py as usize)];
 
-            let col = Color32::from_rgb(result.x.clamp(0.0, 255.0) as u8, result.y.clamp(0.0, 255.0) as u8, result.z.clamp(0.0, 255.0) as u8);
+            let wx = cubic_weight((x0 + (j as f32) - src_x).abs());
+            let wy = cubic_weight((y0 + (i as f32) - src_y).abs());
 
-            new_image[(x as usize, y as usize)] = col;
+            result += Vector3::new(pixel.r() as f32, pixel.g() as f32, pixel.b() as f32) * wx * wy;
         }
-    };
+    }
 
-    new_image
+    Color32::from_rgb(result.x.clamp(0.0, 255.0) as u8, result.y.clamp(0.0, 255.0) as u8, result.z.clamp(0.0, 255.0) as u8)
 }
 
 
-
-fn cubic_weight(t: f32) -> f32{
+pub fn cubic_weight(t: f32) -> f32{
     let a = -0.5;
 
     if t < 1.0 {
@@ -189,8 +377,8 @@ fn cubic_weight(t: f32) -> f32{
 
 
 
-pub fn colorimage_from_image(path: &str) -> ColorImage {
-    let img = image::open(path).unwrap().into_rgba8();
+pub fn colorimage_from_image(path: &str) -> image::ImageResult<ColorImage> {
+    let img = image::open(path)?.into_rgba8();
 
     let (width, height) = img.dimensions();
 
@@ -205,7 +393,7 @@ pub fn colorimage_from_image(path: &str) -> ColorImage {
         max: egui::Pos2 { x: width as f32, y: width as f32 },
     }, None);
 
-    bicubic_downsize(img, 512)
+    Ok(bicubic_downsize(img, 512))
 }
 
 
@@ -218,11 +406,10 @@ pub fn colorimage_to_bw(img: &ColorImage) -> ColorImage {
 
     let pixels: Vec<u8> = img.as_raw()
         .chunks_exact(4)
-        .map(|x| {
+        .flat_map(|x| {
             let sum = ((x[0] as u32 + x[1] as u32 + x[2] as u32) / 3) as u8;
             [sum, sum, sum, 255] // Expand to RGB grayscale
         })
-        .flatten()
         .collect();
 
 
@@ -237,4 +424,41 @@ pub fn colorimage_to_bw(img: &ColorImage) -> ColorImage {
     println!("Return BW 2");
 
     x
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drawing(fill: Color32) -> Drawing {
+        let mut drawing = Drawing::new();
+        drawing.set_image(ColorImage::new([9, 9], fill));
+        drawing
+    }
+
+    #[test]
+    fn clone_copies_covered_heights_only() {
+        // source covered on the left half only
+        let mut source = ColorImage::new([9, 9], Color32::TRANSPARENT);
+        for y in 0..9 {
+            for x in 0..4 {
+                source.pixels[y * 9 + x] = height_to_col(0.2);
+            }
+        }
+
+        let mut layer = drawing(height_to_col(0.6));
+        layer.clone_radius_height(Vector2::new(4, 4), 3, &source, Vector2::new(-2, 0), HeightBlend::Replace, None);
+        let at = |layer: &Drawing, x: usize, y: usize| layer.texture.pixels[y * 9 + x];
+        // the center reads source column 2 at full brush weight, column 7 reads transparent column 5
+        assert!((layer_height(at(&layer, 4, 4)) - 0.2).abs() < 0.01);
+        assert_eq!(at(&layer, 7, 4), height_to_col(0.6));
+
+        let mut empty = drawing(Color32::TRANSPARENT);
+        empty.clone_radius_height(Vector2::new(4, 4), 3, &source, Vector2::new(0, 0), HeightBlend::Replace, None);
+        assert_eq!(at(&empty, 5, 4), Color32::TRANSPARENT);
+        // one pixel off the center the brush weight is 1 - (1/3)^2
+        let weight = 1.0 - 1.0 / 9.0;
+        assert!((at(&empty, 3, 4).a() as f32 / 255.0 - weight).abs() < 0.01);
+        assert!((layer_height(at(&empty, 3, 4)) - 0.2 * weight).abs() < 0.01);
+    }
+}
@@ -0,0 +1,59 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use egui::Color32;
+use nalgebra::Vector2;
+
+use crate::{contour::Contour, hydrology::River, terrain::TerrainSettings};
+
+
+// Contours as SVG polylines in metres, origin at the top left corner of the terrain
+pub fn export_contours_svg(path: &Path, settings: &TerrainSettings, contours: &[Contour], color: Color32) -> std::io::Result<()> {
+    let lines = contours.iter().map(|c| (format!(r#"data-elevation="{}""#, c.elevation), c.points.as_slice()));
+    export_polylines_svg(path, settings, lines, color)
+}
+
+
+// River network as SVG polylines flowing downstream, tagged with the upstream cell count at their end
+pub fn export_rivers_svg(path: &Path, settings: &TerrainSettings, rivers: &[River], color: Color32) -> std::io::Result<()> {
+    let lines = rivers.iter().map(|r| (format!(r#"data-accumulation="{}""#, r.accumulation), r.points.as_slice()));
+    export_polylines_svg(path, settings, lines, color)
+}
+
+
+// Polylines of drawing uvs scaled to metres, each with its own data attribute
+fn export_polylines_svg<'a>(path: &Path, settings: &TerrainSettings, lines: impl Iterator<Item = (String, &'a [Vector2<f32>])>, color: Color32) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{l}" viewBox="0 0 {w} {l}">"#, w = settings.width, l = settings.length)?;
+
+    let stroke = format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b());
+    let stroke_width = 0.001 * settings.extent();
+
+    for (data, line) in lines {
+        let points = line.iter()
+            .map(|p| format!("{:.3},{:.3}", p.x * settings.width, p.y * settings.length))
+            .collect::<Vec<String>>()
+            .join(" ");
+        writeln!(out, r#"  <polyline {} points="{}" fill="none" stroke="{}" stroke-width="{}"/>"#, data, points, stroke, stroke_width)?;
+    }
+
+    writeln!(out, "</svg>")?;
+    out.flush()
+}
+
+
+// Grayscale PNG of values in [0, 1]
+pub fn export_mask_png(path: &Path, size: [usize; 2], values: &[f32], sixteen_bit: bool) -> image::ImageResult<()> {
+    let (w, h) = (size[0] as u32, size[1] as u32);
+
+    if sixteen_bit {
+        let pixels = values.iter().map(|v| (v.clamp(0.0, 1.0) * 65535.0).round() as u16).collect();
+        let img: image::ImageBuffer<image::Luma<u16>, Vec<u16>> = image::ImageBuffer::from_raw(w, h, pixels).expect("Mask size does not match image size");
+        img.save(path)
+    } else {
+        let pixels = values.iter().map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8).collect();
+        let img = image::GrayImage::from_raw(w, h, pixels).expect("Mask size does not match image size");
+        img.save(path)
+    }
+}
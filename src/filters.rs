@@ -0,0 +1,282 @@
+use std::ops::RangeInclusive;
+
+use egui::{ColorImage, Ui};
+
+use crate::{bake::{BakeJob, Pass}, drawing::{height_to_col, layer_height, with_alpha}, mask::Mask};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    Gaussian,
+    Box,
+    Median,
+    Sharpen,
+    HighPass,
+    Dilate,
+    Erode
+}
+
+
+impl FilterKind {
+    pub const ALL: [FilterKind; 7] = [FilterKind::Gaussian, FilterKind::Box, FilterKind::Median, FilterKind::Sharpen, FilterKind::HighPass, FilterKind::Dilate, FilterKind::Erode];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::Gaussian => "Gaussian Blur",
+            FilterKind::Box => "Box Blur",
+            FilterKind::Median => "Median",
+            FilterKind::Sharpen => "Unsharp Mask",
+            FilterKind::HighPass => "High Pass",
+            FilterKind::Dilate => "Dilate",
+            FilterKind::Erode => "Erode",
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct FilterSettings {
+    pub kind: FilterKind,
+    // pixels
+    pub radius: usize,
+    // gain of the detail for unsharp mask and high pass
+    pub amount: f32,
+    // blend between the original and filtered heights
+    pub strength: f32
+}
+
+
+impl FilterSettings {
+    pub fn default() -> Self {
+        Self {
+            kind: FilterKind::Gaussian,
+            radius: 4,
+            amount: 1.0,
+            strength: 1.0
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new("Filter Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Filter");
+            egui::ComboBox::from_id_salt("Filter Kind").selected_text(self.kind.name()).show_ui(ui, |ui| {
+                for kind in FilterKind::ALL {
+                    ui.selectable_value(&mut self.kind, kind, kind.name());
+                }
+            });
+            ui.end_row();
+
+            // the median sorts the whole window for every pixel, so it gets a smaller range
+            let max_radius = if self.kind == FilterKind::Median { 8 } else { 64 };
+            self.radius = self.radius.min(max_radius);
+            ui.label("Radius");
+            ui.add(egui::Slider::new(&mut self.radius, RangeInclusive::new(1, max_radius)).suffix(" px"));
+            ui.end_row();
+
+            if let FilterKind::Sharpen | FilterKind::HighPass = self.kind {
+                ui.label("Amount");
+                ui.add(egui::Slider::new(&mut self.amount, RangeInclusive::new(0.0, 5.0)));
+                ui.end_row();
+            }
+
+            ui.label("Strength");
+            ui.add(egui::Slider::new(&mut self.strength, RangeInclusive::new(0.0, 1.0)));
+            ui.end_row();
+        });
+    }
+
+    // Image passes for a bake job over the heights of a w x h layer
+    pub fn passes(&self, w: usize, h: usize) -> Vec<Pass> {
+        let r = self.radius;
+        let amount = self.amount;
+        match self.kind {
+            FilterKind::Gaussian => gaussian(w, h, r),
+            FilterKind::Box => vec![kernel_pass(w, h, vec![1.0; 2 * r + 1], true), kernel_pass(w, h, vec![1.0; 2 * r + 1], false)],
+            FilterKind::Median => vec![median_pass(w, h, r)],
+            FilterKind::Sharpen => {
+                let mut passes = gaussian(w, h, r);
+                passes.push(Box::new(move |input, blurred, x, y| {
+                    let i = y * w + x;
+                    input[i] + amount * (input[i] - blurred[i])
+                }));
+                passes
+            },
+            FilterKind::HighPass => {
+                // detail around mid gray, ready to be added back on another layer
+                let mut passes = gaussian(w, h, r);
+                passes.push(Box::new(move |input, blurred, x, y| {
+                    let i = y * w + x;
+                    0.5 + amount * (input[i] - blurred[i])
+                }));
+                passes
+            },
+            FilterKind::Dilate => vec![extreme_pass(w, h, r, true, true), extreme_pass(w, h, r, false, true)],
+            FilterKind::Erode => vec![extreme_pass(w, h, r, true, false), extreme_pass(w, h, r, false, false)],
+        }
+    }
+}
+
+
+fn gaussian(w: usize, h: usize, radius: usize) -> Vec<Pass> {
+    let sigma = (radius as f32 / 2.0).max(0.5);
+    let kernel: Vec<f32> = (-(radius as i64)..=radius as i64).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    vec![kernel_pass(w, h, kernel.clone(), true), kernel_pass(w, h, kernel, false)]
+}
+
+
+// Normalized 1D convolution along rows or columns with clamped borders
+fn kernel_pass(w: usize, h: usize, kernel: Vec<f32>, horizontal: bool) -> Pass {
+    let r = (kernel.len() / 2) as i64;
+    let total: f32 = kernel.iter().sum();
+    Box::new(move |_, prev, x, y| {
+        let mut sum = 0.0;
+        for (k, weight) in kernel.iter().enumerate() {
+            let d = k as i64 - r;
+            let i = if horizontal {
+                y * w + (x as i64 + d).clamp(0, w as i64 - 1) as usize
+            } else {
+                (y as i64 + d).clamp(0, h as i64 - 1) as usize * w + x
+            };
+            sum += weight * prev[i];
+        }
+        sum / total
+    })
+}
+
+
+// Separable square max (dilate) or min (erode)
+fn extreme_pass(w: usize, h: usize, radius: usize, horizontal: bool, max: bool) -> Pass {
+    let r = radius as i64;
+    Box::new(move |_, prev, x, y| {
+        let values = (-r..=r).map(|d| if horizontal {
+            prev[y * w + (x as i64 + d).clamp(0, w as i64 - 1) as usize]
+        } else {
+            prev[(y as i64 + d).clamp(0, h as i64 - 1) as usize * w + x]
+        });
+        if max { values.fold(f32::MIN, f32::max) } else { values.fold(f32::MAX, f32::min) }
+    })
+}
+
+
+fn median_pass(w: usize, h: usize, radius: usize) -> Pass {
+    let r = radius as i64;
+    Box::new(move |_, prev, x, y| {
+        let mut window = Vec::with_capacity(((2 * r + 1) * (2 * r + 1)) as usize);
+        for dy in -r..=r {
+            let sy = (y as i64 + dy).clamp(0, h as i64 - 1) as usize;
+            for dx in -r..=r {
+                window.push(prev[sy * w + (x as i64 + dx).clamp(0, w as i64 - 1) as usize]);
+            }
+        }
+        let mid = window.len() / 2;
+        *window.select_nth_unstable_by(mid, f32::total_cmp).1
+    })
+}
+
+
+// A filter running in the background, with everything it writes back taken from when it started
+pub struct FilterJob {
+    pub job: BakeJob,
+    // height layer id and its pixels
+    pub layer: usize,
+    pub base: ColorImage,
+    pub strength: f32,
+    pub mask: Option<Mask>
+}
+
+
+impl FilterJob {
+    pub fn spawn(settings: &FilterSettings, layer: usize, base: ColorImage, mask: Option<Mask>) -> Self {
+        let [w, h] = base.size;
+        Self {
+            job: BakeJob::spawn_passes(base.size, heights(&base), settings.passes(w, h)),
+            layer,
+            base,
+            strength: settings.strength,
+            mask
+        }
+    }
+}
+
+
+// Heights of a layer, row by row
+pub fn heights(img: &ColorImage) -> Vec<f32> {
+    img.pixels.iter().map(|px| layer_height(*px)).collect()
+}
+
+
+// Filtered heights written back over img, faded by strength and the mask. Coverage is kept
+pub fn apply(img: &ColorImage, filtered: &[f32], strength: f32, mask: Option<&Mask>) -> ColorImage {
+    let (w, h) = (img.width(), img.height());
+    let mut out = img.clone();
+    for y in 0..h {
+        for x in 0..w {
+            let i = y * w + x;
+            let t = strength * mask.map_or(1.0, |mask| mask.at(x, y, img.size));
+            let before = layer_height(img.pixels[i]);
+            let after = before + (filtered[i] - before) * t;
+            out.pixels[i] = with_alpha(height_to_col(after), img.pixels[i].a() as f32 / 255.0);
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::Color32;
+
+    const W: usize = 9;
+    const H: usize = 7;
+
+    fn run(kind: FilterKind, radius: usize, input: &[f32]) -> Vec<f32> {
+        let settings = FilterSettings { kind, radius, ..FilterSettings::default() };
+        settings.passes(W, H).iter().fold(input.to_vec(), |prev, pass| {
+            (0..W * H).map(|i| pass(input, &prev, i % W, i / W)).collect()
+        })
+    }
+
+    fn spike() -> Vec<f32> {
+        let mut input = vec![0.2; W * H];
+        input[3 * W + 4] = 1.0;
+        input
+    }
+
+    #[test]
+    fn blurs_keep_flat_ground_flat() {
+        for kind in [FilterKind::Gaussian, FilterKind::Box, FilterKind::Median, FilterKind::Sharpen] {
+            assert!(run(kind, 3, &[0.4; W * H]).iter().all(|h| (h - 0.4).abs() < 1e-5));
+        }
+        assert!(run(FilterKind::HighPass, 3, &[0.4; W * H]).iter().all(|h| (h - 0.5).abs() < 1e-5));
+    }
+
+    #[test]
+    fn blurs_spread_a_spike_and_the_median_removes_it() {
+        let gaussian = run(FilterKind::Gaussian, 2, &spike());
+        assert!(gaussian[3 * W + 4] < 1.0 && gaussian[3 * W + 5] > 0.2);
+        assert!((gaussian.iter().sum::<f32>() - spike().iter().sum::<f32>()).abs() < 1e-3);
+
+        assert!(run(FilterKind::Median, 1, &spike()).iter().all(|h| (h - 0.2).abs() < 1e-6));
+    }
+
+    #[test]
+    fn dilate_and_erode_grow_and_shrink_by_the_radius() {
+        let dilated = run(FilterKind::Dilate, 1, &spike());
+        for (i, h) in dilated.iter().enumerate() {
+            let near = (i % W).abs_diff(4) <= 1 && (i / W).abs_diff(3) <= 1;
+            assert_eq!(*h, if near { 1.0 } else { 0.2 });
+        }
+        assert_eq!(run(FilterKind::Erode, 1, &dilated), spike());
+    }
+
+    #[test]
+    fn apply_fades_by_strength_and_keeps_coverage() {
+        let img = ColorImage::new([W, H], with_alpha(height_to_col(0.2), 0.5));
+        let out = apply(&img, &[0.6; W * H], 0.5, None);
+        assert!(out.pixels.iter().all(|px| (layer_height(*px) - 0.4).abs() < 0.01 && px.a() == img.pixels[0].a()));
+
+        let empty = ColorImage::new([W, H], Color32::TRANSPARENT);
+        assert!(apply(&empty, &[0.6; W * H], 1.0, None).pixels.iter().all(|px| px.a() == 0));
+    }
+}
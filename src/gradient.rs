@@ -0,0 +1,145 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Sense, Stroke, Ui};
+
+use crate::drawing::{col_to_height, DirtyRect};
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct GradientStop {
+    // normalized drawing height
+    pub position: f32,
+    pub color: Color32
+}
+
+
+// Color ramp over the normalized height, used to tint the mesh by elevation
+#[derive(Clone, PartialEq)]
+pub struct Gradient {
+    pub stops: Vec<GradientStop>
+}
+
+
+impl Gradient {
+    fn from_stops(stops: &[(f32, [u8; 3])]) -> Self {
+        Self {
+            stops: stops.iter().map(|(position, [r, g, b])| GradientStop { position: *position, color: Color32::from_rgb(*r, *g, *b) }).collect()
+        }
+    }
+
+    pub fn earth() -> Self {
+        Self::from_stops(&[(0.0, [40, 100, 50]), (0.3, [150, 170, 80]), (0.55, [180, 140, 80]), (0.8, [130, 100, 80]), (1.0, [245, 245, 245])])
+    }
+
+    pub fn desert() -> Self {
+        Self::from_stops(&[(0.0, [200, 170, 110]), (0.5, [225, 195, 135]), (0.8, [170, 110, 70]), (1.0, [120, 80, 60])])
+    }
+
+    pub fn arctic() -> Self {
+        Self::from_stops(&[(0.0, [90, 110, 130]), (0.4, [170, 190, 205]), (0.7, [220, 230, 240]), (1.0, [255, 255, 255])])
+    }
+
+    // The flat shading the mesh used before gradients, 0.1 to 0.7 gray
+    pub fn grayscale() -> Self {
+        Self::from_stops(&[(0.0, [26, 26, 26]), (1.0, [179, 179, 179])])
+    }
+
+    pub fn sample(&self, t: f32) -> Color32 {
+        let (Some(first), Some(last)) = (self.stops.first(), self.stops.last()) else {
+            return Color32::GRAY;
+        };
+        if t <= first.position {
+            return first.color;
+        }
+
+        for pair in self.stops.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if t <= b.position {
+                let span = (b.position - a.position).max(1e-6);
+                return a.color.lerp_to_gamma(b.color, (t - a.position) / span);
+            }
+        }
+
+        last.color
+    }
+
+    // Tints every pixel of a height image
+    pub fn apply(&self, heights: &ColorImage) -> ColorImage {
+        ColorImage {
+            size: heights.size,
+            pixels: heights.pixels.iter().map(|px| self.sample(col_to_height(*px))).collect()
+        }
+    }
+
+    // Re-tints only the pixels inside region, both images have the same size
+    pub fn apply_region(&self, heights: &ColorImage, target: &mut ColorImage, region: DirtyRect) {
+        let w = heights.width();
+        for y in region.min_y..=region.max_y {
+            for x in region.min_x..=region.max_x {
+                target.pixels[y * w + x] = self.sample(col_to_height(heights.pixels[y * w + x]));
+            }
+        }
+    }
+
+    // Ramp preview and stop editor, returns true if the gradient changed
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        let before = self.clone();
+
+        ui.horizontal(|ui| {
+            if ui.button("Earth").clicked() {
+                *self = Self::earth();
+            }
+            if ui.button("Desert").clicked() {
+                *self = Self::desert();
+            }
+            if ui.button("Arctic").clicked() {
+                *self = Self::arctic();
+            }
+            if ui.button("Grayscale").clicked() {
+                *self = Self::grayscale();
+            }
+        });
+
+        // clicking the ramp adds a stop with the color already there
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(ui.available_width().min(220.0), 20.0), Sense::click());
+        let slices = 64;
+        for i in 0..slices {
+            let t = i as f32 / slices as f32;
+            let slice = egui::Rect::from_min_max(
+                egui::pos2(rect.left() + t * rect.width(), rect.top()),
+                egui::pos2(rect.left() + (i + 1) as f32 / slices as f32 * rect.width(), rect.bottom())
+            );
+            ui.painter().rect_filled(slice, 0.0, self.sample(t + 0.5 / slices as f32));
+        }
+        for stop in self.stops.iter() {
+            let x = rect.left() + stop.position * rect.width();
+            ui.painter().line_segment([egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())], Stroke::new(1.5, Color32::BLACK));
+        }
+        if let Some(pos) = response.clicked().then(|| response.interact_pointer_pos()).flatten() {
+            let position = ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+            self.stops.push(GradientStop { position, color: self.sample(position) });
+        }
+
+        let mut remove = None;
+        egui::Grid::new("Gradient Stops Grid").num_columns(3).show(ui, |ui| {
+            for (i, stop) in self.stops.iter_mut().enumerate() {
+                ui.add(egui::DragValue::new(&mut stop.position).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+                ui.color_edit_button_srgba(&mut stop.color);
+                if ui.small_button("Remove").clicked() {
+                    remove = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(i) = remove.filter(|_| self.stops.len() > 1) {
+            self.stops.remove(i);
+        }
+
+        // rows would swap under a drag if they were reordered while editing
+        if ui.ctx().dragged_id().is_none() {
+            self.stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        }
+
+        *self != before
+    }
+}
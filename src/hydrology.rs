@@ -0,0 +1,642 @@
+use std::{cmp::Ordering, collections::BinaryHeap, ops::RangeInclusive};
+
+use egui::{Color32, ColorImage, Ui};
+use nalgebra::{Vector2, Vector3};
+
+use crate::{drawing, mesh::Geometry, terrain::TerrainSettings};
+
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum FlowMethod {
+    D8,
+    DInfinity
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct FlowSettings {
+    pub enabled: bool,
+    pub method: FlowMethod,
+    pub threshold: f32,
+    pub color: Color32
+}
+
+
+impl FlowSettings {
+    pub fn default() -> Self {
+        Self {
+            enabled: false,
+            method: FlowMethod::D8,
+            threshold: 500.0,
+            color: Color32::from_rgb(30, 90, 220)
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.enabled, "Show Rivers");
+
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.method, FlowMethod::D8, "D8");
+            ui.radio_value(&mut self.method, FlowMethod::DInfinity, "D-Infinity");
+        });
+
+        egui::Grid::new("Flow Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Threshold");
+            ui.add(egui::DragValue::new(&mut self.threshold).speed(10.0).range(RangeInclusive::new(1.0, 1000000.0)).suffix(" cells"))
+                .on_hover_text("Upstream cells needed before a cell counts as river");
+            ui.end_row();
+
+            ui.label("Color");
+            ui.color_edit_button_srgba(&mut self.color);
+            ui.end_row();
+        });
+    }
+}
+
+
+// Flow routing over the depression filled heights, indices are y * width + x
+pub struct Flow {
+    pub width: usize,
+    pub height: usize,
+    // D8 receiver of every cell, None where water leaves the map
+    pub receivers: Vec<Option<usize>>,
+    // number of cells draining through each cell, including itself
+    pub accumulation: Vec<f32>
+}
+
+
+// Polyline along the D8 receivers, points are drawing uvs through pixel centers
+pub struct River {
+    pub points: Vec<Vector2<f32>>,
+    pub accumulation: f32
+}
+
+
+// Min heap entry for the priority flood, equal heights pop in insertion order so flats drain outwards
+struct FloodCell {
+    z: f32,
+    order: usize,
+    index: usize
+}
+
+impl PartialEq for FloodCell {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FloodCell {}
+
+impl PartialOrd for FloodCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FloodCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.z.total_cmp(&self.z).then(other.order.cmp(&self.order))
+    }
+}
+
+
+const NEIGHBOURS: [(i64, i64); 8] = [(1, 0), (1, -1), (0, -1), (-1, -1), (-1, 0), (-1, 1), (0, 1), (1, 1)];
+
+
+// Priority flood from the map border (Barnes et al.), every depression is raised to its spill height.
+// With a positive epsilon the filled cells keep a minimal gradient of epsilon metres per cell towards the outlet.
+// Returns the filled heights, the cell each one was reached from and the pop order, which visits
+// every cell after the cells it can drain into.
+fn priority_flood(elevations: &[f32], w: usize, h: usize, epsilon: f32) -> (Vec<f32>, Vec<Option<usize>>, Vec<usize>) {
+    let mut filled = elevations.to_vec();
+    let mut from = vec![None; w * h];
+    let mut visited = vec![false; w * h];
+    let mut order = Vec::with_capacity(w * h);
+    let mut heap = BinaryHeap::new();
+
+    for y in 0..h {
+        for x in 0..w {
+            if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                let index = y * w + x;
+                visited[index] = true;
+                heap.push(FloodCell { z: filled[index], order: heap.len(), index });
+            }
+        }
+    }
+
+    let mut pushed = heap.len();
+    while let Some(cell) = heap.pop() {
+        order.push(cell.index);
+        let (x, y) = ((cell.index % w) as i64, (cell.index / w) as i64);
+
+        for (ox, oy) in NEIGHBOURS {
+            let (nx, ny) = (x + ox, y + oy);
+            if nx < 0 || ny < 0 || nx >= w as i64 || ny >= h as i64 {
+                continue;
+            }
+            let n = ny as usize * w + nx as usize;
+            if visited[n] {
+                continue;
+            }
+
+            visited[n] = true;
+            if filled[n] <= cell.z {
+                filled[n] = cell.z + epsilon;
+            }
+            from[n] = Some(cell.index);
+            heap.push(FloodCell { z: filled[n], order: pushed, index: n });
+            pushed += 1;
+        }
+    }
+
+    (filled, from, order)
+}
+
+
+pub fn compute_flow(settings: &TerrainSettings, img: &ColorImage, method: FlowMethod) -> Flow {
+    let (w, h) = (img.width(), img.height());
+    let (filled, from, order) = priority_flood(&settings.elevations(img), w, h, 0.0);
+
+    let dx = settings.width / w as f32;
+    let dy = settings.length / h as f32;
+    let at = |x: i64, y: i64| (x >= 0 && y >= 0 && x < w as i64 && y < h as i64).then(|| y as usize * w + x as usize);
+
+    // steepest descent on the filled surface, flats left by the fill follow the flood back to their outlet.
+    // Both only ever point at cells popped earlier, so the routing has no cycles
+    let receivers: Vec<Option<usize>> = (0..w * h).map(|i| {
+        let (x, y) = ((i % w) as i64, (i / w) as i64);
+        let mut best = None;
+        let mut best_slope = 0.0;
+        for (ox, oy) in NEIGHBOURS {
+            if let Some(n) = at(x + ox, y + oy) {
+                let distance = ((ox as f32 * dx).powi(2) + (oy as f32 * dy).powi(2)).sqrt();
+                let slope = (filled[i] - filled[n]) / distance;
+                if slope > best_slope {
+                    best_slope = slope;
+                    best = Some(n);
+                }
+            }
+        }
+        best.or(from[i])
+    }).collect();
+
+    let mut accumulation = vec![1.0; w * h];
+    for &i in order.iter().rev() {
+        let amount = accumulation[i];
+        match method {
+            FlowMethod::D8 => {
+                if let Some(r) = receivers[i] {
+                    accumulation[r] += amount;
+                }
+            },
+            FlowMethod::DInfinity => {
+                let (x, y) = ((i % w) as i64, (i / w) as i64);
+                match dinf_split(&filled, (x, y), (dx, dy), at) {
+                    Some((a, b, fa)) => {
+                        accumulation[a] += amount * fa;
+                        if fa < 1.0 {
+                            accumulation[b] += amount * (1.0 - fa);
+                        }
+                    },
+                    None => {
+                        if let Some(r) = receivers[i] {
+                            accumulation[r] += amount;
+                        }
+                    },
+                }
+            },
+        }
+    }
+
+    Flow {
+        width: w,
+        height: h,
+        receivers,
+        accumulation
+    }
+}
+
+
+// Tarboton's D-infinity: the steepest of the 8 triangular facets around a cell decides the flow angle,
+// which is split between the cardinal and diagonal neighbour bounding that facet.
+// Returns (cardinal, diagonal, fraction to cardinal), None if nothing is downslope
+fn dinf_split(filled: &[f32], (x, y): (i64, i64), (dx, dy): (f32, f32), at: impl Fn(i64, i64) -> Option<usize>) -> Option<(usize, usize, f32)> {
+    let e0 = filled[at(x, y)?];
+    let mut best: Option<(usize, usize, f32)> = None;
+    let mut best_slope = 0.0;
+
+    for facet in 0..8 {
+        // facets alternate between starting at a cardinal neighbour and ending at one
+        let (c, d) = if facet % 2 == 0 {
+            (NEIGHBOURS[facet], NEIGHBOURS[(facet + 1) % 8])
+        } else {
+            (NEIGHBOURS[(facet + 1) % 8], NEIGHBOURS[facet])
+        };
+        let (Some(n1), Some(n2)) = (at(x + c.0, y + c.1), at(x + d.0, y + d.1)) else {
+            continue;
+        };
+
+        let (d1, d2) = if c.0 != 0 { (dx, dy) } else { (dy, dx) };
+        let max_angle = (d2 / d1).atan();
+        let s1 = (e0 - filled[n1]) / d1;
+        let s2 = (filled[n1] - filled[n2]) / d2;
+
+        let mut angle = s2.atan2(s1);
+        let mut slope = (s1 * s1 + s2 * s2).sqrt();
+        if angle < 0.0 {
+            angle = 0.0;
+            slope = s1;
+        } else if angle > max_angle {
+            angle = max_angle;
+            slope = (e0 - filled[n2]) / (d1 * d1 + d2 * d2).sqrt();
+        }
+
+        if slope > best_slope {
+            best_slope = slope;
+            best = Some((n1, n2, 1.0 - angle / max_angle));
+        }
+    }
+
+    best
+}
+
+
+impl Flow {
+    pub fn is_river(&self, i: usize, threshold: f32) -> bool {
+        self.accumulation[i] >= threshold
+    }
+
+    // 1 on river cells, 0 elsewhere
+    pub fn river_mask(&self, threshold: f32) -> Vec<f32> {
+        (0..self.accumulation.len()).map(|i| if self.is_river(i, threshold) { 1.0 } else { 0.0 }).collect()
+    }
+
+    // River cells in the given color on a transparent image, opacity grows with the upstream area
+    pub fn river_image(&self, threshold: f32, color: Color32) -> ColorImage {
+        let max = self.accumulation.iter().fold(threshold, |m, a| m.max(*a));
+        let range = (max / threshold).ln().max(1e-6);
+
+        ColorImage {
+            size: [self.width, self.height],
+            pixels: (0..self.accumulation.len()).map(|i| {
+                if !self.is_river(i, threshold) {
+                    return Color32::TRANSPARENT;
+                }
+                let t = (self.accumulation[i] / threshold).ln() / range;
+                crate::drawing::with_alpha(color, 0.5 + 0.5 * t)
+            }).collect()
+        }
+    }
+
+    // Splits the river network into polylines that break at sources and confluences
+    pub fn rivers(&self, threshold: f32) -> Vec<River> {
+        let (w, h) = (self.width, self.height);
+        let uv = |i: usize| Vector2::new(((i % w) as f32 + 0.5) / w as f32, ((i / w) as f32 + 0.5) / h as f32);
+
+        let mut inflow = vec![0_u32; w * h];
+        for i in 0..w * h {
+            if let (true, Some(r)) = (self.is_river(i, threshold), self.receivers[i]) {
+                inflow[r] += 1;
+            }
+        }
+
+        let mut rivers = Vec::new();
+        for start in 0..w * h {
+            if !self.is_river(start, threshold) || inflow[start] == 1 {
+                continue;
+            }
+
+            let mut points = vec![uv(start)];
+            let mut current = start;
+            while let Some(next) = self.receivers[current] {
+                if !self.is_river(next, threshold) {
+                    break;
+                }
+                points.push(uv(next));
+                current = next;
+                if inflow[next] != 1 {
+                    break;
+                }
+            }
+
+            if points.len() > 1 {
+                rivers.push(River {
+                    points,
+                    accumulation: self.accumulation[current]
+                });
+            }
+        }
+
+        rivers
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct FillSettings {
+    pub epsilon: f32,
+    pub min_volume: f32,
+    pub overlay: bool
+}
+
+
+impl FillSettings {
+    pub fn default() -> Self {
+        Self {
+            epsilon: 0.0,
+            min_volume: 0.01,
+            overlay: false
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.overlay, "Show Basins");
+
+        egui::Grid::new("Fill Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Epsilon");
+            ui.add(egui::DragValue::new(&mut self.epsilon).speed(0.0001).range(RangeInclusive::new(0.0, 1.0)).suffix(" m/cell"))
+                .on_hover_text("Minimal slope left on filled areas so they still drain");
+            ui.end_row();
+
+            ui.label("Min Volume");
+            ui.add(egui::DragValue::new(&mut self.min_volume).speed(0.01).range(RangeInclusive::new(0.0, 1000000.0)).suffix(" m³"))
+                .on_hover_text("Smaller basins are hidden from the list");
+            ui.end_row();
+        });
+    }
+}
+
+
+// Connected area the depression fill raised, with the fill it needs
+pub struct Basin {
+    // lowest cell of the basin
+    pub seed: usize,
+    pub cells: Vec<usize>,
+    // filled surface of every cell in cells, same order
+    pub filled: Vec<f32>,
+    pub level: f32,
+    pub area: f32,
+    pub volume: f32
+}
+
+
+// Depression with its own water surface, flooded from seed up to level
+#[derive(Clone, Copy, PartialEq)]
+pub struct Lake {
+    pub seed: (usize, usize),
+    pub level: f32
+}
+
+
+// Finds every depression of the height drawing, largest volume first
+pub fn find_basins(settings: &TerrainSettings, img: &ColorImage, epsilon: f32) -> Vec<Basin> {
+    let (w, h) = (img.width(), img.height());
+    let elevations = settings.elevations(img);
+    // the epsilon slope also lifts every flat towards its outlet, only cells a level fill raises are sinks
+    let (level_fill, _, _) = priority_flood(&elevations, w, h, 0.0);
+    let filled = if epsilon > 0.0 { priority_flood(&elevations, w, h, epsilon).0 } else { level_fill.clone() };
+    let cell_area = settings.width / w as f32 * settings.length / h as f32;
+
+    let raised: Vec<bool> = (0..w * h).map(|i| level_fill[i] - elevations[i] > 1e-6).collect();
+    let mut seen = vec![false; w * h];
+    let mut basins = Vec::new();
+
+    for start in 0..w * h {
+        if !raised[start] || seen[start] {
+            continue;
+        }
+
+        seen[start] = true;
+        let mut cells = Vec::new();
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            cells.push(i);
+            for n in neighbours(i, w, h) {
+                if raised[n] && !seen[n] {
+                    seen[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+
+        let seed = *cells.iter().min_by(|a, b| elevations[**a].total_cmp(&elevations[**b])).unwrap();
+        basins.push(Basin {
+            seed,
+            filled: cells.iter().map(|i| filled[*i]).collect(),
+            level: cells.iter().fold(f32::MIN, |m, i| m.max(filled[*i])),
+            area: cells.len() as f32 * cell_area,
+            volume: cells.iter().map(|i| filled[*i] - elevations[*i]).sum::<f32>() * cell_area,
+            cells
+        });
+    }
+
+    basins.sort_by(|a, b| b.volume.total_cmp(&a.volume));
+    basins
+}
+
+
+fn neighbours(i: usize, w: usize, h: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = ((i % w) as i64, (i / w) as i64);
+    NEIGHBOURS.iter()
+        .map(move |(ox, oy)| (x + ox, y + oy))
+        .filter(move |(nx, ny)| *nx >= 0 && *ny >= 0 && *nx < w as i64 && *ny < h as i64)
+        .map(move |(nx, ny)| ny as usize * w + nx as usize)
+}
+
+
+// Raises the basin to its filled surface in the height drawing
+pub fn fill_basin(settings: &TerrainSettings, img: &mut ColorImage, basin: &Basin) {
+    for (i, z) in basin.cells.iter().zip(basin.filled.iter()) {
+        img.pixels[*i] = drawing::height_to_col(settings.normalized_height(*z));
+    }
+}
+
+
+// Depth of every raised cell as translucent blue, deeper is more opaque
+pub fn basin_image(img: &ColorImage, basins: &[Basin], settings: &TerrainSettings) -> ColorImage {
+    let max = basins.iter().flat_map(|b| b.filled.iter().zip(b.cells.iter()))
+        .fold(1e-6_f32, |m, (z, i)| m.max(z - settings.elevation(drawing::col_to_height(img.pixels[*i]))));
+
+    let mut pixels = vec![Color32::TRANSPARENT; img.pixels.len()];
+    for basin in basins {
+        for (z, i) in basin.filled.iter().zip(basin.cells.iter()) {
+            let depth = (z - settings.elevation(drawing::col_to_height(img.pixels[*i]))) / max;
+            pixels[*i] = drawing::with_alpha(Color32::from_rgb(0, 200, 255), 0.25 + 0.75 * depth.sqrt());
+        }
+    }
+
+    ColorImage {
+        size: img.size,
+        pixels
+    }
+}
+
+
+// Cells flooded by a lake: everything below its level connected to the seed
+pub fn lake_cells(settings: &TerrainSettings, img: &ColorImage, lake: &Lake) -> Vec<bool> {
+    let (w, h) = (img.width(), img.height());
+    let mut flooded = vec![false; w * h];
+    let below = |i: usize| settings.elevation(drawing::col_to_height(img.pixels[i])) < lake.level;
+
+    let seed = lake.seed.1.min(h - 1) * w + lake.seed.0.min(w - 1);
+    if !below(seed) {
+        return flooded;
+    }
+
+    flooded[seed] = true;
+    let mut stack = vec![seed];
+    while let Some(i) = stack.pop() {
+        for n in neighbours(i, w, h) {
+            if !flooded[n] && below(n) {
+                flooded[n] = true;
+                stack.push(n);
+            }
+        }
+    }
+
+    flooded
+}
+
+
+// Flat water surfaces over the flooded cells of every lake, rows of cells are merged into single quads
+pub fn generate_lake_surfaces(settings: &TerrainSettings, img: &ColorImage, lakes: &[Lake], color: Color32) -> Geometry {
+    let (w, h) = (img.width(), img.height());
+    let mut geometry = Geometry {
+        positions: Vec::new(),
+        indicies: Vec::new(),
+        uvs: Vec::new(),
+        colors: Vec::new()
+    };
+
+    for lake in lakes {
+        let flooded = lake_cells(settings, img, lake);
+        let y_world = lake.level * settings.exaggeration;
+
+        for y in 0..h {
+            let mut x = 0;
+            while x < w {
+                if !flooded[y * w + x] {
+                    x += 1;
+                    continue;
+                }
+                let start = x;
+                while x < w && flooded[y * w + x] {
+                    x += 1;
+                }
+
+                let (u0, u1) = (start as f32 / w as f32, x as f32 / w as f32);
+                let (v0, v1) = (y as f32 / h as f32, (y + 1) as f32 / h as f32);
+                let base = geometry.positions.len() as u32;
+                for (u, v) in [(u0, v0), (u1, v0), (u1, v1), (u0, v1)] {
+                    geometry.positions.push(Vector3::new((u - 0.5) * settings.width, y_world, (v - 0.5) * settings.length));
+                    geometry.uvs.push(Vector2::new(u, v));
+                    geometry.colors.push(color);
+                }
+                geometry.indicies.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+            }
+        }
+    }
+
+    geometry
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drawing::height_to_col;
+
+    fn image(w: usize, h: usize, height: impl Fn(usize, usize) -> f32) -> ColorImage {
+        ColorImage {
+            size: [w, h],
+            pixels: (0..w * h).map(|i| height_to_col(height(i % w, i / w))).collect()
+        }
+    }
+
+    // 0.5 everywhere with a 3 x 3 pit of 0.2 in the middle
+    fn pit(x: usize, y: usize) -> f32 {
+        if (3..6).contains(&x) && (3..6).contains(&y) { 0.2 } else { 0.5 }
+    }
+
+    #[test]
+    fn priority_flood_raises_pits_to_their_spill_height() {
+        let elevations: Vec<f32> = (0..81).map(|i| pit(i % 9, i / 9)).collect();
+        let (filled, _, order) = priority_flood(&elevations, 9, 9, 0.0);
+        assert!(filled.iter().all(|z| *z == 0.5));
+        assert_eq!(order.len(), 81);
+    }
+
+    #[test]
+    fn priority_flood_epsilon_drains_flats_towards_the_border() {
+        let elevations = vec![1.0; 49];
+        let (filled, _, _) = priority_flood(&elevations, 7, 7, 0.01);
+        assert_eq!(filled[0], 1.0);
+        assert!(filled[3 * 7 + 3] > filled[3 * 7 + 2] && filled[3 * 7 + 2] > filled[3 * 7 + 1]);
+    }
+
+    #[test]
+    fn basins_find_only_real_sinks() {
+        let settings = TerrainSettings::default();
+        let basins = find_basins(&settings, &image(9, 9, pit), 0.0);
+        assert_eq!(basins.len(), 1);
+        assert_eq!(basins[0].cells.len(), 9);
+        assert!(basins[0].volume > 0.0);
+        assert!((basins[0].level - settings.elevation(0.5)).abs() < 0.01);
+
+        // flats are only lifted by the epsilon gradient, they are no basins
+        assert!(find_basins(&settings, &image(9, 9, |_, _| 0.5), 0.001).is_empty());
+        assert_eq!(find_basins(&settings, &image(9, 9, pit), 0.001).len(), 1);
+    }
+
+    // follows the receivers from every cell, panics if a path comes back on itself
+    fn assert_drains(flow: &Flow) {
+        for start in 0..flow.receivers.len() {
+            let mut cell = start;
+            for _ in 0..flow.receivers.len() {
+                match flow.receivers[cell] {
+                    Some(next) => cell = next,
+                    None => break,
+                }
+            }
+            assert!(flow.receivers[cell].is_none(), "cell {start} never leaves the map");
+        }
+    }
+
+    #[test]
+    fn d8_runs_straight_down_a_ramp() {
+        let flow = compute_flow(&TerrainSettings::default(), &image(9, 5, |x, _| x as f32 / 8.0), FlowMethod::D8);
+        assert_drains(&flow);
+        for y in 0..5 {
+            for x in 1..9 {
+                assert_eq!(flow.receivers[y * 9 + x], Some(y * 9 + x - 1));
+                assert_eq!(flow.accumulation[y * 9 + x], (9 - x) as f32);
+            }
+            assert_eq!(flow.receivers[y * 9], None);
+            assert_eq!(flow.accumulation[y * 9], 9.0);
+        }
+    }
+
+    #[test]
+    fn flow_through_a_pit_drains_to_the_border() {
+        for method in [FlowMethod::D8, FlowMethod::DInfinity] {
+            let flow = compute_flow(&TerrainSettings::default(), &image(9, 9, pit), method);
+            assert_drains(&flow);
+            // the filled pit is flat, so all water follows the receivers and every cell is counted once at an outlet
+            let out: f32 = (0..81).filter(|&i| flow.receivers[i].is_none()).map(|i| flow.accumulation[i]).sum();
+            assert!((out - 81.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn dinf_splits_between_the_two_neighbours_of_the_facet() {
+        // falls one per pixel to the east and half a pixel to the south
+        let filled: Vec<f32> = (0..9).map(|i| 10.0 - (i % 3) as f32 - 0.5 * (i / 3) as f32).collect();
+        let at = |x: i64, y: i64| ((0..3).contains(&x) && (0..3).contains(&y)).then(|| y as usize * 3 + x as usize);
+        let (cardinal, diagonal, fraction) = dinf_split(&filled, (1, 1), (1.0, 1.0), at).unwrap();
+        assert_eq!((cardinal, diagonal), (5, 8));
+        assert!((fraction - (1.0 - 0.5_f32.atan() / std::f32::consts::FRAC_PI_4)).abs() < 1e-5);
+
+        let ramp = compute_flow(&TerrainSettings::default(), &image(9, 5, |x, _| x as f32 / 8.0), FlowMethod::DInfinity);
+        assert!((0..5).all(|y| ramp.accumulation[y * 9] == 9.0));
+    }
+}
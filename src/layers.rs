@@ -0,0 +1,452 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Ui};
+
+use crate::drawing::{col_to_height, height_to_col, layer_height, DirtyRect, Drawing};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    Add,
+    Subtract,
+    Multiply,
+    Max,
+    Min
+}
+
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 6] = [BlendMode::Normal, BlendMode::Add, BlendMode::Subtract, BlendMode::Multiply, BlendMode::Max, BlendMode::Min];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Normal",
+            BlendMode::Add => "Add",
+            BlendMode::Subtract => "Subtract",
+            BlendMode::Multiply => "Multiply",
+            BlendMode::Max => "Max",
+            BlendMode::Min => "Min",
+        }
+    }
+
+    fn apply(&self, base: f32, top: f32) -> f32 {
+        match self {
+            BlendMode::Normal => top,
+            BlendMode::Add => base + top,
+            BlendMode::Subtract => base - top,
+            BlendMode::Multiply => base * top,
+            BlendMode::Max => base.max(top),
+            BlendMode::Min => base.min(top),
+        }
+    }
+}
+
+
+pub struct Layer {
+    // stays with the layer when the stack is reordered, for work that finishes later
+    pub id: usize,
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    pub blend: BlendMode,
+    // alpha is the per pixel coverage, painting raises it
+    pub drawing: Drawing,
+    // rebuilt from the splines whenever they change, paint in it does not last
+    pub generated: bool,
+    // id of the layer whose edits this Add (raise) or Subtract (cut) layer holds where its own blend
+    // cannot reach them
+    pub helper_of: Option<usize>
+}
+
+
+// Layers of one channel, bottom first. The flattened result lives in a separate Drawing that
+// everything downstream (canvas, mesh, analysis, exporters) reads from
+pub struct LayerStack {
+    pub layers: Vec<Layer>,
+    pub selected: usize,
+    restack: bool,
+    next_id: usize
+}
+
+
+impl LayerStack {
+    pub fn new(background: &Drawing) -> Self {
+        let mut drawing = Drawing::new();
+        drawing.set_image(background.get_image());
+
+        Self {
+            layers: vec![Layer {
+                id: 0,
+                name: "Background".to_string(),
+                visible: true,
+                opacity: 1.0,
+                blend: BlendMode::Normal,
+                drawing,
+                generated: false,
+                helper_of: None
+            }],
+            selected: 0,
+            restack: false,
+            next_id: 1
+        }
+    }
+
+    pub fn selected(&self) -> &Drawing {
+        &self.layers[self.selected].drawing
+    }
+
+    pub fn selected_mut(&mut self) -> &mut Drawing {
+        &mut self.layers[self.selected].drawing
+    }
+
+    pub fn selected_id(&self) -> usize {
+        self.layers[self.selected].id
+    }
+
+    pub fn layer_mut(&mut self, id: usize) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|l| l.id == id)
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    // Empty layer above the selected one
+    pub fn add_layer(&mut self, name: &str, blend: BlendMode) {
+        let mut drawing = Drawing::new();
+        drawing.set_image(ColorImage::new(self.layers[0].drawing.texture.size, Color32::TRANSPARENT));
+
+        self.selected += 1;
+        let id = self.new_id();
+        self.layers.insert(self.selected, Layer {
+            id,
+            name: name.to_string(),
+            visible: true,
+            opacity: 1.0,
+            blend,
+            drawing,
+            generated: false,
+            helper_of: None
+        });
+        self.restack = true;
+    }
+
+    // Index of the generated layer, a new one goes on top of the stack the first time
+    pub fn generated_layer(&mut self, name: &str, blend: BlendMode) -> usize {
+        if let Some(i) = self.layers.iter().position(|l| l.generated) {
+            return i;
+        }
+
+        let mut drawing = Drawing::new();
+        drawing.set_image(ColorImage::new(self.layers[0].drawing.texture.size, Color32::TRANSPARENT));
+        let id = self.new_id();
+        self.layers.push(Layer {
+            id,
+            name: name.to_string(),
+            visible: true,
+            opacity: 1.0,
+            blend,
+            drawing,
+            generated: true,
+            helper_of: None
+        });
+        self.restack = true;
+        self.layers.len() - 1
+    }
+
+    fn flatten_pixel(layers: &[Layer], i: usize) -> Color32 {
+        let mut out = [0.0_f32; 3];
+        for layer in layers.iter().filter(|l| l.visible) {
+            let [r, g, b, a] = layer.drawing.texture.pixels[i].to_srgba_unmultiplied();
+            let weight = layer.opacity * a as f32 / 255.0;
+            if weight <= 0.0 {
+                continue;
+            }
+            for (c, top) in out.iter_mut().zip([r, g, b]) {
+                let blended = layer.blend.apply(*c, top as f32 / 255.0);
+                *c = (*c + (blended - *c) * weight).clamp(0.0, 1.0);
+            }
+        }
+        Color32::from_rgb((out[0] * 255.0).round() as u8, (out[1] * 255.0).round() as u8, (out[2] * 255.0).round() as u8)
+    }
+
+    pub fn flatten(&self) -> ColorImage {
+        self.flatten_below(self.layers.len())
+    }
+
+    // Result of the layers under index only
+    pub fn flatten_below(&self, index: usize) -> ColorImage {
+        let size = self.layers[0].drawing.texture.size;
+        self.flatten_region(index, DirtyRect { min_x: 0, min_y: 0, max_x: size[0] - 1, max_y: size[1] - 1 })
+    }
+
+    // Result of the layers under index over region only, region sized
+    pub fn flatten_region(&self, index: usize, region: DirtyRect) -> ColorImage {
+        let w = self.layers[0].drawing.texture.width();
+        let layers = &self.layers[..index.min(self.layers.len())];
+        ColorImage {
+            size: [region.max_x - region.min_x + 1, region.max_y - region.min_y + 1],
+            pixels: (region.min_y..=region.max_y)
+                .flat_map(|y| (region.min_x..=region.max_x).map(move |x| y * w + x))
+                .map(|i| Self::flatten_pixel(layers, i))
+                .collect()
+        }
+    }
+
+    // Helper layer of the given blend right above a layer, created the first time an edit needs it
+    fn helper_layer(&mut self, index: usize, blend: BlendMode) -> usize {
+        let id = self.layers[index].id;
+        if let Some(i) = self.layers.iter().position(|l| l.helper_of == Some(id) && l.blend == blend) {
+            return i;
+        }
+
+        let mut drawing = Drawing::new();
+        drawing.set_image(ColorImage::new(self.layers[0].drawing.texture.size, Color32::TRANSPARENT));
+        let helper = Layer {
+            id: self.new_id(),
+            name: format!("{} {}", self.layers[index].name, if blend == BlendMode::Add { "Raise" } else { "Cut" }),
+            visible: true,
+            opacity: 1.0,
+            blend,
+            drawing,
+            generated: false,
+            helper_of: Some(id)
+        };
+        self.layers.insert(index + 1, helper);
+        self.restack = true;
+        index + 1
+    }
+
+    // Changes what an Add or Subtract layer holds at pixel i by amount, in result heights.
+    // Returns the part of a decrease it did not hold
+    fn hold(layer: &mut Layer, i: usize, amount: f32) -> f32 {
+        let px = layer.drawing.texture.pixels[i];
+        let opacity = layer.opacity.max(1e-3);
+        let held = layer_height(px) * px.a() as f32 / 255.0 * opacity + amount;
+        layer.drawing.texture.pixels[i] = height_to_col((held.max(0.0) / opacity).min(1.0));
+        (-held).max(0.0)
+    }
+
+    // Changes the heights of the selected layer so the result moves by delta per pixel, for tools that work on
+    // the flattened heights. Written pixels become fully covered, layers above pass the change on as they blend.
+    // What the layer's blend cannot reach (lowering a Max or Add layer, raising a Min, Subtract or Multiply
+    // one) goes into a raise or cut layer right above it, which first gives back what the other one holds
+    pub fn add_height_delta(&mut self, delta: &[f32]) {
+        let index = self.selected;
+        let (opacity, blend) = (self.layers[index].opacity, self.layers[index].blend);
+        let id = self.layers[index].id;
+
+        let helper = |layers: &[Layer], blend| layers.iter().position(|l| l.helper_of == Some(id) && l.blend == blend);
+        let (raise, cut) = (helper(&self.layers, BlendMode::Add), helper(&self.layers, BlendMode::Subtract));
+
+        let w = self.layers[0].drawing.texture.size[0];
+        let mut region: Option<DirtyRect> = None;
+        let mut rest = Vec::new();
+        for (i, d) in delta.iter().enumerate() {
+            let mut d = *d;
+            if d == 0.0 {
+                continue;
+            }
+            if let Some(h) = if d > 0.0 { cut } else { raise } {
+                d = d.signum() * Self::hold(&mut self.layers[h], i, -d.abs());
+            }
+
+            let base = col_to_height(Self::flatten_pixel(&self.layers[..index], i));
+            let out = col_to_height(Self::flatten_pixel(&self.layers[..=index], i));
+            let target = (out + d).clamp(0.0, 1.0);
+
+            let mut reached = out;
+            if opacity > 0.0 {
+                let need = base + (target - base) / opacity;
+                let top = match blend {
+                    BlendMode::Normal | BlendMode::Max | BlendMode::Min => need,
+                    BlendMode::Add => need - base,
+                    BlendMode::Subtract => base - need,
+                    BlendMode::Multiply => if base > 0.0 { need / base } else { 1.0 },
+                }.clamp(0.0, 1.0);
+                self.layers[index].drawing.texture.pixels[i] = height_to_col(top);
+                reached = (base + (blend.apply(base, top) - base) * opacity).clamp(0.0, 1.0);
+            }
+            if (target - reached).abs() > 1.0 / 765.0 {
+                rest.push((i, target - reached));
+            }
+
+            let (x, y) = (i % w, i / w);
+            let r = DirtyRect { min_x: x, min_y: y, max_x: x, max_y: y };
+            region = Some(region.map_or(r, |region| region.union(r)));
+        }
+        let Some(region) = region else {
+            return;
+        };
+        for h in [Some(index), raise, cut].into_iter().flatten() {
+            self.layers[h].drawing.mark_dirty(region);
+        }
+
+        for blend in [BlendMode::Add, BlendMode::Subtract] {
+            let sign = if blend == BlendMode::Add { 1.0 } else { -1.0 };
+            if !rest.iter().any(|(_, amount)| amount * sign > 0.0) {
+                continue;
+            }
+            // helpers go in above the layer, so index still points at it
+            let h = self.helper_layer(index, blend);
+            for (i, amount) in rest.iter().filter(|(_, amount)| amount * sign > 0.0) {
+                Self::hold(&mut self.layers[h], *i, amount.abs());
+            }
+            self.layers[h].drawing.mark_dirty(region);
+        }
+    }
+
+    // Moves layer edits since the last call into the flattened drawing, only the changed region is recomputed
+    pub fn sync(&mut self, target: &mut Drawing) {
+        let mut region: Option<DirtyRect> = None;
+        for layer in self.layers.iter_mut() {
+            if let Some(r) = layer.drawing.take_dirty() {
+                region = Some(region.map_or(r, |region| region.union(r)));
+            }
+        }
+
+        let size = self.layers[0].drawing.texture.size;
+        if self.restack || target.texture.size != size {
+            target.set_image(self.flatten());
+            self.restack = false;
+        } else if let Some(r) = region {
+            for y in r.min_y..=r.max_y {
+                for x in r.min_x..=r.max_x {
+                    target.texture.pixels[y * size[0] + x] = Self::flatten_pixel(&self.layers, y * size[0] + x);
+                }
+            }
+            target.mark_dirty(r);
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, id: &str, default_blend: BlendMode) {
+        let mut changed = false;
+
+        // top of the stack is listed first
+        for i in (0..self.layers.len()).rev() {
+            let layer = &mut self.layers[i];
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.selected, i, "");
+                changed |= ui.checkbox(&mut layer.visible, "").on_hover_text("Visible").changed();
+                ui.add(egui::TextEdit::singleline(&mut layer.name).desired_width(90.0));
+                if layer.generated {
+                    ui.label("(splines)");
+                }
+                if layer.helper_of.is_some() {
+                    ui.label(if layer.blend == BlendMode::Add { "(raise)" } else { "(cut)" });
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.add_space(24.0);
+                changed |= ui.add(egui::Slider::new(&mut layer.opacity, RangeInclusive::new(0.0, 1.0)).text("Opacity")).changed();
+                egui::ComboBox::from_id_salt((id, i)).selected_text(layer.blend.name()).width(80.0).show_ui(ui, |ui| {
+                    for mode in BlendMode::ALL {
+                        changed |= ui.selectable_value(&mut layer.blend, mode, mode.name()).changed();
+                    }
+                });
+            });
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button("Add Layer").clicked() {
+                self.add_layer(&format!("Layer {}", self.layers.len()), default_blend);
+            }
+            let s = self.selected;
+            if ui.add_enabled(s + 1 < self.layers.len(), egui::Button::new("Up")).clicked() {
+                self.layers.swap(s, s + 1);
+                self.selected += 1;
+                changed = true;
+            }
+            if ui.add_enabled(s > 0, egui::Button::new("Down")).clicked() {
+                self.layers.swap(s, s - 1);
+                self.selected -= 1;
+                changed = true;
+            }
+            // helpers go with their layer, they would keep shaping the terrain on their own
+            let id = self.layers[s].id;
+            let helpers = self.layers.iter().filter(|l| l.helper_of == Some(id)).count();
+            if ui.add_enabled(self.layers.len() > 1 + helpers, egui::Button::new("Delete")).clicked() {
+                self.layers.remove(s);
+                self.layers.retain(|l| l.helper_of != Some(id));
+                self.selected = s.min(self.layers.len() - 1);
+                changed = true;
+            }
+        });
+
+        if changed {
+            self.restack = true;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drawing::with_alpha;
+
+    fn stack(background: f32) -> LayerStack {
+        let mut drawing = Drawing::new();
+        drawing.set_image(ColorImage::new([4, 4], height_to_col(background)));
+        LayerStack::new(&drawing)
+    }
+
+    fn heights(img: &ColorImage) -> Vec<f32> {
+        img.pixels.iter().map(|px| col_to_height(*px)).collect()
+    }
+
+    #[test]
+    fn flatten_blends_layers_bottom_up() {
+        let mut layers = stack(0.4);
+        layers.add_layer("Add", BlendMode::Add);
+        layers.selected_mut().set_image(ColorImage::new([4, 4], height_to_col(0.2)));
+        assert!(heights(&layers.flatten()).iter().all(|h| (h - 0.6).abs() < 0.01));
+
+        layers.layers[1].opacity = 0.5;
+        assert!(heights(&layers.flatten()).iter().all(|h| (h - 0.5).abs() < 0.01));
+
+        // transparent pixels leave the layers below alone
+        layers.add_layer("Normal", BlendMode::Normal);
+        layers.selected_mut().texture.pixels[0] = with_alpha(height_to_col(1.0), 1.0);
+        let flat = heights(&layers.flatten());
+        assert!((flat[0] - 1.0).abs() < 0.01 && (flat[1] - 0.5).abs() < 0.01);
+        assert!(heights(&layers.flatten_below(1)).iter().all(|h| (h - 0.4).abs() < 0.01));
+    }
+
+    #[test]
+    fn height_delta_lands_on_the_result_for_any_blend() {
+        for blend in BlendMode::ALL {
+            for d in [0.25, -0.25] {
+                let mut layers = stack(0.4);
+                layers.add_layer("Layer", blend);
+                layers.layers[1].opacity = 0.8;
+                let mut delta = vec![0.0; 16];
+                delta[5] = d;
+                layers.add_height_delta(&delta);
+
+                let flat = heights(&layers.flatten());
+                assert!((flat[5] - (0.4 + d)).abs() < 0.01, "{} {d} {}", blend.name(), flat[5]);
+                assert!((flat[0] - 0.4).abs() < 0.01, "{} {d} {}", blend.name(), flat[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn helpers_give_back_before_the_other_one_grows() {
+        let mut layers = stack(0.4);
+        layers.add_layer("Add", BlendMode::Add);
+        let mut delta = vec![0.0; 16];
+        delta[5] = -0.25;
+        layers.add_height_delta(&delta);
+        assert_eq!(layers.layers.len(), 3);
+        assert!(layers.layers[2].blend == BlendMode::Subtract && layers.layers[2].helper_of == Some(layers.layers[1].id));
+
+        delta[5] = 0.25;
+        layers.add_height_delta(&delta);
+        assert!((heights(&layers.flatten())[5] - 0.4).abs() < 0.01);
+        assert_eq!(layers.layers.len(), 3);
+        assert!(layer_height(layers.layers[2].drawing.texture.pixels[5]) < 0.01);
+    }
+}
@@ -1,13 +1,29 @@
 
-use std::{default, ops::RangeInclusive, ptr::null, sync::{Arc, Mutex}};
+use std::{ops::RangeInclusive, sync::{Arc, Mutex}};
 
-use drawing::{bicubic_downsize, colorimage_from_image, colorimage_to_bw, Drawing};
-use mesh::{generate_tiled_plane_colorimg, Mesh};
-use tobj;
+use drawing::{bicubic_downsize, colorimage_from_image, colorimage_to_bw, DirtyRect, Drawing, HeightBlend, BRUSH_RADIUS};
+use mesh::{generate_draped_lines, generate_tiled_grid, generate_tiled_plane_colorimg, update_tiled_plane_region, Mesh};
+use analysis::AnalysisSettings;
+use bake::{AoSettings, BakeJob, LightmapSettings};
+use contour::{Contour, ContourSettings};
+use hydrology::{Basin, FillSettings, Flow, FlowSettings, Lake, River};
+use terrain::TerrainSettings;
+use texture::{GpuTexture, TerrainTextures};
+use texturing::TextureRule;
+use gradient::Gradient;
+use mask::{Mask, MaskCombine, MaskSettings};
+use layers::{BlendMode, LayerStack};
+use selection::{Clip, Floating, Selection};
+use stamps::StampSettings;
+use splines::{ControlPoint, Spline, SplineMode};
+use remap::RemapSettings;
+use stats::HeightStats;
+use filters::{FilterJob, FilterSettings};
+use transform::{CanvasTransform, ResizeFilter, TransformSettings};
 
 use camera::Camera;
-use eframe::{egui, egui_glow};
-use egui::{vec2, Align, Color32, Layout, Margin, Rect};
+use eframe::{egui, egui_glow, glow};
+use egui::{pos2, Align, Color32, ColorImage, Layout, Margin, Pos2, Rect};
 use nalgebra::{Vector2, Vector3};
 
 mod shader;
@@ -15,6 +31,25 @@ use shader::ShaderProgram;
 
 mod mesh;
 mod drawing;
+mod texture;
+mod terrain;
+mod export;
+mod water;
+mod contour;
+mod analysis;
+mod hydrology;
+mod bake;
+mod texturing;
+mod gradient;
+mod mask;
+mod layers;
+mod selection;
+mod stamps;
+mod splines;
+mod remap;
+mod stats;
+mod filters;
+mod transform;
 
 
 mod camera;
@@ -35,31 +70,129 @@ fn main() -> eframe::Result{
     )
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy)]
 enum SelectedTab {
     Height,
     Color
 }
 
 
+// What dragging on the 2D canvas does
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum CanvasTool {
+    Brush,
+    Rectangle,
+    Lasso,
+    Clone,
+    Stamp,
+    Spline
+}
+
+
 #[derive(PartialEq, Eq)]
 enum MeshColoring {
     Color,
-    Height
+    Height,
+    Analysis,
+    Lightmap
+}
+
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum RenderPath {
+    Cpu,
+    Gpu
 }
 // Main App UI
 
 struct App {
+    // flattened layer stacks, brushes and generators write into the layers instead
     drawing: Drawing,
     colors: Drawing,
+    height_layers: LayerStack,
+    color_layers: LayerStack,
     tab: SelectedTab,
     mesh: Arc<Mutex<Mesh>>,
     camera: Arc<Mutex<Camera>>,
     shader_program: Arc<Mutex<ShaderProgram>>,
-    value: f32,
+    grid: Arc<Mutex<Mesh>>,
+    textures: Arc<Mutex<TerrainTextures>>,
+    terrain_program: Arc<Mutex<ShaderProgram>>,
+    render_path: RenderPath,
+    terrain: TerrainSettings,
+    water: Arc<Mutex<Mesh>>,
+    water_coverage: Option<(f32, f32)>,
+    sculpt: bool,
+    hover_uv: Option<Vector2<f32>>,
+    contour_settings: ContourSettings,
+    contours: Option<Vec<Contour>>,
+    contour_mesh: Arc<Mutex<Mesh>>,
+    analysis_settings: AnalysisSettings,
+    analysis: Drawing,
+    analysis_valid: bool,
+    flow_settings: FlowSettings,
+    flow: Option<Flow>,
+    rivers: Option<Vec<River>>,
+    river_overlay: Drawing,
+    river_mesh: Arc<Mutex<Mesh>>,
+    fill_settings: FillSettings,
+    basins: Option<Vec<Basin>>,
+    basin_overlay: Drawing,
+    lakes: Vec<Lake>,
+    lake_mesh: Arc<Mutex<Mesh>>,
+    lakes_valid: bool,
+    ao_settings: AoSettings,
+    ao_job: Option<BakeJob>,
+    ao: Option<Vec<f32>>,
+    ao_image: Drawing,
+    light_settings: LightmapSettings,
+    light_job: Option<BakeJob>,
+    lightmap: Option<Vec<f32>>,
+    lightmap_image: Drawing,
+    texture_rules: Vec<TextureRule>,
+    gradient: Gradient,
+    height_tint: Drawing,
+    masks: Vec<Mask>,
+    active_mask: Option<usize>,
+    mask_settings: MaskSettings,
+    mask_overlay: Drawing,
+    mask_overlay_stale: bool,
+    canvas_tool: CanvasTool,
+    selection: Option<Selection>,
+    selection_drag: Option<Vec<Vector2<f32>>>,
+    feather: usize,
+    clipboard: Option<Clip>,
+    // pasted clip with the channel and layer id it previews into
+    floating: Option<(SelectedTab, usize, Floating)>,
+    floating_drag: Option<Vector2<f32>>,
+    clone_source: Option<Vector2<f32>>,
+    // pixel offset from the brush to the source, kept between strokes once set
+    clone_offset: Option<Vector2<i64>>,
+    // layer as it was when the stroke started, so the brush never copies its own paint
+    clone_snapshot: Option<ColorImage>,
+    clone_blend: HeightBlend,
+    stamp_settings: StampSettings,
+    splines: Vec<Spline>,
+    spline_selected: Option<usize>,
+    // control point being dragged on the canvas
+    spline_drag: Option<usize>,
+    // canvas region the spline layers still have to be rebuilt over
+    splines_dirty: Option<DirtyRect>,
+    remap: RemapSettings,
+    // height layer id, its pixels from before the live preview and the remapped pixels shown instead
+    remap_preview: Option<(usize, ColorImage, ColorImage)>,
+    height_stats: Option<HeightStats>,
+    filter_settings: FilterSettings,
+    filter_job: Option<FilterJob>,
+    // the last filter finished on a layer that was edited in the meantime
+    filter_discarded: bool,
+    // shown in the status bar until the next load or export succeeds or it is dismissed
+    file_error: Option<String>,
+    transform_settings: TransformSettings,
     angle: (f32, f32, f32),
     speed: f32,
     plane_density: u32,
+    mesh_density: usize,
     color: Color32,
     mesh_coloring: MeshColoring
 }
@@ -74,21 +207,307 @@ impl eframe::App for App {
                 ..egui::Frame::default()
             })
             .show(ctx, |ui| {
-                if ui.button("Open Texture").clicked() {
-                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                ui.horizontal(|ui| {
+                    if ui.button("Open Texture").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            // loaded images follow the canvas once it has been cropped or resized
+                            let size = self.height_layers.layers[0].drawing.texture.size;
+                            let fit = |img: ColorImage| if img.size == size { img } else { CanvasTransform::Resize { size, filter: ResizeFilter::Bicubic }.apply(&img) };
+                            match (self.tab, colorimage_from_image(path.to_str().unwrap())) {
+                                (SelectedTab::Height, Ok(img)) => {
+                                    self.height_layers.selected_mut().set_image(fit(colorimage_to_bw(&img)));
+                                    self.file_error = None;
+                                },
+                                (SelectedTab::Color, Ok(img)) => {
+                                    self.color_layers.selected_mut().set_image(fit(img));
+                                    self.file_error = None;
+                                },
+                                (_, Err(e)) => self.file_error = Some(format!("Failed to open texture: {e}")),
+                            }
+                        }
+                    }
+                });
+            });
+
+        // filled from last frame's hover, the canvas and viewport rects are only known further down
+        egui::TopBottomPanel::bottom("Status Bar").show(ctx, |ui| {
+            self.status_bar(ui);
+        });
+        self.hover_uv = None;
+
+        egui::SidePanel::right("Settings Panel")
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    let before = self.terrain;
+                    ui.collapsing("Terrain Settings", |ui| {
+                        self.terrain.ui(ui);
+                    });
+                    ui.collapsing("Water", |ui| {
+                        self.terrain.water.ui(ui);
+                        if ui.button("Export Heightmap PNG").clicked() {
+                            if let Some(path) = rfd::FileDialog::new().add_filter("PNG", &["png"]).save_file() {
+                                let mut heights: Vec<f32> = self.drawing.texture.pixels.iter().map(|px| drawing::col_to_height(*px)).collect();
+                                water::apply_sea_level(&self.terrain, &mut heights);
+                                self.file_error = export::export_mask_png(&path, self.drawing.texture.size, &heights, true).err().map(|e| format!("Failed to export PNG: {e}"));
+                            }
+                        }
+                    });
+                    let contours_before = self.contour_settings;
+                    ui.collapsing("Contours", |ui| {
+                        self.contour_settings.ui(ui);
+                        if ui.button("Export SVG").clicked() {
+                            if let Some(path) = rfd::FileDialog::new().add_filter("SVG", &["svg"]).save_file() {
+                                let contours = contour::extract_contours(&self.terrain, &self.drawing.texture, self.contour_settings.interval);
+                                self.file_error = export::export_contours_svg(&path, &self.terrain, &contours, self.contour_settings.color).err().map(|e| format!("Failed to export SVG: {e}"));
+                            }
+                        }
+                    });
+                    let analysis_before = self.analysis_settings;
+                    ui.collapsing("Analysis", |ui| {
+                        self.analysis_settings.ui(ui);
+                        if ui.button("Export PNG").clicked() {
+                            if let Some(path) = rfd::FileDialog::new().add_filter("PNG", &["png"]).save_file() {
+                                let kind = self.analysis_settings.kind;
+                                let values = analysis::normalize(kind, &analysis::compute(&self.terrain, &self.drawing.texture, kind));
+                                self.file_error = export::export_mask_png(&path, self.drawing.texture.size, &values, self.analysis_settings.sixteen_bit).err().map(|e| format!("Failed to export PNG: {e}"));
+                            }
+                        }
+                    });
+                    let flow_before = self.flow_settings;
+                    ui.collapsing("Statistics", |ui| {
+                        let terrain = self.terrain;
+                        self.height_stats.get_or_insert_with(|| HeightStats::compute(&terrain, &self.drawing.texture)).ui(ui, &terrain);
+                    });
+                    ui.collapsing("Rivers", |ui| {
+                        self.flow_settings.ui(ui);
+                        ui.horizontal(|ui| {
+                            if ui.button("Export Mask PNG").clicked() {
+                                if let Some(path) = rfd::FileDialog::new().add_filter("PNG", &["png"]).save_file() {
+                                    let threshold = self.flow_settings.threshold;
+                                    let mask = self.current_flow().river_mask(threshold);
+                                    self.file_error = export::export_mask_png(&path, self.drawing.texture.size, &mask, false).err().map(|e| format!("Failed to export PNG: {e}"));
+                                }
+                            }
+                            if ui.button("Export SVG").clicked() {
+                                if let Some(path) = rfd::FileDialog::new().add_filter("SVG", &["svg"]).save_file() {
+                                    let threshold = self.flow_settings.threshold;
+                                    let rivers = self.current_flow().rivers(threshold);
+                                    self.file_error = export::export_rivers_svg(&path, &self.terrain, &rivers, self.flow_settings.color).err().map(|e| format!("Failed to export SVG: {e}"));
+                                }
+                            }
+                        });
+                    });
+                    let fill_before = self.fill_settings;
+                    ui.collapsing("Depressions", |ui| {
+                        self.fill_settings.ui(ui);
+                        if self.fill_settings.epsilon != fill_before.epsilon {
+                            self.basins = None;
+                        }
+                        self.depressions_ui(ui);
+                    });
+                    ui.collapsing("Layers", |ui| {
                         match self.tab {
-                            SelectedTab::Height => {
-                                self.drawing.texture = colorimage_to_bw(&colorimage_from_image(path.to_str().unwrap()));
-                                println!("We got here");
+                            SelectedTab::Height => self.height_layers.ui(ui, "Height Layers", BlendMode::Add),
+                            SelectedTab::Color => self.color_layers.ui(ui, "Color Layers", BlendMode::Normal),
+                        }
+                    });
+                    ui.collapsing("Canvas", |ui| {
+                        let size = self.height_layers.layers[0].drawing.texture.size;
+                        if let Some(transform) = self.transform_settings.ui(ui, size, self.selection.as_ref()) {
+                            self.transform_canvas(transform);
+                        }
+                    });
+                    ui.collapsing("Selection", |ui| {
+                        self.selection_ui(ui);
+                    });
+                    ui.collapsing("Stamps", |ui| {
+                        self.stamp_settings.ui(ui);
+                    });
+                    ui.collapsing("Splines", |ui| {
+                        self.splines_ui(ui);
+                    });
+                    // the preview only follows the settings while they are on screen
+                    if ui.collapsing("Height Remap", |ui| self.remap_ui(ui)).body_returned.is_none() {
+                        self.end_remap_preview();
+                    }
+                    ui.collapsing("Filters", |ui| {
+                        self.filter_settings.ui(ui);
+                        match &self.filter_job {
+                            Some(filter) => {
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::ProgressBar::new(filter.job.progress()).show_percentage().desired_width(120.0));
+                                    if ui.button("Cancel").clicked() {
+                                        filter.job.cancel();
+                                    }
+                                });
+                            },
+                            None => {
+                                if ui.button("Apply to Layer").clicked() {
+                                    let mask = self.active_mask.and_then(|i| self.masks.get(i)).cloned();
+                                    let base = self.height_layers.selected().get_image();
+                                    self.filter_job = Some(FilterJob::spawn(&self.filter_settings, self.height_layers.selected_id(), base, mask));
+                                    self.filter_discarded = false;
+                                }
+                                if self.filter_discarded {
+                                    ui.colored_label(Color32::YELLOW, "The layer was edited while filtering, the result was dropped");
+                                }
+                            },
+                        }
+                    });
+                    ui.collapsing("Masks", |ui| {
+                        self.masks_ui(ui);
+                    });
+                    ui.collapsing("Height Gradient", |ui| {
+                        if self.gradient.ui(ui) {
+                            self.update_height_tint(None);
+                        }
+                        if ui.button("Bake into Colors").clicked() {
+                            self.set_colors(self.height_tint.get_image());
+                        }
+                    });
+                    ui.collapsing("Auto Texture", |ui| {
+                        if texturing::rules_ui(ui, &mut self.texture_rules, &mut self.file_error) {
+                            self.set_colors(texturing::generate_colors(&self.terrain, &self.drawing.texture, &self.texture_rules));
+                        }
+                    });
+                    ui.collapsing("Ambient Occlusion", |ui| {
+                        self.ao_settings.ui(ui);
+                        match &self.ao_job {
+                            Some(job) => {
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::ProgressBar::new(job.progress()).show_percentage().desired_width(120.0));
+                                    if ui.button("Cancel").clicked() {
+                                        job.cancel();
+                                    }
+                                });
                             },
-                            SelectedTab::Color => {
-                                self.colors.texture = colorimage_from_image(path.to_str().unwrap());
+                            None => {
+                                if ui.button("Bake").clicked() {
+                                    let texel = bake::ambient_occlusion(&self.terrain, &self.drawing.texture, self.ao_settings);
+                                    self.ao_job = Some(BakeJob::spawn(self.drawing.texture.size, texel));
+                                }
                             },
                         }
+                        let mut multiplied = None;
+                        if let Some(ao) = &self.ao {
+                            let size = self.ao_image.texture.size;
+                            ui.horizontal(|ui| {
+                                if ui.button("Multiply into Colors").clicked() {
+                                    let mut img = self.color_layers.selected().get_image();
+                                    bake::multiply_into(&mut img, size, ao);
+                                    multiplied = Some(img);
+                                }
+                                if ui.button("Export PNG").clicked() {
+                                    if let Some(path) = rfd::FileDialog::new().add_filter("PNG", &["png"]).save_file() {
+                                        self.file_error = export::export_mask_png(&path, size, ao, self.ao_settings.sixteen_bit).err().map(|e| format!("Failed to export PNG: {e}"));
+                                    }
+                                }
+                            });
+                        }
+                        if let Some(img) = multiplied {
+                            self.set_colors(img);
+                        }
+                    });
+                    ui.collapsing("Lightmap", |ui| {
+                        self.light_settings.ui(ui);
+                        match &self.light_job {
+                            Some(job) => {
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::ProgressBar::new(job.progress()).show_percentage().desired_width(120.0));
+                                    if ui.button("Cancel").clicked() {
+                                        job.cancel();
+                                    }
+                                });
+                            },
+                            None => {
+                                if ui.button("Bake").clicked() {
+                                    let ao = self.ao.clone().filter(|ao| self.light_settings.use_ao && ao.len() == self.drawing.texture.pixels.len());
+                                    let texel = bake::lightmap(&self.terrain, &self.drawing.texture, self.light_settings, ao);
+                                    self.light_job = Some(BakeJob::spawn(self.drawing.texture.size, texel));
+                                }
+                            },
+                        }
+                        if let Some(lightmap) = &self.lightmap {
+                            ui.label("Preview with the Lightmap mesh coloring");
+                            if ui.button("Export PNG").clicked() {
+                                if let Some(path) = rfd::FileDialog::new().add_filter("PNG", &["png"]).save_file() {
+                                    self.file_error = export::export_mask_png(&path, self.lightmap_image.texture.size, lightmap, self.light_settings.sixteen_bit).err().map(|e| format!("Failed to export PNG: {e}"));
+                                }
+                            }
+                        }
+                    });
+                    if self.terrain != before {
+                        self.basins = None;
+                        self.lakes_valid = false;
+                        // spline elevations are in metres
+                        if !self.splines.is_empty() {
+                            self.queue_splines(None);
+                        }
+                        self.height_stats = None;
                     }
-                }
+                    if self.terrain != before || self.flow_settings.method != flow_before.method {
+                        self.flow = None;
+                    }
+                    if self.terrain != before || self.flow_settings != flow_before {
+                        self.rivers = None;
+                    }
+                    if self.terrain != before || self.contour_settings != contours_before {
+                        self.contours = None;
+                    }
+                    if self.terrain != before || self.analysis_settings.kind != analysis_before.kind {
+                        self.analysis_valid = false;
+                    }
+                    if self.analysis_settings.kind != analysis_before.kind && self.mesh_coloring == MeshColoring::Analysis {
+                        self.rebuild_mesh(_frame.gl().unwrap(), self.mesh_density);
+                    }
+                    if self.terrain != before {
+                        self.rebuild_mesh(_frame.gl().unwrap(), self.mesh_density);
+                        self.rebuild_grid(_frame.gl().unwrap(), self.plane_density as usize);
+                        self.water.lock().unwrap().set_geometry(_frame.gl().unwrap(), water::generate_water_plane(&self.terrain));
+                        self.water_coverage = None;
+                    }
+                });
             });
 
+        self.update_contours(_frame.gl().unwrap());
+        self.update_rivers(_frame.gl().unwrap(), ctx);
+        self.update_lakes(_frame.gl().unwrap(), ctx);
+        if let Some(job) = &mut self.ao_job {
+            if job.is_finished() {
+                // a canceled bake has no result and keeps the previous one
+                if let Some(ao) = job.take_result() {
+                    self.ao_image.set_image(bake::grayscale_image(self.drawing.texture.size, &ao));
+                    self.ao = Some(ao);
+                }
+                self.ao_job = None;
+            }
+        }
+        if let Some(job) = &mut self.light_job {
+            if job.is_finished() {
+                if let Some(lightmap) = job.take_result() {
+                    self.lightmap_image.set_image(bake::grayscale_image(self.drawing.texture.size, &lightmap));
+                    self.lightmap = Some(lightmap);
+                }
+                self.light_job = None;
+            }
+        }
+        if let Some(filter) = &mut self.filter_job {
+            if filter.job.is_finished() {
+                if let (Some(filtered), Some(layer)) = (filter.job.take_result(), self.height_layers.layer_mut(filter.layer)) {
+                    // writing back would overwrite whatever was painted while the filter ran
+                    self.filter_discarded = layer.drawing.texture.pixels != filter.base.pixels;
+                    if !self.filter_discarded {
+                        layer.drawing.set_image(filters::apply(&filter.base, &filtered, filter.strength, filter.mask.as_ref()));
+                    }
+                }
+                self.filter_job = None;
+            }
+        }
+        if self.fill_settings.overlay {
+            self.update_basins(ctx);
+        }
+
         let mut img_rect : Rect = Rect::NOTHING;
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -113,6 +532,63 @@ impl eframe::App for App {
                                     SelectedTab::Height => img_rect = self.drawing.draw(ui, ctx).rect,
                                     SelectedTab::Color => img_rect = self.colors.draw(ui, ctx).rect,
                                 }
+                                if self.analysis_settings.overlay {
+                                    self.update_analysis();
+                                    let tint = Color32::from_white_alpha((self.analysis_settings.opacity * 255.0) as u8);
+                                    ui.painter().image(self.analysis.texture_id(ctx), img_rect, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), tint);
+                                }
+                                if let (true, Some(_)) = (self.fill_settings.overlay, &self.basins) {
+                                    ui.painter().image(self.basin_overlay.texture_id(ctx), img_rect, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), Color32::WHITE);
+                                }
+                                if let (true, Some(_)) = (self.ao_settings.overlay, &self.ao) {
+                                    ui.painter().image(self.ao_image.texture_id(ctx), img_rect, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), Color32::WHITE);
+                                }
+                                if let (true, Some(_)) = (self.mask_settings.overlay, self.active_mask) {
+                                    ui.painter().image(self.mask_overlay.texture_id(ctx), img_rect, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), Color32::WHITE);
+                                }
+                                if self.flow_settings.enabled {
+                                    ui.painter().image(self.river_overlay.texture_id(ctx), img_rect, Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)), Color32::WHITE);
+                                }
+                                if let (true, Some(contours)) = (self.contour_settings.enabled, &self.contours) {
+                                    contour::paint_contours(ui.painter(), img_rect, contours, self.contour_settings.color);
+                                }
+                                if let Some(selection) = &self.selection {
+                                    selection::paint_outline(ui.painter(), img_rect, &selection.points, true, Color32::WHITE);
+                                }
+                                if let Some(points) = self.selection_drag.as_ref().filter(|p| p.len() > 1) {
+                                    match self.canvas_tool {
+                                        CanvasTool::Rectangle => selection::paint_outline(ui.painter(), img_rect, &Selection::rectangle(points[0], points[points.len() - 1]).points, true, Color32::WHITE),
+                                        _ => selection::paint_outline(ui.painter(), img_rect, points, false, Color32::WHITE),
+                                    }
+                                }
+                                if let Some((_, _, floating)) = &self.floating {
+                                    selection::paint_outline(ui.painter(), img_rect, &floating.outline(), true, Color32::YELLOW);
+                                }
+                                if self.canvas_tool == CanvasTool::Spline {
+                                    for (i, spline) in self.splines.iter().enumerate() {
+                                        spline.paint(ui.painter(), img_rect, self.spline_selected == Some(i));
+                                    }
+                                }
+                                if let (CanvasTool::Stamp, Some(uv)) = (self.canvas_tool, self.hover_uv) {
+                                    let center = img_rect.min + egui::vec2(uv.x * img_rect.width(), uv.y * img_rect.height());
+                                    let radius = self.stamp_settings.size / self.terrain.width * img_rect.width();
+                                    let angle = self.stamp_settings.rotation.to_radians();
+                                    // the line points along the stamp's x axis, downwind for dunes
+                                    ui.painter().circle_stroke(center, radius, egui::Stroke::new(1.0, Color32::WHITE));
+                                    ui.painter().line_segment([center, center + egui::vec2(angle.cos(), angle.sin()) * radius], egui::Stroke::new(1.0, Color32::WHITE));
+                                }
+                                if let (CanvasTool::Clone, Some(source)) = (self.canvas_tool, self.clone_source) {
+                                    let size = self.drawing.texture.size;
+                                    // the source follows the brush once the offset is known
+                                    let source = match (self.clone_offset, self.hover_uv) {
+                                        (Some(offset), Some(uv)) => uv + Vector2::new(offset.x as f32 / size[0] as f32, offset.y as f32 / size[1] as f32),
+                                        _ => source,
+                                    };
+                                    let center = img_rect.min + egui::vec2(source.x * img_rect.width(), source.y * img_rect.height());
+                                    ui.painter().circle_stroke(center, BRUSH_RADIUS as f32 * img_rect.width() / size[0] as f32, egui::Stroke::new(1.0, Color32::WHITE));
+                                    ui.painter().line_segment([center - egui::vec2(4.0, 0.0), center + egui::vec2(4.0, 0.0)], egui::Stroke::new(1.0, Color32::WHITE));
+                                    ui.painter().line_segment([center - egui::vec2(0.0, 4.0), center + egui::vec2(0.0, 4.0)], egui::Stroke::new(1.0, Color32::WHITE));
+                                }
                             }).response.rect.height();
                         });
 
@@ -122,8 +598,23 @@ impl eframe::App for App {
                             ui.radio_value(&mut self.tab, SelectedTab::Height, "Height");
                             ui.add_space(5.0);
                             ui.radio_value(&mut self.tab, SelectedTab::Color, "Color");
+                            ui.separator();
+                            ui.radio_value(&mut self.canvas_tool, CanvasTool::Brush, "Brush");
+                            ui.radio_value(&mut self.canvas_tool, CanvasTool::Rectangle, "Rectangle");
+                            ui.radio_value(&mut self.canvas_tool, CanvasTool::Lasso, "Lasso");
+                            ui.radio_value(&mut self.canvas_tool, CanvasTool::Clone, "Clone").on_hover_text("Alt-click to set the source");
+                            ui.radio_value(&mut self.canvas_tool, CanvasTool::Stamp, "Stamp");
+                            ui.radio_value(&mut self.canvas_tool, CanvasTool::Spline, "Spline");
                         });
                         ui.add_space(4.0);
+                        if let (CanvasTool::Clone, SelectedTab::Height) = (self.canvas_tool, self.tab) {
+                            ui.horizontal(|ui| {
+                                ui.add_space(5.0);
+                                for blend in HeightBlend::ALL {
+                                    ui.radio_value(&mut self.clone_blend, blend, blend.name());
+                                }
+                            });
+                        }
                         if let SelectedTab::Color = self.tab {
                             let t = self.color.to_array();
                             let mut temp = [(t[0] as f32) / 255.0,  (t[1] as f32) / 255.0, (t[2] as f32) / 255.0];
@@ -146,27 +637,30 @@ impl eframe::App for App {
                         
 
                         ui.horizontal(|ui| {                
-                            ui.label(format!("Verts: {}", self.mesh.lock().unwrap().positions.len()));
+                            let mesh = match self.render_path {
+                                RenderPath::Cpu => self.mesh.lock().unwrap(),
+                                RenderPath::Gpu => self.grid.lock().unwrap(),
+                            };
+                            ui.label(format!("Verts: {}", mesh.positions.len()));
                             ui.add_space(2.0);
-                            ui.label(format!("Tris: {}", self.mesh.lock().unwrap().indicies.len()/3));
+                            ui.label(format!("Tris: {}", mesh.indicies.len()/3));
+                            if self.terrain.water.enabled {
+                                let terrain = self.terrain;
+                                let (under, over) = *self.water_coverage.get_or_insert_with(|| water::water_coverage(&terrain, &self.drawing.texture));
+                                ui.add_space(2.0);
+                                ui.label(format!("Under Water: {:.1}%", under * 100.0));
+                                ui.add_space(2.0);
+                                ui.label(format!("Over Water: {:.1}%", over * 100.0));
+                            }
                         });
 
                         ui.add_space(4.0);
                         
                         if ui.button("Compile").clicked() {
-                            let wireframe = self.mesh.lock().unwrap().wireframe;
-                            // self.mesh.lock().unwrap().destroy(_frame.gl().unwrap());
-                            let temp = bicubic_downsize(self.colors.get_image(), self.plane_density as usize + 1);
-                            let mut mesh = generate_tiled_plane_colorimg(_frame.gl().unwrap(), 20.0, 20.0, self.plane_density as usize, self.plane_density as usize, &bicubic_downsize( self.drawing.get_image(), self.plane_density as usize + 1 ), 
-                                match self.mesh_coloring {
-                                    MeshColoring::Color => Some(&temp),
-                                    MeshColoring::Height => None,
-                                }
-                            );
-                            // let mut mesh = generate_tiled_plane_colorimg(_frame.gl().unwrap(), 20.0, 20.0, self.plane_density as usize, self.plane_density as usize, bicubic_downsize( self.drawing.get_image(), self.plane_density as usize + 1 ));
-                            mesh.wireframe = wireframe;
-                            self.mesh = Arc::new(Mutex::new(mesh));
-                            self.mesh.lock().unwrap().load_buffers(_frame.gl().unwrap());
+                            match self.render_path {
+                                RenderPath::Cpu => self.rebuild_mesh(_frame.gl().unwrap(), self.plane_density as usize),
+                                RenderPath::Gpu => self.rebuild_grid(_frame.gl().unwrap(), self.plane_density as usize),
+                            }
                         };
 
                         ui.add_space(4.0);
@@ -174,21 +668,49 @@ impl eframe::App for App {
                         ui.horizontal(|ui| {
                             ui.label("Plane Density");
                             ui.add_space(1.0);                        
-                            ui.add(egui::Slider::new(&mut self.plane_density, RangeInclusive::new(5, 511)));
+                            // a flat grid is cheap to regenerate, so the GPU path follows the slider directly
+                            if ui.add(egui::Slider::new(&mut self.plane_density, RangeInclusive::new(5, 511))).changed() && self.render_path == RenderPath::Gpu {
+                                self.rebuild_grid(_frame.gl().unwrap(), self.plane_density as usize);
+                            }
                         });
 
                         ui.add_space(4.0);
                         ui.collapsing("Viewport", |ui| {
                             if ui.toggle_value(&mut self.mesh.lock().unwrap().wireframe, "Wireframe").clicked() {    
-                                self.mesh.lock().unwrap().load_buffers(&_frame.gl().unwrap());
+                                self.mesh.lock().unwrap().load_indices(_frame.gl().unwrap());
+                                let mut grid = self.grid.lock().unwrap();
+                                grid.wireframe = self.mesh.lock().unwrap().wireframe;
+                                grid.load_indices(_frame.gl().unwrap());
                             }
                             ui.add_space(5.0);
                             ui.horizontal(|ui| {
                                 ui.add_space(5.0);
-                                ui.radio_value(&mut self.mesh_coloring, MeshColoring::Color, "Color");
+                                let color = ui.radio_value(&mut self.mesh_coloring, MeshColoring::Color, "Color").changed();
+                                ui.add_space(5.0);
+                                let height = ui.radio_value(&mut self.mesh_coloring, MeshColoring::Height, "Height").changed();
+                                ui.add_space(5.0);
+                                let analysis = ui.radio_value(&mut self.mesh_coloring, MeshColoring::Analysis, "Analysis").changed();
                                 ui.add_space(5.0);
-                                ui.radio_value(&mut self.mesh_coloring, MeshColoring::Height, "Height");
-                            })
+                                let lightmap = ui.add_enabled_ui(self.lightmap.is_some(), |ui| {
+                                    ui.radio_value(&mut self.mesh_coloring, MeshColoring::Lightmap, "Lightmap").on_disabled_hover_text("Bake a lightmap first")
+                                }).inner.changed();
+                                if color || height || analysis || lightmap {
+                                    self.rebuild_mesh(_frame.gl().unwrap(), self.mesh_density);
+                                }
+                            });
+                            ui.add_space(5.0);
+                            ui.horizontal(|ui| {
+                                ui.add_space(5.0);
+                                let cpu = ui.radio_value(&mut self.render_path, RenderPath::Cpu, "CPU Mesh").changed();
+                                ui.add_space(5.0);
+                                ui.radio_value(&mut self.render_path, RenderPath::Gpu, "GPU Displacement");
+                                // the CPU mesh is not kept in sync while displacing on the GPU
+                                if cpu {
+                                    self.rebuild_mesh(_frame.gl().unwrap(), self.mesh_density);
+                                }
+                            });
+                            ui.add_space(5.0);
+                            ui.checkbox(&mut self.sculpt, "Sculpt in Viewport").on_hover_text("Left drag paints the selected tab, right drag rotates");
                         });
                         ui.add_space(4.0);
                         ui.collapsing("Camera Controls", |ui| {
@@ -204,6 +726,11 @@ impl eframe::App for App {
                                 ui.add(egui::DragValue::new(&mut self.angle.1));
                                 ui.add(egui::DragValue::new(&mut self.angle.2));
                             });
+                            if ui.button("Frame Terrain").clicked() {
+                                let extent = self.terrain.extent();
+                                self.camera.lock().unwrap().pos = Vector3::new(0.0, 0.4 * extent + self.terrain.world_height(1.0), 0.75 * extent);
+                                self.angle = (-20.0, 0.0, 0.0);
+                            }
                             ui.label("Speed");
                             ui.horizontal(|ui| {
                                 ui.add(egui::Slider::new(&mut self.speed, RangeInclusive::new(0.0, 20.0)));
@@ -232,48 +759,135 @@ impl eframe::App for App {
 
         // MOVEMENT HANDLER 
         {
+            // speeds were tuned for the default 20m terrain
+            let step = 0.01 * self.terrain.extent() / 20.0;
             if ctx.input(|i| i.key_down(egui::Key::W)) {
                 let mut cam = self.camera.lock().unwrap();
                 let look = cam.look;
-                cam.pos += look * 0.01 * self.speed;
+                cam.pos += look * step * self.speed;
             }
             if ctx.input(|i| i.key_down(egui::Key::S)) {
                 let mut cam = self.camera.lock().unwrap();
                 let look = cam.look;
-                cam.pos += look * -0.01 * self.speed;
+                cam.pos += look * -step * self.speed;
             }
     
             if ctx.input(|i| i.key_down(egui::Key::A)) {
                 let mut cam = self.camera.lock().unwrap();
                 let right = cam.right;
-                cam.pos += right * -0.01 * self.speed;
+                cam.pos += right * -step * self.speed;
             }
     
             if ctx.input(|i| i.key_down(egui::Key::D)) {
                 let mut cam = self.camera.lock().unwrap();
                 let right = cam.right;
-                cam.pos += right * 0.01 * self.speed;
+                cam.pos += right * step * self.speed;
             }
     
             if ctx.input(|i| i.key_down(egui::Key::Q)) {
                 let mut cam = self.camera.lock().unwrap();
                 let up = cam.get_up_vec() ;
-                cam.pos += up * -0.01 * self.speed;
+                cam.pos += up * -step * self.speed;
             }
             
             if ctx.input(|i| i.key_down(egui::Key::E)) {
                 let mut cam = self.camera.lock().unwrap();
                 let up = cam.get_up_vec() ;
-                cam.pos += up * 0.01 * self.speed;
+                cam.pos += up * step * self.speed;
             }
     
         }
 
 
+        if let Some(pos) = ctx.pointer_hover_pos().filter(|pos| img_rect.contains(*pos)) {
+            self.hover_uv = Some(Vector2::new((pos.x - img_rect.left()) / img_rect.width(), (pos.y - img_rect.top()) / img_rect.height()));
+        }
+
         //DRAWING LOGIC
-        match self.tab {
-            SelectedTab::Height => self.drawing.draw_update(ctx, img_rect),
-            SelectedTab::Color => self.colors.draw_update_color(ctx, img_rect, self.color),
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        match (self.mask_settings.paint, self.active_mask) {
+            (true, Some(i)) => {
+                if let Some(uv) = drawing::brush_uv(ctx, img_rect) {
+                    self.masks[i].paint(uv, BRUSH_RADIUS, if self.mask_settings.erase { -0.2 } else { 0.2 });
+                    self.mask_overlay_stale = true;
+                }
+            },
+            // a pasted clip takes the drag whatever tool is active
+            _ if self.floating.is_some() => self.selection_input(ctx, img_rect),
+            _ if self.canvas_tool == CanvasTool::Clone => self.clone_input(ctx, img_rect),
+            _ if self.canvas_tool == CanvasTool::Spline => self.spline_input(ctx, img_rect),
+            // stamps always shape the heights, whichever tab is shown
+            _ if self.canvas_tool == CanvasTool::Stamp => if let Some(pos) = ctx.input(|i| i.pointer.primary_pressed().then(|| i.pointer.interact_pos()).flatten()).filter(|pos| img_rect.contains(*pos)) {
+                let uv = Vector2::new((pos.x - img_rect.left()) / img_rect.width(), (pos.y - img_rect.top()) / img_rect.height());
+                self.stamp_settings.place(&self.terrain, &mut self.height_layers, &self.drawing.texture, uv, mask);
+            },
+            _ if self.canvas_tool != CanvasTool::Brush => self.selection_input(ctx, img_rect),
+            _ => match self.tab {
+                SelectedTab::Height => self.height_layers.selected_mut().draw_update(ctx, img_rect, mask),
+                SelectedTab::Color => self.color_layers.selected_mut().draw_update_color(ctx, img_rect, self.color, mask),
+            },
+        }
+        if self.mask_overlay_stale {
+            if let Some(mask) = self.active_mask.and_then(|i| self.masks.get(i)) {
+                self.mask_overlay.set_image(mask.overlay_image());
+            }
+            self.mask_overlay_stale = false;
+        }
+
+        self.keep_remap_edits();
+        if let Some(region) = self.splines_dirty {
+            self.apply_splines(region);
+        }
+
+        self.height_layers.sync(&mut self.drawing);
+        self.color_layers.sync(&mut self.colors);
+
+        // LIVE PREVIEW
+        {
+            let gl = _frame.gl().unwrap();
+            let height_dirty = self.drawing.take_dirty();
+            if height_dirty.is_some() {
+                self.water_coverage = None;
+                self.contours = None;
+                self.analysis_valid = false;
+                self.flow = None;
+                self.rivers = None;
+                self.basins = None;
+                self.lakes_valid = false;
+                self.height_stats = None;
+            }
+            if let Some(region) = height_dirty {
+                self.update_height_tint(Some(region));
+            }
+            // derived maps have to be current before mesh colors are resampled from them
+            self.update_analysis();
+
+            let color_dirty = match self.mesh_coloring {
+                MeshColoring::Color => self.colors.take_dirty(),
+                MeshColoring::Height => self.height_tint.take_dirty(),
+                MeshColoring::Analysis => self.analysis.take_dirty(),
+                MeshColoring::Lightmap => self.lightmap_image.take_dirty(),
+            };
+            let region = match self.mesh_coloring {
+                MeshColoring::Color | MeshColoring::Height | MeshColoring::Lightmap => match (height_dirty, color_dirty) {
+                    (Some(a), Some(b)) => Some(a.union(b)),
+                    (a, b) => a.or(b),
+                },
+                // the analysis kernels reach one pixel past the painted region
+                MeshColoring::Analysis => height_dirty.map(|r| r.grow(1, self.drawing.texture.size)),
+            };
+
+            let mut textures = self.textures.lock().unwrap();
+            if let Some(region) = height_dirty {
+                textures.height.upload_region(gl, &self.drawing.texture, region);
+            }
+            if let (Some(region), Some(cols)) = (color_dirty, self.mesh_colors()) {
+                textures.colors.upload_region(gl, cols, region);
+            }
+
+            if let (Some(region), RenderPath::Cpu) = (region, self.render_path) {
+                update_tiled_plane_region(gl, &mut self.mesh.lock().unwrap(), &self.terrain, (self.mesh_density, self.mesh_density), &self.drawing.texture, self.mesh_colors(), region);
+            }
         }
         
 
@@ -282,9 +896,33 @@ impl eframe::App for App {
         let right = rot * Vector3::new(1.0, 0.0, 0.0);
         self.camera.lock().unwrap().right = right;
         self.camera.lock().unwrap().look = look;
+        self.camera.lock().unwrap().far = 5.0 * (self.terrain.extent() + self.terrain.world_height(1.0).abs()).max(20.0);
         
         ctx.request_repaint();
     }
+
+    fn on_exit(&mut self, gl: Option<&glow::Context>) {
+        if let Some(job) = &self.ao_job {
+            job.cancel();
+        }
+        if let Some(job) = &self.light_job {
+            job.cancel();
+        }
+        if let Some(filter) = &self.filter_job {
+            filter.job.cancel();
+        }
+        if let Some(gl) = gl {
+            self.mesh.lock().unwrap().destroy(gl);
+            self.shader_program.lock().unwrap().destroy(gl);
+            self.grid.lock().unwrap().destroy(gl);
+            self.textures.lock().unwrap().destroy(gl);
+            self.terrain_program.lock().unwrap().destroy(gl);
+            self.water.lock().unwrap().destroy(gl);
+            self.contour_mesh.lock().unwrap().destroy(gl);
+            self.river_mesh.lock().unwrap().destroy(gl);
+            self.lake_mesh.lock().unwrap().destroy(gl);
+        }
+    }
 }
 
 
@@ -296,29 +934,901 @@ impl App {
             .expect("You need to run eframe with the glow backend");
 
         let drawing = Drawing::new();
+        let colors = Drawing::new();
 
-        let mesh = generate_tiled_plane_colorimg(gl, 20.0, 20.0, 100, 100, &drawing.texture, None);
+        let terrain = TerrainSettings::default();
+
+        let gradient = Gradient::earth();
+        let mut height_tint = Drawing::new();
+        height_tint.set_image(gradient.apply(&drawing.texture));
+
+        let mesh = Mesh::new(gl, generate_tiled_plane_colorimg(&terrain, 100, 100, &bicubic_downsize(drawing.get_image(), 101), Some(&bicubic_downsize(height_tint.get_image(), 101))), false);
 
         let shader_program = ShaderProgram::new(gl, "src/main.vert.glsl", "src/main.frag.glsl");
+
+        let grid = Mesh::new(gl, generate_tiled_grid(&terrain, 100, 100), false);
+        let water = Mesh::new(gl, water::generate_water_plane(&terrain), false);
+        let contour_mesh = Mesh::new_lines(gl, generate_draped_lines(&terrain, &drawing.texture, &[], Color32::BLACK));
+        let river_mesh = Mesh::new_lines(gl, generate_draped_lines(&terrain, &drawing.texture, &[], Color32::BLACK));
+        let lake_mesh = Mesh::new(gl, hydrology::generate_lake_surfaces(&terrain, &drawing.texture, &[], Color32::BLACK), false);
+        let textures = TerrainTextures {
+            height: GpuTexture::new(gl, &drawing.texture),
+            colors: GpuTexture::new(gl, &height_tint.texture)
+        };
+        let terrain_program = ShaderProgram::new(gl, "src/terrain.vert.glsl", "src/terrain.frag.glsl");
         
         let camera = Camera::default();
         
         Self { 
+            height_layers: LayerStack::new(&drawing),
+            color_layers: LayerStack::new(&colors),
             drawing,
-            colors: Drawing::new(),
+            colors,
             tab: SelectedTab::Height,
             mesh: Arc::new(Mutex::new(mesh)), 
             shader_program: Arc::new(Mutex::new(shader_program)),
+            grid: Arc::new(Mutex::new(grid)),
+            textures: Arc::new(Mutex::new(textures)),
+            terrain_program: Arc::new(Mutex::new(terrain_program)),
+            render_path: RenderPath::Cpu,
+            terrain,
+            water: Arc::new(Mutex::new(water)),
+            water_coverage: None,
+            sculpt: false,
+            hover_uv: None,
+            contour_settings: ContourSettings::default(),
+            contours: None,
+            contour_mesh: Arc::new(Mutex::new(contour_mesh)),
+            analysis_settings: AnalysisSettings::default(),
+            analysis: Drawing::new(),
+            analysis_valid: false,
+            flow_settings: FlowSettings::default(),
+            flow: None,
+            rivers: None,
+            river_overlay: Drawing::new(),
+            river_mesh: Arc::new(Mutex::new(river_mesh)),
+            fill_settings: FillSettings::default(),
+            basins: None,
+            basin_overlay: Drawing::new(),
+            lakes: Vec::new(),
+            lake_mesh: Arc::new(Mutex::new(lake_mesh)),
+            lakes_valid: true,
+            ao_settings: AoSettings::default(),
+            ao_job: None,
+            ao: None,
+            ao_image: Drawing::new(),
+            light_settings: LightmapSettings::default(),
+            light_job: None,
+            lightmap: None,
+            lightmap_image: Drawing::new(),
+            texture_rules: TextureRule::defaults(),
+            gradient,
+            height_tint,
+            masks: Vec::new(),
+            active_mask: None,
+            mask_settings: MaskSettings::default(),
+            mask_overlay: Drawing::new(),
+            mask_overlay_stale: false,
+            canvas_tool: CanvasTool::Brush,
+            selection: None,
+            selection_drag: None,
+            feather: 4,
+            clipboard: None,
+            floating: None,
+            floating_drag: None,
+            clone_source: None,
+            clone_offset: None,
+            clone_snapshot: None,
+            clone_blend: HeightBlend::Replace,
+            stamp_settings: StampSettings::default(),
+            splines: Vec::new(),
+            spline_selected: None,
+            spline_drag: None,
+            splines_dirty: None,
+            remap: RemapSettings::default(),
+            remap_preview: None,
+            height_stats: None,
+            filter_settings: FilterSettings::default(),
+            filter_job: None,
+            filter_discarded: false,
+            file_error: None,
+            transform_settings: TransformSettings::default(),
             camera: Arc::new(Mutex::new(camera)),
-            value: 0.0,
             angle: (-20.0, 0.0, 0.0),
             speed: 10.0,
             plane_density: 100,
+            mesh_density: 100,
             color: Color32::GREEN,
             mesh_coloring: MeshColoring::Height
         }
     }
 
+    // Regenerates the plane at the given density into the existing mesh buffers
+    fn rebuild_mesh(&mut self, gl: &glow::Context, density: usize) {
+        self.update_analysis();
+
+        let temp = self.mesh_colors().map(|cols| bicubic_downsize(cols.clone(), density + 1));
+        let geometry = generate_tiled_plane_colorimg(&self.terrain, density, density, &bicubic_downsize( self.drawing.get_image(), density + 1 ), temp.as_ref());
+        // the paint callback holds a clone of the same Arc, so the mesh is rebuilt in place
+        self.mesh.lock().unwrap().set_geometry(gl, geometry);
+        self.mesh_density = density;
+
+        // the displacement path samples the same color source from a texture
+        if let Some(cols) = self.mesh_colors() {
+            self.textures.lock().unwrap().colors.upload(gl, cols);
+        }
+    }
+
+    // Image the mesh is colored from, None for the height shading
+    fn mesh_colors(&self) -> Option<&ColorImage> {
+        match self.mesh_coloring {
+            MeshColoring::Color => Some(&self.colors.texture),
+            MeshColoring::Height => Some(&self.height_tint.texture),
+            MeshColoring::Analysis => Some(&self.analysis.texture),
+            MeshColoring::Lightmap => Some(&self.lightmap_image.texture),
+        }
+    }
+
+    // Replaces the selected color layer with the output of a generator, only where the active mask allows
+    fn set_colors(&mut self, img: ColorImage) {
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        let layer = self.color_layers.selected_mut();
+        layer.set_image(mask::blend_masked(mask, &layer.texture, img));
+    }
+
+    // img holds new flattened heights, the selected layer only receives the difference so its blend mode is respected
+    fn set_heights(&mut self, img: ColorImage) {
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        let size = self.drawing.texture.size;
+        let delta: Vec<f32> = (0..size[0] * size[1]).map(|i| {
+            let d = drawing::col_to_height(img.pixels[i]) - drawing::col_to_height(self.drawing.texture.pixels[i]);
+            d * mask.map_or(1.0, |mask| mask.at(i % size[0], i / size[0], size))
+        }).collect();
+        self.height_layers.add_height_delta(&delta);
+    }
+
+    fn selection_ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(egui::Slider::new(&mut self.feather, RangeInclusive::new(0, 32)).text("Feather").suffix(" px"));
+        ui.horizontal(|ui| {
+            if ui.add_enabled(self.selection.is_some(), egui::Button::new("Copy")).clicked() {
+                let img = match self.tab {
+                    SelectedTab::Height => &self.height_layers.selected().texture,
+                    SelectedTab::Color => &self.color_layers.selected().texture,
+                };
+                self.clipboard = self.selection.as_ref().and_then(|s| selection::copy(img, s, self.feather));
+            }
+            if ui.add_enabled(self.clipboard.is_some() && self.floating.is_none(), egui::Button::new("Paste")).clicked() {
+                self.paste();
+            }
+            if ui.add_enabled(self.selection.is_some(), egui::Button::new("Deselect")).clicked() {
+                self.selection = None;
+            }
+        });
+        ui.label("Copies from and pastes into the selected layer of the current tab");
+
+        let mut moved = false;
+        let mut finish = None;
+        if let Some((_, _, floating)) = &mut self.floating {
+            ui.separator();
+            moved |= ui.add(egui::Slider::new(&mut floating.rotation, RangeInclusive::new(-180.0, 180.0)).text("Rotation").suffix("°")).changed();
+            moved |= ui.add(egui::Slider::new(&mut floating.scale, RangeInclusive::new(0.1, 4.0)).logarithmic(true).text("Scale")).changed();
+            ui.label("Drag on the canvas to move");
+            ui.horizontal(|ui| {
+                if ui.button("Apply").clicked() {
+                    finish = Some(true);
+                }
+                if ui.button("Cancel").clicked() {
+                    finish = Some(false);
+                }
+            });
+        }
+        if moved {
+            self.preview_floating();
+        }
+        if let Some(apply) = finish {
+            self.finish_floating(apply);
+        }
+    }
+
+    // Floats the clipboard over the selected layer where it was copied from
+    fn paste(&mut self) {
+        let Some(clip) = self.clipboard.clone() else {
+            return;
+        };
+        let stack = match self.tab {
+            SelectedTab::Height => &self.height_layers,
+            SelectedTab::Color => &self.color_layers,
+        };
+        self.floating = Some((self.tab, stack.selected_id(), Floating::new(clip, stack.selected().get_image())));
+        self.preview_floating();
+    }
+
+    fn preview_floating(&mut self) {
+        let Some((tab, id, floating)) = &self.floating else {
+            return;
+        };
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        let stack = match tab {
+            SelectedTab::Height => &mut self.height_layers,
+            SelectedTab::Color => &mut self.color_layers,
+        };
+        if let Some(layer) = stack.layer_mut(*id) {
+            layer.drawing.set_image(floating.composite(mask));
+        }
+    }
+
+    // The preview already holds the applied result, cancelling puts the layer back
+    fn finish_floating(&mut self, apply: bool) {
+        let Some((tab, id, floating)) = self.floating.take() else {
+            return;
+        };
+        if apply {
+            return;
+        }
+        let stack = match tab {
+            SelectedTab::Height => &mut self.height_layers,
+            SelectedTab::Color => &mut self.color_layers,
+        };
+        if let Some(layer) = stack.layer_mut(id) {
+            layer.drawing.set_image(floating.base);
+        }
+    }
+
+    // Rectangle and lasso drags on the canvas, or moving the pasted clip
+    fn selection_input(&mut self, ctx: &egui::Context, img_rect: Rect) {
+        let (pressed, down, pos) = ctx.input(|i| (i.pointer.primary_pressed(), i.pointer.primary_down(), i.pointer.interact_pos()));
+        let Some(pos) = pos else {
+            return;
+        };
+        let uv = Vector2::new(
+            ((pos.x - img_rect.left()) / img_rect.width()).clamp(0.0, 1.0),
+            ((pos.y - img_rect.top()) / img_rect.height()).clamp(0.0, 1.0)
+        );
+
+        if pressed && img_rect.contains(pos) {
+            if self.floating.is_some() {
+                self.floating_drag = Some(uv);
+            } else {
+                self.selection_drag = Some(vec![uv]);
+            }
+        }
+
+        if down {
+            let mut moved = false;
+            if let (Some(from), Some((_, _, floating))) = (self.floating_drag, &mut self.floating) {
+                if uv != from {
+                    floating.center += uv - from;
+                    self.floating_drag = Some(uv);
+                    moved = true;
+                }
+            } else if let Some(points) = &mut self.selection_drag {
+                match self.canvas_tool {
+                    CanvasTool::Lasso => if points.last().is_none_or(|p| (p - uv).norm() > 0.002) {
+                        points.push(uv);
+                    },
+                    _ => {
+                        points.truncate(1);
+                        points.push(uv);
+                    },
+                }
+            }
+            if moved {
+                self.preview_floating();
+            }
+        } else {
+            self.floating_drag = None;
+            // a click without a drag clears the selection
+            if let Some(points) = self.selection_drag.take() {
+                self.selection = match self.canvas_tool {
+                    CanvasTool::Lasso if points.len() > 2 => Some(Selection { points }),
+                    CanvasTool::Rectangle if points.len() > 1 && points[0] != points[1] => Some(Selection::rectangle(points[0], points[1])),
+                    _ => None,
+                };
+            }
+        }
+    }
+
+    // Alt-click picks the source, strokes copy from the layer as it was when they started
+    fn clone_input(&mut self, ctx: &egui::Context, img_rect: Rect) {
+        let (pressed, down, alt, pos) = ctx.input(|i| (i.pointer.primary_pressed(), i.pointer.primary_down(), i.modifiers.alt, i.pointer.interact_pos()));
+        let stack = match self.tab {
+            SelectedTab::Height => &mut self.height_layers,
+            SelectedTab::Color => &mut self.color_layers,
+        };
+        let size = stack.selected().texture.size;
+        let pixel = |uv: Vector2<f32>| Vector2::new((uv.x * size[0] as f32) as i64, (uv.y * size[1] as f32) as i64);
+
+        if !down {
+            self.clone_snapshot = None;
+        }
+        let Some(pos) = pos.filter(|pos| img_rect.contains(*pos)) else {
+            return;
+        };
+        let uv = Vector2::new((pos.x - img_rect.left()) / img_rect.width(), (pos.y - img_rect.top()) / img_rect.height());
+
+        if alt {
+            if pressed {
+                self.clone_source = Some(uv);
+                self.clone_offset = None;
+            }
+            return;
+        }
+        if pressed {
+            if let Some(source) = self.clone_source {
+                self.clone_offset.get_or_insert(pixel(source) - pixel(uv));
+                self.clone_snapshot = Some(stack.selected().get_image());
+            }
+        }
+
+        let (Some(uv), Some(offset), Some(snapshot)) = (drawing::brush_uv(ctx, img_rect), self.clone_offset, &self.clone_snapshot) else {
+            return;
+        };
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        let p = pixel(uv).map(|v| v as usize);
+        match self.tab {
+            SelectedTab::Height => stack.selected_mut().clone_radius_height(p, BRUSH_RADIUS, snapshot, offset, self.clone_blend, mask),
+            SelectedTab::Color => stack.selected_mut().clone_radius_color(p, BRUSH_RADIUS, snapshot, offset, mask),
+        }
+    }
+
+    fn splines_ui(&mut self, ui: &mut egui::Ui) {
+        let before = self.splines.clone();
+
+        ui.horizontal(|ui| {
+            for (label, name, mode) in [("Add Road", "Road", SplineMode::Road), ("Add River", "River", SplineMode::River)] {
+                if ui.button(label).clicked() {
+                    self.splines.push(Spline::new(&format!("{} {}", name, self.splines.len() + 1), mode));
+                    self.spline_selected = Some(self.splines.len() - 1);
+                    self.canvas_tool = CanvasTool::Spline;
+                }
+            }
+        });
+
+        let mut remove = None;
+        for (i, spline) in self.splines.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.spline_selected, Some(i), &spline.name);
+                if ui.small_button("Delete").clicked() {
+                    remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = remove {
+            self.splines.remove(i);
+            self.spline_selected = None;
+        }
+
+        if let Some(spline) = self.spline_selected.and_then(|i| self.splines.get_mut(i)) {
+            ui.separator();
+            spline.ui(ui);
+            ui.label("Click to add points, drag to move them, right-click to remove");
+        }
+
+        if ui.button("Re-apply").on_hover_text("Rivers only dig into the terrain under them, re-apply after painting there").clicked() {
+            self.queue_splines(None);
+        }
+
+        // removing a spline shifts the ones after it, those are rebuilt too
+        let size = self.height_layers.layers[0].drawing.texture.size;
+        let changed = (0..before.len().max(self.splines.len()))
+            .filter(|&i| before.get(i) != self.splines.get(i))
+            .flat_map(|i| [before.get(i), self.splines.get(i)])
+            .flatten()
+            .filter_map(|s| s.bounds(&self.terrain, size))
+            .reduce(DirtyRect::union);
+        if let Some(region) = changed {
+            self.queue_splines(Some(region));
+        }
+    }
+
+    // Queues a spline layer rebuild over region, None for the whole canvas
+    fn queue_splines(&mut self, region: Option<DirtyRect>) {
+        let size = self.height_layers.layers[0].drawing.texture.size;
+        let region = region.unwrap_or(DirtyRect { min_x: 0, min_y: 0, max_x: size[0] - 1, max_y: size[1] - 1 });
+        self.splines_dirty = Some(self.splines_dirty.map_or(region, |r| r.union(region)));
+    }
+
+    // Queues a rebuild over where the selected spline was before an edit and where it is now
+    fn spline_edited(&mut self, before: Option<DirtyRect>) {
+        let size = self.height_layers.layers[0].drawing.texture.size;
+        let after = self.spline_selected.and_then(|i| self.splines.get(i)).and_then(|s| s.bounds(&self.terrain, size));
+        if let Some(region) = before.into_iter().chain(after).reduce(DirtyRect::union) {
+            self.queue_splines(Some(region));
+        }
+    }
+
+    // Adds, moves and removes control points of the selected spline
+    fn spline_input(&mut self, ctx: &egui::Context, img_rect: Rect) {
+        let (pressed, secondary, down, pos) = ctx.input(|i| (i.pointer.primary_pressed(), i.pointer.secondary_pressed(), i.pointer.primary_down(), i.pointer.interact_pos()));
+        if !down {
+            self.spline_drag = None;
+        }
+        let Some(pos) = pos else {
+            return;
+        };
+        let uv = Vector2::new(
+            ((pos.x - img_rect.left()) / img_rect.width()).clamp(0.0, 1.0),
+            ((pos.y - img_rect.top()) / img_rect.height()).clamp(0.0, 1.0)
+        );
+
+        let size = self.height_layers.layers[0].drawing.texture.size;
+        let before = self.spline_selected.and_then(|i| self.splines.get(i)).and_then(|s| s.bounds(&self.terrain, size));
+
+        if let Some(i) = self.spline_drag {
+            if let Some(point) = self.spline_selected.and_then(|s| self.splines.get_mut(s)).and_then(|s| s.points.get_mut(i)) {
+                if point.uv != uv {
+                    point.uv = uv;
+                    self.spline_edited(before);
+                }
+            }
+            return;
+        }
+        if !(pressed || secondary) || !img_rect.contains(pos) {
+            return;
+        }
+
+        if self.spline_selected.is_none_or(|i| i >= self.splines.len()) {
+            self.splines.push(Spline::new(&format!("Road {}", self.splines.len() + 1), SplineMode::Road));
+            self.spline_selected = Some(self.splines.len() - 1);
+        }
+        // the ground under the spline layer, the composite already has the splines in it
+        let below = self.height_layers.layers.iter().position(|l| l.generated).unwrap_or(self.height_layers.layers.len());
+        let (x, y) = (((uv.x * size[0] as f32) as usize).min(size[0] - 1), ((uv.y * size[1] as f32) as usize).min(size[1] - 1));
+        let ground = self.height_layers.flatten_region(below, DirtyRect { min_x: x, min_y: y, max_x: x, max_y: y });
+        let elevation = self.terrain.elevation(drawing::col_to_height(ground.pixels[0]));
+        let spline = &mut self.splines[self.spline_selected.unwrap()];
+
+        let to_screen = |uv: Vector2<f32>| img_rect.min + egui::vec2(uv.x * img_rect.width(), uv.y * img_rect.height());
+        let hit = spline.points.iter().position(|p| to_screen(p.uv).distance(pos) < 6.0);
+        match (hit, secondary) {
+            (Some(i), true) => {
+                spline.points.remove(i);
+                self.spline_edited(before);
+            },
+            (Some(i), false) => self.spline_drag = Some(i),
+            (None, false) => {
+                spline.points.push(ControlPoint { uv, elevation });
+                self.spline_drag = Some(spline.points.len() - 1);
+                self.spline_edited(before);
+            },
+            (None, true) => {},
+        }
+    }
+
+    // Rebuilds the generated spline layers over region, painting and generators never touch the splines themselves
+    fn apply_splines(&mut self, region: DirtyRect) {
+        let index = self.height_layers.generated_layer("Splines", BlendMode::Normal);
+        let size = self.height_layers.layers[0].drawing.texture.size;
+        let below = self.height_layers.flatten_region(index, region);
+        let (heights, colors) = splines::rasterize(&self.terrain, &self.splines, &below, size, region);
+        self.height_layers.layers[index].drawing.set_region(&heights, region);
+
+        if self.splines.iter().any(|s| s.paint) || self.color_layers.layers.iter().any(|l| l.generated) {
+            let index = self.color_layers.generated_layer("Splines", BlendMode::Normal);
+            self.color_layers.layers[index].drawing.set_region(&colors, region);
+        }
+        self.splines_dirty = None;
+    }
+
+    // Levels, curve and terraces on the selected height layer. The live preview holds the layer's
+    // original pixels until the remap is applied or the preview is turned off
+    fn remap_ui(&mut self, ui: &mut egui::Ui) {
+        let before = self.remap.clone();
+        let mut preview = self.remap_preview.is_some();
+        let mut apply = false;
+
+        self.remap.ui(ui);
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut preview, "Live Preview");
+            if ui.button("Normalize").on_hover_text("Stretches the layer's height range to 0 - 1").clicked() {
+                let base = self.remap_preview.as_ref().map_or(&self.height_layers.selected().texture, |(_, base, _)| base);
+                if let Some((lo, hi)) = remap::height_range(base) {
+                    (self.remap.in_black, self.remap.in_white) = (lo, hi);
+                }
+            }
+            apply = ui.button("Apply").clicked();
+            if ui.button("Reset").clicked() {
+                self.remap = RemapSettings::default();
+            }
+        });
+
+        // the preview follows the selection
+        if self.remap_preview.as_ref().is_some_and(|(id, _, _)| *id != self.height_layers.selected_id()) {
+            self.end_remap_preview();
+        }
+        let started = preview && self.remap_preview.is_none();
+        match (preview, self.remap_preview.is_some()) {
+            (true, false) => {
+                let base = self.height_layers.selected().get_image();
+                self.remap_preview = Some((self.height_layers.selected_id(), base.clone(), base));
+            },
+            (false, true) => self.end_remap_preview(),
+            _ => {},
+        }
+
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        if let Some((id, base, shown)) = &mut self.remap_preview {
+            if let (true, Some(layer)) = (started || apply || self.remap != before, self.height_layers.layer_mut(*id)) {
+                *shown = mask::blend_masked(mask, base, self.remap.remap_image(base));
+                layer.drawing.set_image(shown.clone());
+            }
+        } else if apply {
+            let base = self.height_layers.selected().get_image();
+            let img = mask::blend_masked(mask, &base, self.remap.remap_image(&base));
+            self.height_layers.selected_mut().set_image(img);
+        }
+
+        if apply {
+            self.remap = RemapSettings::default();
+            if let Some((id, base, _)) = &mut self.remap_preview {
+                if let Some(layer) = self.height_layers.layer_mut(*id) {
+                    *base = layer.drawing.get_image();
+                }
+            }
+        }
+    }
+
+    fn end_remap_preview(&mut self) {
+        if let Some((id, base, _)) = self.remap_preview.take() {
+            if let Some(layer) = self.height_layers.layer_mut(id) {
+                layer.drawing.set_image(base);
+            }
+        }
+    }
+
+    // Anything painted or generated into the previewed layer would be wiped by the next settings change,
+    // so an edit ends the preview and keeps the edited pixels over the original ones
+    fn keep_remap_edits(&mut self) {
+        let Some((id, base, shown)) = &self.remap_preview else {
+            return;
+        };
+        let Some(layer) = self.height_layers.layer_mut(*id) else {
+            self.remap_preview = None;
+            return;
+        };
+        if layer.drawing.texture.pixels == shown.pixels {
+            return;
+        }
+        let pixels = layer.drawing.texture.pixels.iter().zip(&shown.pixels).zip(&base.pixels)
+            .map(|((px, shown), base)| if px == shown { *base } else { *px })
+            .collect();
+        layer.drawing.set_image(ColorImage { size: base.size, pixels });
+        self.remap_preview = None;
+    }
+
+    // Applies a whole canvas transform to both layer stacks and to everything placed on the canvas.
+    // Bakes are dropped and the derived maps are rebuilt from the new heights on the next sync
+    fn transform_canvas(&mut self, transform: CanvasTransform) {
+        self.finish_floating(true);
+        self.end_remap_preview();
+        for job in [self.ao_job.take(), self.light_job.take(), self.filter_job.take().map(|filter| filter.job)].iter().flatten() {
+            job.cancel();
+        }
+
+        let size = self.height_layers.layers[0].drawing.texture.size;
+        let out = transform.output_size(size);
+        for layer in self.height_layers.layers.iter_mut().chain(self.color_layers.layers.iter_mut()) {
+            let img = transform.apply(&layer.drawing.texture);
+            layer.drawing.set_image(img);
+        }
+
+        // masks are resampled to the canvas first so crops and offsets line up pixel for pixel
+        for mask in self.masks.iter_mut() {
+            let values: Vec<f32> = (0..size[0] * size[1]).map(|i| mask.at(i % size[0], i / size[0], size)).collect();
+            mask.values = transform.map_pixels(&values, size);
+            mask.size = out;
+        }
+        self.mask_overlay_stale = true;
+
+        for point in self.splines.iter_mut().flat_map(|s| s.points.iter_mut()) {
+            point.uv = transform.map_uv(point.uv, size);
+        }
+        // queued regions were in the old canvas
+        self.splines_dirty = None;
+        if !self.splines.is_empty() {
+            self.queue_splines(None);
+        }
+        self.spline_drag = None;
+        if let Some(selection) = &mut self.selection {
+            for point in selection.points.iter_mut() {
+                *point = transform.map_uv(*point, size);
+            }
+        }
+        self.selection_drag = None;
+        self.lakes.retain_mut(|lake| match transform.map_pixel(lake.seed.0, lake.seed.1, size) {
+            Some(seed) => {
+                lake.seed = seed;
+                true
+            },
+            None => false,
+        });
+        self.lakes_valid = false;
+
+        // the clone offset is in pixels and the source may be gone, so it has to be picked again
+        self.clone_source = None;
+        self.clone_offset = None;
+        self.clone_snapshot = None;
+
+        self.ao = None;
+        self.lightmap = None;
+        self.ao_image.set_image(ColorImage::new(out, Color32::BLACK));
+        self.lightmap_image.set_image(ColorImage::new(out, Color32::BLACK));
+        if self.mesh_coloring == MeshColoring::Lightmap {
+            self.mesh_coloring = MeshColoring::Height;
+        }
+
+        transform.apply_terrain(&mut self.terrain, size);
+        self.transform_settings.crop_min = [0, 0];
+        self.transform_settings.crop_size = out;
+        self.transform_settings.resize = out;
+    }
+
+    // Mask list, generators and operations on the active mask
+    fn masks_ui(&mut self, ui: &mut egui::Ui) {
+        let active_before = self.active_mask;
+        self.mask_settings.ui(ui);
+
+        ui.radio_value(&mut self.active_mask, None, "No Mask");
+        let mut remove = None;
+        for (i, mask) in self.masks.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.active_mask, Some(i), "");
+                ui.add(egui::TextEdit::singleline(&mut mask.name).desired_width(120.0));
+                if ui.small_button("Delete").clicked() {
+                    remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = remove {
+            self.masks.remove(i);
+            self.active_mask = match self.active_mask {
+                Some(a) if a == i => None,
+                Some(a) if a > i => Some(a - 1),
+                a => a,
+            };
+        }
+
+        let size = self.drawing.texture.size;
+        let mut added = None;
+        ui.horizontal_wrapped(|ui| {
+            if ui.button("New").clicked() {
+                added = Some(Mask::new("Mask", size, 0.0));
+            }
+            if ui.button("From Height").clicked() {
+                added = Some(Mask::from_height_range(&self.terrain, &self.drawing.texture, self.mask_settings.height, self.mask_settings.blend));
+            }
+            if ui.button("From Slope").clicked() {
+                added = Some(Mask::from_slope_range(&self.terrain, &self.drawing.texture, self.mask_settings.slope, self.mask_settings.blend));
+            }
+            if ui.button("Load").clicked() {
+                if let Some(path) = rfd::FileDialog::new().pick_file() {
+                    match Mask::from_file(path.to_str().unwrap()) {
+                        Ok(mask) => {
+                            added = Some(mask);
+                            self.file_error = None;
+                        },
+                        Err(e) => self.file_error = Some(format!("Failed to load mask: {e}")),
+                    }
+                }
+            }
+        });
+        if let Some(mask) = added {
+            self.masks.push(mask);
+            self.active_mask = Some(self.masks.len() - 1);
+        }
+
+        if let Some(active) = self.active_mask {
+            ui.horizontal(|ui| {
+                if ui.button("Invert").clicked() {
+                    self.masks[active].invert();
+                    self.mask_overlay_stale = true;
+                }
+                if ui.button("Blur").clicked() {
+                    self.masks[active].blur(self.mask_settings.blur_radius);
+                    self.mask_overlay_stale = true;
+                }
+            });
+
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_salt("Mask Combine").selected_text(match self.mask_settings.combine {
+                    MaskCombine::Union => "Union",
+                    MaskCombine::Intersect => "Intersect",
+                    MaskCombine::Subtract => "Subtract",
+                    MaskCombine::Add => "Add",
+                }).show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.mask_settings.combine, MaskCombine::Union, "Union");
+                    ui.selectable_value(&mut self.mask_settings.combine, MaskCombine::Intersect, "Intersect");
+                    ui.selectable_value(&mut self.mask_settings.combine, MaskCombine::Subtract, "Subtract");
+                    ui.selectable_value(&mut self.mask_settings.combine, MaskCombine::Add, "Add");
+                });
+                ui.label("with");
+                let mut other = None;
+                for (i, mask) in self.masks.iter().enumerate() {
+                    if i != active && ui.small_button(&mask.name).clicked() {
+                        other = Some(i);
+                    }
+                }
+                if let Some(other) = other {
+                    let other = self.masks[other].clone();
+                    self.masks[active].combine(&other, self.mask_settings.combine);
+                    self.mask_overlay_stale = true;
+                }
+            });
+        }
+
+        if self.active_mask != active_before {
+            self.mask_overlay_stale = true;
+        }
+    }
+
+    // Re-tints the painted region of the height gradient image, or all of it when None
+    fn update_height_tint(&mut self, region: Option<DirtyRect>) {
+        match region {
+            Some(region) if self.height_tint.texture.size == self.drawing.texture.size => {
+                self.gradient.apply_region(&self.drawing.texture, &mut self.height_tint.texture, region);
+                self.height_tint.mark_dirty(region);
+            },
+            _ => self.height_tint.set_image(self.gradient.apply(&self.drawing.texture)),
+        }
+    }
+
+    // Recomputes the false color analysis map if it is shown anywhere and out of date
+    fn update_analysis(&mut self) {
+        if self.analysis_valid || !(self.analysis_settings.overlay || self.mesh_coloring == MeshColoring::Analysis) {
+            return;
+        }
+
+        self.analysis.set_image(analysis::analysis_image(&self.terrain, &self.drawing.texture, self.analysis_settings.kind));
+        self.analysis_valid = true;
+    }
+
+    // Re-extracts the isolines after the heights or settings changed
+    fn update_contours(&mut self, gl: &glow::Context) {
+        if !self.contour_settings.enabled || self.contours.is_some() {
+            return;
+        }
+
+        let contours = contour::extract_contours(&self.terrain, &self.drawing.texture, self.contour_settings.interval);
+        let lines: Vec<&[Vector2<f32>]> = contours.iter().map(|c| c.points.as_slice()).collect();
+        self.contour_mesh.lock().unwrap().set_geometry(gl, generate_draped_lines(&self.terrain, &self.drawing.texture, &lines, self.contour_settings.color));
+        self.contours = Some(contours);
+    }
+
+    fn current_flow(&mut self) -> &Flow {
+        self.flow.get_or_insert_with(|| hydrology::compute_flow(&self.terrain, &self.drawing.texture, self.flow_settings.method))
+    }
+
+    // Re-traces the river network, flow routing is too slow to follow every brush dab so it waits for the pointer to be released
+    fn update_rivers(&mut self, gl: &glow::Context, ctx: &egui::Context) {
+        if !self.flow_settings.enabled || self.rivers.is_some() || ctx.input(|i| i.pointer.any_down()) {
+            return;
+        }
+
+        let threshold = self.flow_settings.threshold;
+        let color = self.flow_settings.color;
+        let overlay = self.current_flow().river_image(threshold, color);
+        let rivers = self.current_flow().rivers(threshold);
+
+        self.river_overlay.set_image(overlay);
+        let lines: Vec<&[Vector2<f32>]> = rivers.iter().map(|r| r.points.as_slice()).collect();
+        self.river_mesh.lock().unwrap().set_geometry(gl, generate_draped_lines(&self.terrain, &self.drawing.texture, &lines, color));
+        self.rivers = Some(rivers);
+    }
+
+    // Basin report with per basin fill and lake actions, followed by the list of lakes
+    fn depressions_ui(&mut self, ui: &mut egui::Ui) {
+        self.update_basins(ui.ctx());
+        let Some(basins) = &self.basins else {
+            return;
+        };
+
+        let shown: Vec<usize> = (0..basins.len()).filter(|i| basins[*i].volume >= self.fill_settings.min_volume).collect();
+        let total: f32 = basins.iter().map(|b| b.volume).sum();
+        ui.label(format!("{} basins, {:.3} m³ to fill", basins.len(), total));
+
+        let mut fill = Vec::new();
+        let mut make_lake = Vec::new();
+        ui.horizontal(|ui| {
+            if ui.button("Fill All").on_hover_text("Fills every basin that is not a lake").clicked() {
+                fill.extend(0..basins.len());
+            }
+            if ui.button("Shown to Lakes").clicked() {
+                make_lake.extend(shown.iter().copied());
+            }
+        });
+
+        egui::ScrollArea::vertical().id_salt("Basin List").max_height(160.0).show(ui, |ui| {
+            egui::Grid::new("Basin Grid").num_columns(4).striped(true).show(ui, |ui| {
+                ui.label("Volume");
+                ui.label("Area");
+                ui.label("Level");
+                ui.label("Actions");
+                ui.end_row();
+                for i in shown.iter() {
+                    let basin = &basins[*i];
+                    ui.label(format!("{:.3} m³", basin.volume));
+                    ui.label(format!("{:.2} m²", basin.area));
+                    ui.label(format!("{:.3} m", basin.level));
+                    ui.horizontal(|ui| {
+                        if ui.small_button("Fill").clicked() {
+                            fill.push(*i);
+                        }
+                        if ui.small_button("Lake").clicked() {
+                            make_lake.push(*i);
+                        }
+                    });
+                    ui.end_row();
+                }
+            });
+        });
+
+        let w = self.drawing.texture.width();
+        for i in make_lake {
+            let basin = &basins[i];
+            self.lakes.push(Lake { seed: (basin.seed % w, basin.seed / w), level: basin.level });
+            self.lakes_valid = false;
+        }
+
+        if !fill.is_empty() {
+            // sinks that hold a lake are kept
+            let lake_masks: Vec<Vec<bool>> = self.lakes.iter().map(|lake| hydrology::lake_cells(&self.terrain, &self.drawing.texture, lake)).collect();
+            let mut img = self.drawing.get_image();
+            for i in fill {
+                let basin = &basins[i];
+                if !lake_masks.iter().any(|mask| mask[basin.seed]) {
+                    hydrology::fill_basin(&self.terrain, &mut img, basin);
+                }
+            }
+            self.set_heights(img);
+        }
+
+        if !self.lakes.is_empty() {
+            ui.separator();
+            let mut remove = None;
+            egui::Grid::new("Lake Grid").num_columns(3).show(ui, |ui| {
+                for (i, lake) in self.lakes.iter_mut().enumerate() {
+                    ui.label(format!("Lake {}", i + 1));
+                    if ui.add(egui::DragValue::new(&mut lake.level).speed(0.01).suffix(" m")).changed() {
+                        self.lakes_valid = false;
+                    }
+                    if ui.small_button("Remove").clicked() {
+                        remove = Some(i);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(i) = remove {
+                self.lakes.remove(i);
+                self.lakes_valid = false;
+            }
+        }
+    }
+
+    fn update_basins(&mut self, ctx: &egui::Context) {
+        if self.basins.is_some() || ctx.input(|i| i.pointer.any_down()) {
+            return;
+        }
+
+        let basins = hydrology::find_basins(&self.terrain, &self.drawing.texture, self.fill_settings.epsilon);
+        self.basin_overlay.set_image(hydrology::basin_image(&self.drawing.texture, &basins, &self.terrain));
+        self.basins = Some(basins);
+    }
+
+    // Regenerates the lake surfaces once painting has stopped, flooding follows the current heights
+    fn update_lakes(&mut self, gl: &glow::Context, ctx: &egui::Context) {
+        if self.lakes_valid || ctx.input(|i| i.pointer.any_down()) {
+            return;
+        }
+
+        let color = drawing::with_alpha(self.terrain.water.color, self.terrain.water.opacity);
+        self.lake_mesh.lock().unwrap().set_geometry(gl, hydrology::generate_lake_surfaces(&self.terrain, &self.drawing.texture, &self.lakes, color));
+        self.lakes_valid = true;
+    }
+
+    fn rebuild_grid(&mut self, gl: &glow::Context, density: usize) {
+        self.grid.lock().unwrap().set_geometry(gl, generate_tiled_grid(&self.terrain, density, density));
+    }
+
     fn custom_painting(&mut self, ui : &mut egui::Ui) {
         let (w, h) = (ui.available_width(), ui.available_height() - 5.0);
 
@@ -330,16 +1840,135 @@ impl App {
         let shader_program = self.shader_program.clone();
         let mesh = self.mesh.clone();
         let camera = self.camera.clone();
+        let terrain_program = self.terrain_program.clone();
+        let grid = self.grid.clone();
+        let textures = self.textures.clone();
+        let render_path = self.render_path;
+        let use_colors = self.mesh_colors().is_some();
+        let terrain = self.terrain;
+        let water_mesh = self.water.clone();
+        let contour_mesh = self.contour_mesh.clone();
+        let show_contours = self.contour_settings.enabled;
+        let river_mesh = self.river_mesh.clone();
+        let show_rivers = self.flow_settings.enabled;
+        let lake_mesh = self.lake_mesh.clone();
+        let show_lakes = !self.lakes.is_empty();
+
+        let hit = response.hover_pos().and_then(|pos| self.viewport_hit(rect, pos));
+        if let Some(hit) = hit {
+            self.hover_uv = Some(self.terrain.world_to_uv(hit));
+        }
 
-        self.angle.0 += response.drag_motion().y * -0.1;
-        self.angle.1 += response.drag_motion().x * -0.1;
+        if !self.sculpt || response.dragged_by(egui::PointerButton::Secondary) {
+            self.angle.0 += response.drag_motion().y * -0.1;
+            self.angle.1 += response.drag_motion().x * -0.1;
+        }
+
+        // VIEWPORT SCULPTING
+        let mask = self.active_mask.and_then(|i| self.masks.get(i));
+        if let (true, Some(hit)) = (self.sculpt, hit) {
+            let moved = ui.input(|i| i.pointer.delta().length() > 0.1);
+            if response.dragged_by(egui::PointerButton::Primary) && (moved || response.drag_started()) {
+                let uv = self.terrain.world_to_uv(hit);
+                match self.tab {
+                    SelectedTab::Height => {
+                        let pixel = terrain::uv_to_pixel(&self.drawing.texture, uv);
+                        self.height_layers.selected_mut().add_radius(pixel, BRUSH_RADIUS, mask);
+                    },
+                    SelectedTab::Color => {
+                        let pixel = terrain::uv_to_pixel(&self.colors.texture, uv);
+                        self.color_layers.selected_mut().add_radius_color(pixel, BRUSH_RADIUS, self.color, mask);
+                    },
+                }
+            }
+        }
 
         let callback = egui::PaintCallback {
             rect,
             callback: std::sync::Arc::new(egui_glow::CallbackFn::new(move |_info, painter| {
-                shader_program.lock().unwrap().paint(painter.gl(), &mesh.lock().unwrap(), &camera.lock().unwrap());
+                match render_path {
+                    RenderPath::Cpu => shader_program.lock().unwrap().paint(painter.gl(), &mesh.lock().unwrap(), &camera.lock().unwrap()),
+                    RenderPath::Gpu => terrain_program.lock().unwrap().paint_displaced(painter.gl(), &grid.lock().unwrap(), &textures.lock().unwrap(), use_colors, &terrain, &camera.lock().unwrap()),
+                }
+                if show_contours {
+                    shader_program.lock().unwrap().paint_transparent(painter.gl(), &contour_mesh.lock().unwrap(), &camera.lock().unwrap());
+                }
+                if show_rivers {
+                    shader_program.lock().unwrap().paint_transparent(painter.gl(), &river_mesh.lock().unwrap(), &camera.lock().unwrap());
+                }
+                if show_lakes {
+                    shader_program.lock().unwrap().paint_transparent(painter.gl(), &lake_mesh.lock().unwrap(), &camera.lock().unwrap());
+                }
+                if terrain.water.enabled {
+                    shader_program.lock().unwrap().paint_transparent(painter.gl(), &water_mesh.lock().unwrap(), &camera.lock().unwrap());
+                }
             })),
         };
         ui.painter().add(callback);
+
+        if let (true, Some(hit)) = (self.sculpt, hit) {
+            let ring = self.brush_ring(rect, hit);
+            ui.painter().add(egui::Shape::closed_line(ring, egui::Stroke::new(1.5, Color32::WHITE)));
+        }
+    }
+
+    // Readout of the terrain under the cursor in either the canvas or the viewport
+    fn status_bar(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if let Some(error) = &self.file_error {
+                ui.colored_label(Color32::from_rgb(220, 80, 60), error);
+                if ui.small_button("Dismiss").clicked() {
+                    self.file_error = None;
+                }
+                ui.separator();
+            }
+
+            let Some(uv) = self.hover_uv else {
+                ui.label("Hover the canvas or viewport");
+                return;
+            };
+
+            let img = &self.drawing.texture;
+            let pixel = terrain::uv_to_pixel(img, uv);
+            let world = self.terrain.uv_to_world(img, uv);
+            let elevation = self.terrain.elevation(terrain::sample_height(img, uv));
+            let slope = self.terrain.slope_degrees(img, pixel.x, pixel.y);
+            let col_pixel = terrain::uv_to_pixel(&self.colors.texture, uv);
+            let col = self.colors.texture[(col_pixel.x, col_pixel.y)];
+
+            ui.label(format!("Pixel: {}, {}", pixel.x, pixel.y));
+            ui.separator();
+            ui.label(format!("X: {:.2} m  Z: {:.2} m", world.x, world.z));
+            ui.separator();
+            ui.label(format!("Elevation: {:.3} m", elevation));
+            ui.separator();
+            ui.label(format!("Slope: {:.1}°", slope));
+            ui.separator();
+            ui.label("Color:");
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), egui::Sense::hover());
+            ui.painter().rect_filled(rect, 2.0, col);
+            ui.label(format!("#{:02X}{:02X}{:02X}", col.r(), col.g(), col.b()));
+        });
+    }
+
+    // Terrain position under a screen position inside the viewport
+    fn viewport_hit(&self, rect: Rect, pos: Pos2) -> Option<Vector3<f32>> {
+        let ndc = Vector2::new((pos.x - rect.left()) / rect.width() * 2.0 - 1.0, 1.0 - (pos.y - rect.top()) / rect.height() * 2.0);
+        let (origin, dir) = self.camera.lock().unwrap().screen_ray(ndc);
+        self.terrain.raycast(&self.drawing.texture, origin, dir)
+    }
+
+    // Brush outline draped over the heightfield and projected into the viewport
+    fn brush_ring(&self, rect: Rect, hit: Vector3<f32>) -> Vec<Pos2> {
+        let center = self.terrain.world_to_uv(hit);
+        let radius = Vector2::new(BRUSH_RADIUS as f32 / self.drawing.texture.width() as f32, BRUSH_RADIUS as f32 / self.drawing.texture.height() as f32);
+        let camera = self.camera.lock().unwrap();
+
+        (0..48).filter_map(|i| {
+            let a = i as f32 / 48.0 * std::f32::consts::TAU;
+            let uv = center + Vector2::new(a.cos() * radius.x, a.sin() * radius.y);
+            let ndc = camera.project(self.terrain.uv_to_world(&self.drawing.texture, uv))?;
+            Some(Pos2::new(rect.left() + (ndc.x + 1.0) / 2.0 * rect.width(), rect.top() + (1.0 - ndc.y) / 2.0 * rect.height()))
+        }).collect()
     }
 }
\ No newline at end of file
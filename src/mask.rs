@@ -0,0 +1,229 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Ui};
+use nalgebra::Vector2;
+
+use crate::{analysis::{self, AnalysisKind}, drawing::colorimage_from_image, terrain::TerrainSettings, texturing::range_weight};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MaskCombine {
+    Union,
+    Intersect,
+    Subtract,
+    Add
+}
+
+
+// Grayscale selection in [0, 1] that limits where brushes, generators and filters apply
+#[derive(Clone)]
+pub struct Mask {
+    pub name: String,
+    pub size: [usize; 2],
+    pub values: Vec<f32>
+}
+
+
+impl Mask {
+    pub fn new(name: &str, size: [usize; 2], value: f32) -> Self {
+        Self {
+            name: name.to_string(),
+            size,
+            values: vec![value; size[0] * size[1]]
+        }
+    }
+
+    // Covers elevations inside range, fading out over blend metres
+    pub fn from_height_range(settings: &TerrainSettings, img: &ColorImage, range: (f32, f32), blend: f32) -> Self {
+        Self {
+            name: format!("Height {:.2} - {:.2} m", range.0, range.1),
+            size: img.size,
+            values: settings.elevations(img).iter().map(|z| range_weight(*z, range, blend)).collect()
+        }
+    }
+
+    // Covers slopes inside range, fading out over blend degrees
+    pub fn from_slope_range(settings: &TerrainSettings, img: &ColorImage, range: (f32, f32), blend: f32) -> Self {
+        Self {
+            name: format!("Slope {:.0} - {:.0}°", range.0, range.1),
+            size: img.size,
+            values: analysis::compute(settings, img, AnalysisKind::Slope).iter().map(|s| range_weight(*s, range, blend)).collect()
+        }
+    }
+
+    pub fn from_file(path: &str) -> image::ImageResult<Self> {
+        let img = colorimage_from_image(path)?;
+        Ok(Self {
+            name: std::path::Path::new(path).file_stem().map_or("Mask".to_string(), |s| s.to_string_lossy().to_string()),
+            size: img.size,
+            values: img.pixels.iter().map(|px| (px.r() as f32 + px.g() as f32 + px.b() as f32) / (3.0 * 255.0)).collect()
+        })
+    }
+
+    // Value for pixel (x, y) of an image with the given size, nearest neighbour when the sizes differ
+    pub fn at(&self, x: usize, y: usize, size: [usize; 2]) -> f32 {
+        let mx = (x * self.size[0] / size[0].max(1)).min(self.size[0] - 1);
+        let my = (y * self.size[1] / size[1].max(1)).min(self.size[1] - 1);
+        self.values[my * self.size[0] + mx]
+    }
+
+    // Soft round dab, negative amounts erase
+    pub fn paint(&mut self, uv: Vector2<f32>, radius: usize, amount: f32) {
+        let (w, h) = (self.size[0] as i64, self.size[1] as i64);
+        let (cx, cy) = ((uv.x * w as f32) as i64, (uv.y * h as f32) as i64);
+        let r = radius as i64;
+
+        for y in (cy - r).max(0)..=(cy + r).min(h - 1) {
+            for x in (cx - r).max(0)..=(cx + r).min(w - 1) {
+                let d = (((x - cx).pow(2) + (y - cy).pow(2)) as f32).sqrt() / radius.max(1) as f32;
+                if d > 1.0 {
+                    continue;
+                }
+                let falloff = 1.0 - d * d;
+                let i = (y * w + x) as usize;
+                self.values[i] = (self.values[i] + amount * falloff).clamp(0.0, 1.0);
+            }
+        }
+    }
+
+    pub fn invert(&mut self) {
+        self.values.iter_mut().for_each(|v| *v = 1.0 - *v);
+    }
+
+    // Two passes of a separable box blur, close to a gaussian
+    pub fn blur(&mut self, radius: usize) {
+        let (w, h) = (self.size[0], self.size[1]);
+        for _ in 0..2 {
+            self.values = box_blur(&self.values, w, h, radius, true);
+            self.values = box_blur(&self.values, w, h, radius, false);
+        }
+    }
+
+    pub fn combine(&mut self, other: &Mask, op: MaskCombine) {
+        let size = self.size;
+        for y in 0..size[1] {
+            for x in 0..size[0] {
+                let b = other.at(x, y, size);
+                let a = &mut self.values[y * size[0] + x];
+                *a = match op {
+                    MaskCombine::Union => a.max(b),
+                    MaskCombine::Intersect => a.min(b),
+                    MaskCombine::Subtract => (*a - b).max(0.0),
+                    MaskCombine::Add => (*a + b).min(1.0),
+                };
+            }
+        }
+    }
+
+    // Translucent red where the mask is set, for the 2D canvas
+    pub fn overlay_image(&self) -> ColorImage {
+        ColorImage {
+            size: self.size,
+            pixels: self.values.iter().map(|v| Color32::from_rgba_unmultiplied(255, 0, 0, (v * 140.0) as u8)).collect()
+        }
+    }
+}
+
+
+// Moving average along rows or columns with clamped borders
+fn box_blur(values: &[f32], w: usize, h: usize, radius: usize, horizontal: bool) -> Vec<f32> {
+    let (len, lines) = if horizontal { (w, h) } else { (h, w) };
+    let index = |line: usize, i: usize| if horizontal { line * w + i } else { i * w + line };
+    let r = radius as i64;
+
+    let mut out = vec![0.0; w * h];
+    for line in 0..lines {
+        let at = |i: i64| values[index(line, i.clamp(0, len as i64 - 1) as usize)];
+        let mut sum: f32 = (-r..=r).map(at).sum();
+        for i in 0..len as i64 {
+            out[index(line, i as usize)] = sum / (2 * r + 1) as f32;
+            sum += at(i + r + 1) - at(i - r);
+        }
+    }
+    out
+}
+
+
+// Lerps every channel from before to after by the mask, after is returned as is without a mask
+pub fn blend_masked(mask: Option<&Mask>, before: &ColorImage, after: ColorImage) -> ColorImage {
+    let Some(mask) = mask.filter(|_| before.size == after.size) else {
+        return after;
+    };
+
+    let size = after.size;
+    let mut out = after;
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            let t = mask.at(x, y, size);
+            let i = y * size[0] + x;
+            let (a, b) = (before.pixels[i].to_array(), out.pixels[i].to_array());
+            let mix = |c: usize| (a[c] as f32 + (b[c] as f32 - a[c] as f32) * t).round() as u8;
+            out.pixels[i] = Color32::from_rgba_premultiplied(mix(0), mix(1), mix(2), mix(3));
+        }
+    }
+    out
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct MaskSettings {
+    pub overlay: bool,
+    pub paint: bool,
+    pub erase: bool,
+    pub height: (f32, f32),
+    pub slope: (f32, f32),
+    pub blend: f32,
+    pub blur_radius: usize,
+    pub combine: MaskCombine
+}
+
+
+impl MaskSettings {
+    pub fn default() -> Self {
+        Self {
+            overlay: true,
+            paint: false,
+            erase: false,
+            height: (0.0, 2.0),
+            slope: (0.0, 30.0),
+            blend: 0.1,
+            blur_radius: 4,
+            combine: MaskCombine::Union
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.overlay, "Show Overlay");
+            ui.checkbox(&mut self.paint, "Paint Mask");
+            ui.add_enabled(self.paint, egui::Checkbox::new(&mut self.erase, "Erase"));
+        });
+
+        egui::Grid::new("Mask Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Height");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.height.0).speed(0.05).suffix(" m"));
+                ui.add(egui::DragValue::new(&mut self.height.1).speed(0.05).suffix(" m"));
+            });
+            ui.end_row();
+
+            ui.label("Slope");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.slope.0).speed(0.5).range(RangeInclusive::new(0.0, 90.0)).suffix("°"));
+                ui.add(egui::DragValue::new(&mut self.slope.1).speed(0.5).range(RangeInclusive::new(0.0, 90.0)).suffix("°"));
+            });
+            ui.end_row();
+
+            ui.label("Edge Blend");
+            ui.add(egui::DragValue::new(&mut self.blend).speed(0.01).range(RangeInclusive::new(0.0, 100.0))).on_hover_text("In metres for height and degrees for slope");
+            ui.end_row();
+
+            ui.label("Blur Radius");
+            ui.add(egui::Slider::new(&mut self.blur_radius, RangeInclusive::new(1, 64)).suffix(" px"));
+            ui.end_row();
+        });
+
+        self.height.1 = self.height.1.max(self.height.0);
+        self.slope.1 = self.slope.1.max(self.slope.0);
+    }
+}
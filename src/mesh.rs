@@ -1,10 +1,64 @@
-use eframe::glow::{self, Context, HasContext as _};
-use egui::{Color32, ColorImage, Vec2};
+use eframe::glow::{self, HasContext as _};
+use egui::{Color32, ColorImage};
 use nalgebra::{Vector2, Vector3, Vector4};
 
-use crate::drawing;
+use crate::{drawing::{bicubic_sample, col_to_height, DirtyRect}, gradient::Gradient, terrain::TerrainSettings};
 
 
+// GL buffer plus the number of bytes currently allocated for it, so uploads of the
+// same size can go through buffer_sub_data instead of reallocating
+#[derive(Debug)]
+pub struct GpuBuffer {
+    pub buffer: glow::Buffer,
+    size: usize
+}
+
+impl GpuBuffer {
+    pub fn new(gl: &glow::Context) -> Self {
+        unsafe {
+            Self {
+                buffer: gl.create_buffer().expect("Cannot create buffer"),
+                size: 0
+            }
+        }
+    }
+
+    pub fn upload(&mut self, gl: &glow::Context, target: u32, data: &[u8]) {
+        unsafe {
+            gl.bind_buffer(target, Some(self.buffer));
+            if self.size == data.len() {
+                gl.buffer_sub_data_u8_slice(target, 0, data);
+            } else {
+                gl.buffer_data_u8_slice(target, data, glow::DYNAMIC_DRAW);
+                self.size = data.len();
+            }
+        }
+    }
+
+    pub fn upload_range(&mut self, gl: &glow::Context, target: u32, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.size, "Buffer range upload out of bounds");
+        unsafe {
+            gl.bind_buffer(target, Some(self.buffer));
+            gl.buffer_sub_data_u8_slice(target, offset as i32, data);
+        }
+    }
+
+    pub fn destroy(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_buffer(self.buffer);
+        }
+    }
+}
+
+
+// CPU side vertex data, produced by the generators and handed to a Mesh for upload
+pub struct Geometry {
+    pub positions: Vec<Vector3<f32>>,
+    pub indicies: Vec<u32>,
+    pub uvs: Vec<Vector2<f32>>,
+    pub colors: Vec<Color32>
+}
+
 
 #[derive(Debug)]
 pub struct Mesh {
@@ -13,131 +67,169 @@ pub struct Mesh {
     uvs: Vec<Vector2<f32>>,
     pub colors: Vec<Vector4<f32>>,
     pub vertex_array: glow::VertexArray,
-    pub position_buffer: glow::Buffer,
-    pub color_buffer: glow::Buffer,
-    pub index_buffer: glow::Buffer,
-    pub uv_buffer: glow::Buffer,
+    pub position_buffer: GpuBuffer,
+    pub color_buffer: GpuBuffer,
+    pub index_buffer: GpuBuffer,
+    pub uv_buffer: GpuBuffer,
     pub index_buffer_size: u32,
-    pub wireframe: bool
+    pub wireframe: bool,
+    // indicies are line segment pairs instead of triangles
+    pub lines: bool
 }
 
 
 impl Mesh {
-    pub fn new(gl: &glow::Context, positions: Vec<Vector3<f32>>, indicies: Vec<u32>, uvs: Vec<Vector2<f32>>, colors: Vec<Color32>, wireframe: bool) -> Self {
-        use glow::HasContext as _;
-
-        unsafe {
-            let vert_count = positions.len();
-
-            let uvs = uvs.clone();
-            
-            let colors: Vec<Vector4<f32>> = colors.iter().map(|x| {
-                Vector4::new(x.r() as f32 / 255.0, x.g() as f32 / 255.0, x.b() as f32 / 255.0, 1.0)
-            }).collect();
+    pub fn new(gl: &glow::Context, geometry: Geometry, wireframe: bool) -> Self {
+        Self::build(gl, geometry, wireframe, false)
+    }
 
-            let position_buffer: glow::NativeBuffer = gl.create_buffer().expect("Cannot create position buffer");
-            let color_buffer = gl.create_buffer().expect("Cannot create color buffer");
-            let uv_buffer = gl.create_buffer().expect("Cannot create uv buffer");
-            let index_buffer = gl.create_buffer().expect("Cannot create index buffer");
+    pub fn new_lines(gl: &glow::Context, geometry: Geometry) -> Self {
+        Self::build(gl, geometry, false, true)
+    }
 
+    fn build(gl: &glow::Context, geometry: Geometry, wireframe: bool, lines: bool) -> Self {
+        unsafe {
             let vertex_array = gl.create_vertex_array().expect("Cannot create vertex array");
 
             let mut x = Self {
-                positions: positions.clone(), 
-                indicies: indicies.clone(),
-                uvs: uvs.clone(),
-                colors: colors.clone(),
+                positions: geometry.positions,
+                indicies: geometry.indicies,
+                uvs: geometry.uvs,
+                colors: geometry.colors.iter().map(|x| col_to_vertex_color(*x)).collect(),
                 vertex_array,
-                position_buffer,
-                color_buffer,
-                index_buffer,
-                uv_buffer,
-                index_buffer_size: (if wireframe {2} else {1})*indicies.len() as u32,
-                wireframe
+                position_buffer: GpuBuffer::new(gl),
+                color_buffer: GpuBuffer::new(gl),
+                index_buffer: GpuBuffer::new(gl),
+                uv_buffer: GpuBuffer::new(gl),
+                index_buffer_size: 0,
+                wireframe,
+                lines
             };
 
+            // attribute layout is part of the VAO and only needs to be set up once,
+            // re-uploading buffer contents afterwards keeps the bindings intact
+            gl.bind_vertex_array(Some(x.vertex_array));
+            gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(x.index_buffer.buffer));
+
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(x.position_buffer.buffer));
+            gl.vertex_attrib_pointer_f32(0, 4, glow::FLOAT, false, 0, 0);  // Position (4 floats per vertex)
+            gl.enable_vertex_attrib_array(0);  // Enable position attribute
+
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(x.color_buffer.buffer));
+            gl.vertex_attrib_pointer_f32(1, 4, glow::FLOAT, false, 0, 0);  // Color (4 floats per vertex)
+            gl.enable_vertex_attrib_array(1);  // Enable color attribute
+
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(x.uv_buffer.buffer));
+            gl.vertex_attrib_pointer_f32(2, 2, glow::FLOAT, false, 0, 0);
+            gl.enable_vertex_attrib_array(2);  // Enable uv attribute
+
+            gl.bind_vertex_array(None);
+
             x.load_buffers(gl);
 
             x
         }
     }
 
+    // Replaces the vertex data while keeping the GL objects, buffers of unchanged size are updated in place
+    pub fn set_geometry(&mut self, gl: &glow::Context, geometry: Geometry) {
+        self.positions = geometry.positions;
+        self.indicies = geometry.indicies;
+        self.uvs = geometry.uvs;
+        self.colors = geometry.colors.iter().map(|x| col_to_vertex_color(*x)).collect();
+
+        self.load_buffers(gl);
+    }
 
     pub fn load_buffers(&mut self, gl: &glow::Context) {
-        unsafe {
-            self.position_buffer = gl.create_buffer().expect("Cannot create position buffer");
-            self.color_buffer = gl.create_buffer().expect("Cannot create color buffer");
-            self.uv_buffer = gl.create_buffer().expect("Cannot create uv buffer");
-            self.index_buffer = gl.create_buffer().expect("Cannot create index buffer");
+        self.load_indices(gl);
 
-            self.vertex_array = gl.create_vertex_array().expect("Cannot create vertex array");
+        let positions = self.positions.iter().flat_map(|x| [x.x, x.y, x.z, 1.0]).collect::<Vec<f32>>();
+        self.position_buffer.upload(gl, glow::ARRAY_BUFFER, bytemuck::cast_slice(&positions));
 
-            // gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
-            gl.bind_vertex_array(Some(self.vertex_array));
-            gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(self.index_buffer));
-            gl.buffer_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, bytemuck::cast_slice(&self.indicies.chunks_exact(3).map(|x| {
+        let colors = self.colors.iter().flat_map(|x| [x.x, x.y, x.z, x.w]).collect::<Vec<f32>>();
+        self.color_buffer.upload(gl, glow::ARRAY_BUFFER, bytemuck::cast_slice(&colors));
+
+        let uvs = self.uvs.iter().flat_map(|x| [x.x, x.y]).collect::<Vec<f32>>();
+        self.uv_buffer.upload(gl, glow::ARRAY_BUFFER, bytemuck::cast_slice(&uvs));
+    }
+
+    // Re-uploads positions and colors of the vertices in start..end after they were edited on the CPU
+    pub fn load_vertex_range(&mut self, gl: &glow::Context, start: usize, end: usize) {
+        let positions = self.positions[start..end].iter().flat_map(|x| [x.x, x.y, x.z, 1.0]).collect::<Vec<f32>>();
+        self.position_buffer.upload_range(gl, glow::ARRAY_BUFFER, start * 4 * 4, bytemuck::cast_slice(&positions));
+
+        let colors = self.colors[start..end].iter().flat_map(|x| [x.x, x.y, x.z, x.w]).collect::<Vec<f32>>();
+        self.color_buffer.upload_range(gl, glow::ARRAY_BUFFER, start * 4 * 4, bytemuck::cast_slice(&colors));
+    }
+
+    // Only the index buffer depends on the wireframe flag
+    pub fn load_indices(&mut self, gl: &glow::Context) {
+        let indices = if self.lines {
+            self.indicies.clone()
+        } else {
+            self.indicies.chunks_exact(3).flat_map(|x| {
                 if self.wireframe {
                     [x[0], x[1], x[1], x[2], x[2], x[0]].to_vec()
                 } else {
                     [x[0], x[1], x[2]].to_vec()
                 }
-            } ).flatten().collect::<Vec<u32>>()), glow::STATIC_DRAW);
+            }).collect::<Vec<u32>>()
+        };
 
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.position_buffer));
-            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, bytemuck::cast_slice(&self.positions.iter().flat_map(|x| {
-                vec![x.x, x.y, x.z, 1.0].into_iter()
-            }).collect::<Vec<f32>>()), glow::STATIC_DRAW);
-            gl.vertex_attrib_pointer_f32(0, 4, glow::FLOAT, false, 0, 0);  // Position (2 floats per vertex)
-            gl.enable_vertex_attrib_array(0);  // Enable position attribute
-
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.color_buffer));
-            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, bytemuck::cast_slice(&self.colors.iter().flat_map(|x| {
-                if !self.wireframe {
-                    vec![x.x, x.y, x.z, x.w].into_iter()
-                } else {
-                    // vec![1.0, 1.0, 1.0, 1.0].into_iter()
-                    vec![x.x, x.y, x.z, x.w].into_iter()
-                }
-                
-            }).collect::<Vec<f32>>()), glow::STATIC_DRAW);
-            gl.vertex_attrib_pointer_f32(1, 4, glow::FLOAT, false, 0, 0);  // Color (4 floats per vertex)
-            gl.enable_vertex_attrib_array(1);  // Enable color attribute
+        unsafe {
+            // the element array binding is VAO state
+            gl.bind_vertex_array(Some(self.vertex_array));
+            self.index_buffer.upload(gl, glow::ELEMENT_ARRAY_BUFFER, bytemuck::cast_slice(&indices));
+            gl.bind_vertex_array(None);
+        }
 
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.uv_buffer));
-            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, bytemuck::cast_slice(&self.uvs.iter().flat_map(|x|{
-                vec![x.x, x.y].into_iter()
-            }).collect::<Vec<f32>>()), glow::STATIC_DRAW);
-            gl.vertex_attrib_pointer_f32(2, 2, glow::FLOAT, false, 0, 0);
-            gl.enable_vertex_attrib_array(2);  // Enable uv attribute
+        self.index_buffer_size = indices.len() as u32;
+    }
 
-            self.index_buffer_size = (if self.wireframe {2} else {1})*self.indicies.len() as u32;
-        }
+    pub fn draw_mode(&self) -> u32 {
+        if self.wireframe || self.lines {glow::LINES} else {glow::TRIANGLES}
     }
 
     pub fn destroy(&self, gl: &glow::Context) {
-        use glow::HasContext as _;
         unsafe {
-            gl.delete_buffer(self.position_buffer);
             gl.delete_vertex_array(self.vertex_array);
-            gl.delete_buffer(self.color_buffer);
-            gl.delete_buffer(self.index_buffer);
-            gl.delete_buffer(self.uv_buffer);
         }
+        self.position_buffer.destroy(gl);
+        self.color_buffer.destroy(gl);
+        self.index_buffer.destroy(gl);
+        self.uv_buffer.destroy(gl);
     }
 
 }
 
 
+fn col_to_vertex_color(x: Color32) -> Vector4<f32> {
+    let [r, g, b, a] = x.to_srgba_unmultiplied();
+    Vector4::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, a as f32 / 255.0)
+}
+
+
 // let height  = img.pixels[y * (tiles_x+1) + x].to_array().iter().map(|x| *x as f32).sum::<f32>() * ((3.0 / 255.0) / 4.0);
 
 
-pub fn generate_tiled_plane_colorimg(gl: &Context, width: f32, height: f32, tiles_x: usize, tiles_y: usize, img: &ColorImage, cols: Option<&ColorImage>) -> Mesh {
+fn plane_vertex(settings: &TerrainSettings, height_px: Color32, col: Option<Color32>) -> (f32, Color32) {
+    let h = col_to_height(height_px);
+    let color = match col {
+        Some(col) => col,
+        None => Gradient::grayscale().sample(h),
+    };
+    (settings.world_height(h), settings.water.shore_tint(color, settings.elevation(h)))
+}
+
+
+pub fn generate_tiled_plane_colorimg(settings: &TerrainSettings, tiles_x: usize, tiles_y: usize, img: &ColorImage, cols: Option<&ColorImage>) -> Geometry {
+    let (width, height) = (settings.width, settings.length);
     let tile_width = width / tiles_x as f32;
     let tile_height = height / tiles_y as f32;
 
     let mut positions: Vec<Vector3<f32>> = Vec::new();
     let mut uvs: Vec<Vector2<f32>> = Vec::new();
-    let mut indices: Vec<u32> = Vec::new();
     let mut colors: Vec<Color32> = Vec::new();
 
     // println!("{} tiles x", tiles_x);
@@ -153,19 +245,81 @@ pub fn generate_tiled_plane_colorimg(gl: &Context, width: f32, height: f32, tile
 
             // let height  = img.pixels[y * (tiles_x+1) + x].to_array().iter().map(|x| *x as f32).sum::<f32>() * (1.0 / (3.0 * 255.0)) * 4.0;
             // println!("{}", 0.6 * (height / 4.0) + 0.2);
-            let height = img.pixels[y * (tiles_x+1) + x].to_array().iter().map(|x| *x as f32).sum::<f32>() * (1.0 / (3.0 * 255.0)) * 4.0;
-            if let Some(col) = cols {
-                colors.push(col.pixels[y * (tiles_x+1) + x]);
-            } else {
-                colors.push(drawing::vec4_to_col(Vector4::new(0.6 * (height / 4.0) + 0.1, 0.6 * (height / 4.0) + 0.1, 0.6 * (height / 4.0) + 0.1, 1.0)));
-            }
+            let (height, color) = plane_vertex(settings, img.pixels[y * (tiles_x+1) + x], cols.map(|col| col.pixels[y * (tiles_x+1) + x]));
+            colors.push(color);
 
             positions.push(Vector3::new(offset_x, height, offset_y));
             uvs.push(Vector2::new(x as f32 / tiles_x as f32, y as f32 / tiles_y as f32));
         }
     }
 
-    
+
+    Geometry {
+        positions,
+        indicies: grid_indices(tiles_x, tiles_y),
+        uvs,
+        colors
+    }
+}
+
+
+// Line geometry draped over the heightfield, lifted slightly to avoid z-fighting. Lines are polylines of drawing uvs
+pub fn generate_draped_lines(settings: &TerrainSettings, img: &ColorImage, lines: &[&[Vector2<f32>]], color: Color32) -> Geometry {
+    let lift = 0.001 * settings.extent();
+
+    let mut positions = Vec::new();
+    let mut indicies = Vec::new();
+    let mut uvs = Vec::new();
+
+    for line in lines {
+        let start = positions.len() as u32;
+        for p in line.iter() {
+            let mut pos = settings.uv_to_world(img, *p);
+            pos.y += lift;
+            positions.push(pos);
+            uvs.push(*p);
+        }
+        for i in 1..line.len() as u32 {
+            indicies.push(start + i - 1);
+            indicies.push(start + i);
+        }
+    }
+
+    Geometry {
+        colors: vec![color; positions.len()],
+        positions,
+        indicies,
+        uvs
+    }
+}
+
+
+// Flat grid for the displacement render path, heights are applied in terrain.vert.glsl
+pub fn generate_tiled_grid(settings: &TerrainSettings, tiles_x: usize, tiles_y: usize) -> Geometry {
+    let (width, height) = (settings.width, settings.length);
+    let mut positions: Vec<Vector3<f32>> = Vec::new();
+    let mut uvs: Vec<Vector2<f32>> = Vec::new();
+
+    for x in 0..=tiles_x {
+        for y in 0..=tiles_y {
+            positions.push(Vector3::new(x as f32 * width / tiles_x as f32 - width / 2.0, 0.0, y as f32 * height / tiles_y as f32 - height / 2.0));
+            uvs.push(Vector2::new(x as f32 / tiles_x as f32, y as f32 / tiles_y as f32));
+        }
+    }
+
+    Geometry {
+        colors: vec![Color32::WHITE; positions.len()],
+        positions,
+        indicies: grid_indices(tiles_x, tiles_y),
+        uvs
+    }
+}
+
+
+// Two triangles per tile, vertices are laid out column by column
+fn grid_indices(tiles_x: usize, tiles_y: usize) -> Vec<u32> {
+    let mut indices: Vec<u32> = Vec::new();
+
     for x in 0..=tiles_x {
         for y in 0..=tiles_y {
             if x != 0 && y != 0 {
@@ -190,13 +344,39 @@ pub fn generate_tiled_plane_colorimg(gl: &Context, width: f32, height: f32, tile
         }
     }
 
-    // Create the mesh
-    Mesh::new(&gl,
-        positions,
-        indices,
-        uvs,
-        colors,
-        false
-    )
+    indices
 }
 
+
+// Re-samples only the vertices of a plane from generate_tiled_plane_colorimg whose bicubic footprint
+// touches the changed pixel region, and uploads them column by column (columns are contiguous in the buffers).
+// img and cols are the full resolution drawings, sampled the same way bicubic_downsize would.
+pub fn update_tiled_plane_region(gl: &glow::Context, mesh: &mut Mesh, settings: &TerrainSettings, tiles: (usize, usize), img: &ColorImage, cols: Option<&ColorImage>, region: DirtyRect) {
+    let (tiles_x, tiles_y) = tiles;
+    if mesh.positions.len() != (tiles_x + 1) * (tiles_y + 1) {
+        return;
+    }
+
+    let scale_x = img.width() as f32 / (tiles_x + 1) as f32;
+    let scale_y = img.height() as f32 / (tiles_y + 1) as f32;
+
+    // the 4x4 kernel reaches two source pixels in each direction
+    let min_x = ((region.min_x as f32 - 2.0) / scale_x).floor().max(0.0) as usize;
+    let min_y = ((region.min_y as f32 - 2.0) / scale_y).floor().max(0.0) as usize;
+    let max_x = (((region.max_x as f32 + 2.0) / scale_x).ceil() as usize).min(tiles_x);
+    let max_y = (((region.max_y as f32 + 2.0) / scale_y).ceil() as usize).min(tiles_y);
+
+    for x in min_x..=max_x {
+        for y in min_y..=max_y {
+            let (src_x, src_y) = (x as f32 * scale_x, y as f32 * scale_y);
+            let (height, color) = plane_vertex(settings, bicubic_sample(img, src_x, src_y), cols.map(|col| bicubic_sample(col, src_x, src_y)));
+
+            let idx = y + x * (tiles_y + 1);
+            mesh.positions[idx].y = height;
+            mesh.colors[idx] = col_to_vertex_color(color);
+        }
+
+        let column = x * (tiles_y + 1);
+        mesh.load_vertex_range(gl, column + min_y, column + max_y + 1);
+    }
+}
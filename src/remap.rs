@@ -0,0 +1,309 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Sense, Stroke, Ui};
+
+use crate::drawing::{height_to_col, layer_height, with_alpha};
+
+
+// Height to height transfer function, applied in the order the fields are listed
+#[derive(Clone, PartialEq)]
+pub struct RemapSettings {
+    pub in_black: f32,
+    pub in_white: f32,
+    pub gamma: f32,
+    pub out_black: f32,
+    pub out_white: f32,
+    // control points of a monotone curve, sorted by input
+    pub curve: Vec<(f32, f32)>,
+    // 0 turns terracing off
+    pub terraces: usize,
+    pub sharpness: f32,
+    pub invert: bool,
+    pub clamp: (f32, f32)
+}
+
+
+impl RemapSettings {
+    pub fn default() -> Self {
+        Self {
+            in_black: 0.0,
+            in_white: 1.0,
+            gamma: 1.0,
+            out_black: 0.0,
+            out_white: 1.0,
+            curve: vec![(0.0, 0.0), (1.0, 1.0)],
+            terraces: 0,
+            sharpness: 0.7,
+            invert: false,
+            clamp: (0.0, 1.0)
+        }
+    }
+
+    pub fn apply(&self, h: f32, tangents: &[f32]) -> f32 {
+        let t = ((h - self.in_black) / (self.in_white - self.in_black).max(1e-6)).clamp(0.0, 1.0);
+        let t = t.powf(1.0 / self.gamma.max(1e-3));
+        let mut h = self.out_black + t * (self.out_white - self.out_black);
+
+        h = curve_value(&self.curve, tangents, h);
+
+        if self.terraces > 0 {
+            // flat shelves with a riser in the middle of every step, sharper means narrower risers
+            let v = h * self.terraces as f32;
+            let (k, f) = (v.floor(), v - v.floor());
+            let f = ((f - 0.5) / (1.0 - self.sharpness).max(1e-3) + 0.5).clamp(0.0, 1.0);
+            h = (k + f) / self.terraces as f32;
+        }
+
+        if self.invert {
+            h = 1.0 - h;
+        }
+        h.clamp(self.clamp.0, self.clamp.1)
+    }
+
+    // Remapped value for each of the 766 levels a height pixel can hold
+    pub fn table(&self) -> Vec<f32> {
+        let tangents = curve_tangents(&self.curve);
+        (0..=765).map(|i| self.apply(i as f32 / 765.0, &tangents)).collect()
+    }
+
+    // Remaps every pixel of a height layer, coverage is kept
+    pub fn remap_image(&self, img: &ColorImage) -> ColorImage {
+        let table = self.table();
+        ColorImage {
+            size: img.size,
+            pixels: img.pixels.iter().map(|px| {
+                let h = table[(layer_height(*px) * 765.0).round() as usize];
+                with_alpha(height_to_col(h), px.a() as f32 / 255.0)
+            }).collect()
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new("Remap Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Input Levels");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.in_black).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+                ui.add(egui::DragValue::new(&mut self.in_white).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+            });
+            ui.end_row();
+
+            ui.label("Gamma");
+            ui.add(egui::Slider::new(&mut self.gamma, RangeInclusive::new(0.1, 5.0)).logarithmic(true));
+            ui.end_row();
+
+            ui.label("Output Levels");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.out_black).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+                ui.add(egui::DragValue::new(&mut self.out_white).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+            });
+            ui.end_row();
+
+            ui.label("Terraces");
+            ui.add(egui::Slider::new(&mut self.terraces, RangeInclusive::new(0, 32)));
+            ui.end_row();
+
+            ui.label("Sharpness");
+            ui.add_enabled(self.terraces > 0, egui::Slider::new(&mut self.sharpness, RangeInclusive::new(0.0, 1.0)));
+            ui.end_row();
+
+            ui.label("Clamp");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.clamp.0).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+                ui.add(egui::DragValue::new(&mut self.clamp.1).speed(0.005).range(RangeInclusive::new(0.0, 1.0)).fixed_decimals(3));
+            });
+            ui.end_row();
+
+            ui.label("Invert");
+            ui.checkbox(&mut self.invert, "");
+            ui.end_row();
+        });
+        self.clamp.1 = self.clamp.1.max(self.clamp.0);
+
+        ui.label("Curve");
+        self.curve_ui(ui);
+    }
+
+    // Drag points to shape the curve, click to add one and right-click to remove it
+    fn curve_ui(&mut self, ui: &mut Ui) {
+        let side = ui.available_width().min(180.0);
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(side, side), Sense::click_and_drag());
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 0.0, Color32::from_gray(30));
+        for i in 1..4 {
+            let f = i as f32 / 4.0;
+            painter.line_segment([rect.lerp_inside(egui::vec2(f, 0.0)), rect.lerp_inside(egui::vec2(f, 1.0))], Stroke::new(1.0, Color32::from_gray(50)));
+            painter.line_segment([rect.lerp_inside(egui::vec2(0.0, f)), rect.lerp_inside(egui::vec2(1.0, f))], Stroke::new(1.0, Color32::from_gray(50)));
+        }
+
+        let to_screen = |(x, y): (f32, f32)| rect.lerp_inside(egui::vec2(x, 1.0 - y));
+        let from_screen = |pos: egui::Pos2| (((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0), (1.0 - (pos.y - rect.top()) / rect.height()).clamp(0.0, 1.0));
+
+        let tangents = curve_tangents(&self.curve);
+        let line: Vec<egui::Pos2> = (0..=64).map(|i| {
+            let x = i as f32 / 64.0;
+            to_screen((x, curve_value(&self.curve, &tangents, x)))
+        }).collect();
+        painter.add(egui::Shape::line(line, Stroke::new(1.5, Color32::WHITE)));
+
+        let hit = response.interact_pointer_pos().or(response.hover_pos())
+            .and_then(|pos| self.curve.iter().position(|p| to_screen(*p).distance(pos) < 6.0));
+        let drag_id = response.id.with("Curve Point");
+
+        if response.drag_started() {
+            ui.data_mut(|d| d.insert_temp(drag_id, hit));
+        }
+        let dragged: Option<usize> = ui.data(|d| d.get_temp(drag_id)).flatten();
+        if let (true, Some(i), Some(pos)) = (response.dragged(), dragged, response.interact_pointer_pos()) {
+            if i < self.curve.len() {
+                // points stay between their neighbours so the order never changes under the drag
+                let lo = if i > 0 { self.curve[i - 1].0 } else { 0.0 };
+                let hi = self.curve.get(i + 1).map_or(1.0, |p| p.0);
+                let (x, y) = from_screen(pos);
+                self.curve[i] = (x.clamp(lo, hi), y);
+            }
+        }
+        if response.drag_stopped() {
+            ui.data_mut(|d| d.remove::<Option<usize>>(drag_id));
+        }
+
+        if let (true, None, Some(pos)) = (response.clicked(), hit, response.interact_pointer_pos()) {
+            self.curve.push(from_screen(pos));
+            self.curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        if let (true, Some(i)) = (response.secondary_clicked(), hit) {
+            if self.curve.len() > 2 {
+                self.curve.remove(i);
+            }
+        }
+
+        for (i, point) in self.curve.iter().enumerate() {
+            let color = if Some(i) == hit { Color32::YELLOW } else { Color32::WHITE };
+            painter.circle(to_screen(*point), 4.0, color, Stroke::new(1.0, Color32::BLACK));
+        }
+    }
+}
+
+
+// Fritsch-Carlson tangents, the curve never overshoots between its points
+fn curve_tangents(points: &[(f32, f32)]) -> Vec<f32> {
+    let n = points.len();
+    if n < 2 {
+        return vec![1.0; n];
+    }
+
+    let slopes: Vec<f32> = points.windows(2).map(|p| (p[1].1 - p[0].1) / (p[1].0 - p[0].0).max(1e-6)).collect();
+    let mut m: Vec<f32> = (0..n).map(|k| match k {
+        0 => slopes[0],
+        k if k == n - 1 => slopes[n - 2],
+        k if slopes[k - 1] * slopes[k] <= 0.0 => 0.0,
+        k => (slopes[k - 1] + slopes[k]) / 2.0,
+    }).collect();
+
+    for k in 0..n - 1 {
+        if slopes[k] == 0.0 {
+            m[k] = 0.0;
+            m[k + 1] = 0.0;
+            continue;
+        }
+        let (a, b) = (m[k] / slopes[k], m[k + 1] / slopes[k]);
+        let s = a * a + b * b;
+        if s > 9.0 {
+            let t = 3.0 / s.sqrt();
+            m[k] = t * a * slopes[k];
+            m[k + 1] = t * b * slopes[k];
+        }
+    }
+    m
+}
+
+
+fn curve_value(points: &[(f32, f32)], tangents: &[f32], x: f32) -> f32 {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return x;
+    };
+    if x <= first.0 {
+        return first.1;
+    }
+    if x >= last.0 {
+        return last.1;
+    }
+
+    let k = points.windows(2).position(|p| x <= p[1].0).unwrap_or(points.len() - 2);
+    let ((x0, y0), (x1, y1)) = (points[k], points[k + 1]);
+    let h = (x1 - x0).max(1e-6);
+    let t = (x - x0) / h;
+    let (t2, t3) = (t * t, t * t * t);
+
+    let value = (2.0 * t3 - 3.0 * t2 + 1.0) * y0 + (t3 - 2.0 * t2 + t) * h * tangents[k]
+        + (-2.0 * t3 + 3.0 * t2) * y1 + (t3 - t2) * h * tangents[k + 1];
+    value.clamp(0.0, 1.0)
+}
+
+
+// Lowest and highest height of the covered pixels, for normalizing
+pub fn height_range(img: &ColorImage) -> Option<(f32, f32)> {
+    img.pixels.iter().filter(|px| px.a() > 0).map(|px| layer_height(*px)).fold(None, |range, h| {
+        Some(range.map_or((h, h), |(lo, hi): (f32, f32)| (lo.min(h), hi.max(h))))
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(points: &[(f32, f32)], x: f32) -> f32 {
+        curve_value(points, &curve_tangents(points), x)
+    }
+
+    #[test]
+    fn default_remap_is_identity() {
+        let table = RemapSettings::default().table();
+        assert!(table.iter().enumerate().all(|(i, h)| (h - i as f32 / 765.0).abs() < 1e-4));
+    }
+
+    #[test]
+    fn levels_invert_and_clamp() {
+        let remap = RemapSettings { in_black: 0.2, in_white: 0.6, ..RemapSettings::default() };
+        let tangents = curve_tangents(&remap.curve);
+        assert_eq!(remap.apply(0.1, &tangents), 0.0);
+        assert!((remap.apply(0.4, &tangents) - 0.5).abs() < 1e-4);
+        assert_eq!(remap.apply(0.9, &tangents), 1.0);
+
+        let remap = RemapSettings { invert: true, clamp: (0.3, 0.8), ..RemapSettings::default() };
+        assert_eq!(remap.apply(0.0, &tangents), 0.8);
+        assert!((remap.apply(0.5, &tangents) - 0.5).abs() < 1e-4);
+        assert_eq!(remap.apply(1.0, &tangents), 0.3);
+    }
+
+    #[test]
+    fn terraces_flatten_into_shelves() {
+        let remap = RemapSettings { terraces: 4, sharpness: 1.0, ..RemapSettings::default() };
+        let tangents = curve_tangents(&remap.curve);
+        // risers sit halfway between the shelves
+        assert_eq!(remap.apply(0.15, &tangents), 0.25);
+        assert_eq!(remap.apply(0.35, &tangents), 0.25);
+        assert_eq!(remap.apply(0.4, &tangents), 0.5);
+    }
+
+    #[test]
+    fn curve_passes_through_its_points_without_overshooting() {
+        let points = [(0.0, 0.0), (0.3, 0.6), (0.5, 0.6), (0.8, 0.9), (1.0, 1.0)];
+        for (x, y) in points {
+            assert!((curve(&points, x) - y).abs() < 1e-5);
+        }
+
+        // monotone data gives a monotone curve, and the flat part stays flat
+        let values: Vec<f32> = (0..=200).map(|i| curve(&points, i as f32 / 200.0)).collect();
+        assert!(values.windows(2).all(|v| v[1] >= v[0] - 1e-6));
+        assert!((60..=100).all(|i| (values[i] - 0.6).abs() < 1e-5));
+    }
+
+    #[test]
+    fn tangents_of_a_straight_line_are_its_slope() {
+        let tangents = curve_tangents(&[(0.0, 0.0), (0.5, 0.25), (1.0, 0.5)]);
+        assert!(tangents.iter().all(|m| (m - 0.5).abs() < 1e-6));
+        assert_eq!(curve_tangents(&[(0.0, 0.0), (0.5, 1.0), (1.0, 0.0)])[1], 0.0);
+    }
+}
@@ -0,0 +1,198 @@
+use egui::{Color32, ColorImage, Painter, Pos2, Rect, Stroke};
+use nalgebra::{Rotation2, Vector2};
+
+use crate::mask::Mask;
+
+
+// Closed polygon of drawing uvs, a rectangle selection is stored as its four corners
+pub struct Selection {
+    pub points: Vec<Vector2<f32>>
+}
+
+
+impl Selection {
+    pub fn rectangle(a: Vector2<f32>, b: Vector2<f32>) -> Self {
+        Self {
+            points: vec![a, Vector2::new(b.x, a.y), b, Vector2::new(a.x, b.y)]
+        }
+    }
+
+    // Even-odd rule
+    pub fn contains(&self, p: Vector2<f32>) -> bool {
+        let mut inside = false;
+        let n = self.points.len();
+        for i in 0..n {
+            let (a, b) = (self.points[i], self.points[(i + n - 1) % n]);
+            if (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    pub fn bounds(&self) -> (Vector2<f32>, Vector2<f32>) {
+        self.points.iter().fold((Vector2::new(f32::MAX, f32::MAX), Vector2::new(f32::MIN, f32::MIN)), |(min, max), p| {
+            (min.inf(p), max.sup(p))
+        })
+    }
+}
+
+
+// Copied pixels with their feathered selection coverage
+#[derive(Clone)]
+pub struct Clip {
+    pub image: ColorImage,
+    pub coverage: Vec<f32>,
+    // uv the clip was copied from, pasting starts there
+    pub center: Vector2<f32>
+}
+
+
+// Crops the selection out of img, the coverage fades to zero over feather pixels around the outline
+pub fn copy(img: &ColorImage, selection: &Selection, feather: usize) -> Option<Clip> {
+    let (w, h) = (img.width() as i64, img.height() as i64);
+    let (min, max) = selection.bounds();
+    let pad = feather as i64;
+
+    let x0 = ((min.x * w as f32) as i64 - pad).max(0);
+    let y0 = ((min.y * h as f32) as i64 - pad).max(0);
+    let x1 = ((max.x * w as f32).ceil() as i64 + pad).min(w - 1);
+    let y1 = ((max.y * h as f32).ceil() as i64 + pad).min(h - 1);
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+
+    let size = [(x1 - x0 + 1) as usize, (y1 - y0 + 1) as usize];
+    let mut coverage = Mask::new("Selection", size, 0.0);
+    let mut pixels = Vec::with_capacity(size[0] * size[1]);
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            pixels.push(img.pixels[(y * w + x) as usize]);
+            let uv = Vector2::new((x as f32 + 0.5) / w as f32, (y as f32 + 0.5) / h as f32);
+            if selection.contains(uv) {
+                coverage.values[((y - y0) * size[0] as i64 + x - x0) as usize] = 1.0;
+            }
+        }
+    }
+    if feather > 0 {
+        coverage.blur(feather / 2 + 1);
+    }
+
+    Some(Clip {
+        image: ColorImage { size, pixels },
+        coverage: coverage.values,
+        center: Vector2::new((x0 + x1 + 1) as f32 / (2 * w) as f32, (y0 + y1 + 1) as f32 / (2 * h) as f32)
+    })
+}
+
+
+// Pasted clip that can still be moved, rotated and scaled before it is applied onto base
+pub struct Floating {
+    pub clip: Clip,
+    pub center: Vector2<f32>,
+    // degrees
+    pub rotation: f32,
+    pub scale: f32,
+    pub base: ColorImage
+}
+
+
+impl Floating {
+    pub fn new(clip: Clip, base: ColorImage) -> Self {
+        Self {
+            center: clip.center,
+            clip,
+            rotation: 0.0,
+            scale: 1.0,
+            base
+        }
+    }
+
+    // Clip corners in drawing uvs after the transform
+    pub fn outline(&self) -> Vec<Vector2<f32>> {
+        let (cw, ch) = (self.clip.image.width() as f32, self.clip.image.height() as f32);
+        let (w, h) = (self.base.width() as f32, self.base.height() as f32);
+        let rot = Rotation2::new(self.rotation.to_radians());
+
+        [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)].iter().map(|(x, y)| {
+            let p = rot * Vector2::new(x * cw, y * ch) * self.scale;
+            self.center + Vector2::new(p.x / w, p.y / h)
+        }).collect()
+    }
+
+    // Base with the transformed clip blended on top, the active mask limits the coverage
+    pub fn composite(&self, mask: Option<&Mask>) -> ColorImage {
+        let mut out = self.base.clone();
+        let (w, h) = (out.width(), out.height());
+        let (cw, ch) = (self.clip.image.width(), self.clip.image.height());
+        let inverse = Rotation2::new(-self.rotation.to_radians());
+        let center_px = Vector2::new(self.center.x * w as f32, self.center.y * h as f32);
+
+        let outline = self.outline();
+        let (min, max) = Selection { points: outline }.bounds();
+        let x0 = ((min.x * w as f32).floor().max(0.0)) as usize;
+        let y0 = ((min.y * h as f32).floor().max(0.0)) as usize;
+        let x1 = ((max.x * w as f32).ceil() as usize).min(w - 1);
+        let y1 = ((max.y * h as f32).ceil() as usize).min(h - 1);
+        if min.x >= 1.0 || min.y >= 1.0 || max.x <= 0.0 || max.y <= 0.0 {
+            return out;
+        }
+
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let d = inverse * (Vector2::new(x as f32 + 0.5, y as f32 + 0.5) - center_px) / self.scale.max(1e-3);
+                let (sx, sy) = (d.x + cw as f32 / 2.0 - 0.5, d.y + ch as f32 / 2.0 - 0.5);
+                if sx < -0.5 || sy < -0.5 || sx > cw as f32 - 0.5 || sy > ch as f32 - 0.5 {
+                    continue;
+                }
+
+                let (color, coverage) = self.sample(sx, sy);
+                let t = coverage * mask.map_or(1.0, |mask| mask.at(x, y, [w, h]));
+                if t <= 0.0 {
+                    continue;
+                }
+
+                let a = out.pixels[y * w + x].to_srgba_unmultiplied();
+                let mix = |c: usize| (a[c] as f32 + (color[c] - a[c] as f32) * t).round() as u8;
+                out.pixels[y * w + x] = Color32::from_rgba_unmultiplied(mix(0), mix(1), mix(2), mix(3));
+            }
+        }
+
+        out
+    }
+
+    // Bilinear sample of the clip at a fractional pixel, channels stay in 0..255
+    fn sample(&self, x: f32, y: f32) -> ([f32; 4], f32) {
+        let (cw, ch) = (self.clip.image.width(), self.clip.image.height());
+        let x = x.clamp(0.0, cw as f32 - 1.0);
+        let y = y.clamp(0.0, ch as f32 - 1.0);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(cw - 1), (y0 + 1).min(ch - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let corners = [(x0, y0, (1.0 - fx) * (1.0 - fy)), (x1, y0, fx * (1.0 - fy)), (x0, y1, (1.0 - fx) * fy), (x1, y1, fx * fy)];
+        let mut color = [0.0; 4];
+        let mut coverage = 0.0;
+        for (x, y, weight) in corners {
+            let px = self.clip.image.pixels[y * cw + x].to_srgba_unmultiplied();
+            for c in 0..4 {
+                color[c] += px[c] as f32 * weight;
+            }
+            coverage += self.clip.coverage[y * cw + x] * weight;
+        }
+        (color, coverage)
+    }
+}
+
+
+// Dashed looking outline of a uv polygon on the canvas
+pub fn paint_outline(painter: &Painter, rect: Rect, points: &[Vector2<f32>], closed: bool, color: Color32) {
+    let points: Vec<Pos2> = points.iter().map(|p| rect.min + egui::vec2(p.x * rect.width(), p.y * rect.height())).collect();
+    if closed {
+        painter.add(egui::Shape::closed_line(points.clone(), Stroke::new(2.0, Color32::BLACK)));
+        painter.add(egui::Shape::dashed_line(&[points.clone(), vec![points[0]]].concat(), Stroke::new(1.0, color), 4.0, 4.0));
+    } else {
+        painter.add(egui::Shape::line(points, Stroke::new(1.0, color)));
+    }
+}
+
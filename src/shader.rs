@@ -1,16 +1,11 @@
 // pub mod Shader {
-    use eframe::glow::{self, HasContext};
-    use egui::Vec2;
-    use nalgebra::{Vector2, Vector3, Vector4};
-    use rand;
+    use eframe::glow;
 
-    use crate::{camera::Camera, mesh::Mesh};
+    use crate::{camera::Camera, mesh::Mesh, terrain::TerrainSettings, texture::TerrainTextures};
 
     
     pub struct ShaderProgram {
-        pub program : glow::Program,
-        vert_shader: glow::Shader,
-        frag_shader: glow::Shader
+        pub program : glow::Program
     }
 
 
@@ -37,7 +32,7 @@
                     let shader = gl
                         .create_shader(*shader_type)
                         .expect("Cannot create shader");
-                    gl.shader_source(shader, &format!("{shader_source}"));
+                    gl.shader_source(shader, shader_source);
                     gl.compile_shader(shader);
                     assert!(
                         gl.get_shader_compile_status(shader),
@@ -64,9 +59,7 @@
                 }
 
                 Self {
-                    program,
-                    vert_shader: shaders[0],
-                    frag_shader: shaders[1]
+                    program
                 }
             }
         }
@@ -97,9 +90,83 @@
                 );
 
                 gl.bind_vertex_array(Some(mesh.vertex_array));
-                gl.draw_elements(if mesh.wireframe {glow::LINES} else {glow::TRIANGLES}, mesh.index_buffer_size as i32, glow::UNSIGNED_INT, 0);
+                gl.draw_elements(mesh.draw_mode(), mesh.index_buffer_size as i32, glow::UNSIGNED_INT, 0);
+                gl.bind_vertex_array(None);
             }
         }
-    }
 
-    
\ No newline at end of file
+        // Displacement path: draws a flat grid and lets the vertex shader lift it from the height texture
+        pub fn paint_displaced(&self, gl: &glow::Context, grid: &Mesh, textures: &TerrainTextures, use_colors: bool, settings: &TerrainSettings, camera: &Camera) {
+            use glow::HasContext as _;
+
+            unsafe {
+                gl.clear(glow::DEPTH_BUFFER_BIT);
+                gl.depth_func(glow::LESS);
+                gl.enable(glow::DEPTH_TEST);
+
+                gl.use_program(Some(self.program));
+
+                gl.uniform_matrix_4_f32_slice(
+                    gl.get_uniform_location(self.program, "u_ViewProj").as_ref(),
+                    false, 
+                    camera.get_proj_view_mat().as_slice()
+                );
+                gl.uniform_2_f32(gl.get_uniform_location(self.program, "u_Size").as_ref(), settings.width, settings.length);
+                gl.uniform_2_f32(gl.get_uniform_location(self.program, "u_Elevation").as_ref(), settings.min_elevation, settings.max_elevation);
+                gl.uniform_1_f32(gl.get_uniform_location(self.program, "u_Exaggeration").as_ref(), settings.exaggeration);
+                gl.uniform_3_f32(gl.get_uniform_location(self.program, "u_Shore").as_ref(), settings.water.sea_level, settings.water.shore_width, settings.water.enabled as i32 as f32);
+                gl.uniform_1_i32(gl.get_uniform_location(self.program, "u_UseColor").as_ref(), use_colors as i32);
+
+                gl.active_texture(glow::TEXTURE0);
+                gl.bind_texture(glow::TEXTURE_2D, Some(textures.height.texture));
+                gl.uniform_1_i32(gl.get_uniform_location(self.program, "u_Height").as_ref(), 0);
+
+                gl.active_texture(glow::TEXTURE1);
+                gl.bind_texture(glow::TEXTURE_2D, Some(textures.colors.texture));
+                gl.uniform_1_i32(gl.get_uniform_location(self.program, "u_Color").as_ref(), 1);
+
+                gl.bind_vertex_array(Some(grid.vertex_array));
+                gl.draw_elements(grid.draw_mode(), grid.index_buffer_size as i32, glow::UNSIGNED_INT, 0);
+                gl.bind_vertex_array(None);
+
+                // egui expects unit 0 to be active
+                gl.bind_texture(glow::TEXTURE_2D, None);
+                gl.active_texture(glow::TEXTURE0);
+                gl.bind_texture(glow::TEXTURE_2D, None);
+            }
+        }
+
+        // Blended pass on top of the terrain (water, draped lines), keeps the depth buffer so the terrain still occludes
+        pub fn paint_transparent(&self, gl: &glow::Context, mesh: &Mesh, camera: &Camera) {
+            use glow::HasContext as _;
+
+            unsafe {
+                // egui draws after this callback with its own blending, so whatever was set is put back at the end
+                let blend = gl.is_enabled(glow::BLEND);
+                let func = [glow::BLEND_SRC_RGB, glow::BLEND_DST_RGB, glow::BLEND_SRC_ALPHA, glow::BLEND_DST_ALPHA].map(|p| gl.get_parameter_i32(p) as u32);
+
+                gl.enable(glow::DEPTH_TEST);
+                gl.depth_mask(false);
+                gl.enable(glow::BLEND);
+                gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
+
+                gl.use_program(Some(self.program));
+
+                gl.uniform_matrix_4_f32_slice(
+                    gl.get_uniform_location(self.program, "u_ViewProj").as_ref(),
+                    false, 
+                    camera.get_proj_view_mat().as_slice()
+                );
+
+                gl.bind_vertex_array(Some(mesh.vertex_array));
+                gl.draw_elements(mesh.draw_mode(), mesh.index_buffer_size as i32, glow::UNSIGNED_INT, 0);
+                gl.bind_vertex_array(None);
+
+                gl.depth_mask(true);
+                gl.blend_func_separate(func[0], func[1], func[2], func[3]);
+                if !blend {
+                    gl.disable(glow::BLEND);
+                }
+            }
+        }
+    }
@@ -0,0 +1,341 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Painter, Rect, Stroke, Ui};
+use nalgebra::{Vector2, Vector3};
+
+use crate::{drawing::{col_to_height, height_to_col, with_alpha, DirtyRect}, terrain::TerrainSettings};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SplineKind {
+    CatmullRom,
+    // every three points after the first are two handles and the next anchor
+    Bezier
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SplineMode {
+    // flattens the terrain to the path elevation
+    Road,
+    // only digs down to the path elevation
+    River
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct ControlPoint {
+    pub uv: Vector2<f32>,
+    // metres
+    pub elevation: f32
+}
+
+
+#[derive(Clone, PartialEq)]
+pub struct Spline {
+    pub name: String,
+    pub kind: SplineKind,
+    pub mode: SplineMode,
+    pub points: Vec<ControlPoint>,
+    // full width of the flat part and the blend on either side, in metres
+    pub width: f32,
+    pub falloff: f32,
+    pub paint: bool,
+    pub color: Color32
+}
+
+
+impl Spline {
+    pub fn new(name: &str, mode: SplineMode) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: SplineKind::CatmullRom,
+            mode,
+            points: Vec::new(),
+            width: 0.6,
+            falloff: 0.4,
+            paint: false,
+            color: match mode {
+                SplineMode::Road => Color32::from_rgb(90, 80, 70),
+                SplineMode::River => Color32::from_rgb(60, 90, 140),
+            }
+        }
+    }
+
+    // Points along the curve with uv in xy and elevation in z
+    pub fn sample(&self, steps: usize) -> Vec<Vector3<f32>> {
+        let p: Vec<Vector3<f32>> = self.points.iter().map(|c| Vector3::new(c.uv.x, c.uv.y, c.elevation)).collect();
+        if p.len() < 2 {
+            return p;
+        }
+
+        let mut out = vec![p[0]];
+        match self.kind {
+            SplineKind::CatmullRom => {
+                for i in 0..p.len() - 1 {
+                    let (p0, p1, p2, p3) = (p[i.saturating_sub(1)], p[i], p[i + 1], p[(i + 2).min(p.len() - 1)]);
+                    for s in 1..=steps {
+                        let t = s as f32 / steps as f32;
+                        let (t2, t3) = (t * t, t * t * t);
+                        out.push(0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3));
+                    }
+                }
+            },
+            SplineKind::Bezier => {
+                let mut i = 0;
+                while i + 3 < p.len() {
+                    let (p0, p1, p2, p3) = (p[i], p[i + 1], p[i + 2], p[i + 3]);
+                    for s in 1..=steps {
+                        let t = s as f32 / steps as f32;
+                        let u = 1.0 - t;
+                        out.push(u * u * u * p0 + 3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t * p3);
+                    }
+                    i += 3;
+                }
+                // points that do not complete a segment yet are joined straight
+                out.extend_from_slice(&p[i + 1..]);
+            },
+        }
+        out
+    }
+
+    // Pixels of a canvas of the given size the spline reaches, None when it draws nothing
+    pub fn bounds(&self, terrain: &TerrainSettings, size: [usize; 2]) -> Option<DirtyRect> {
+        let path = self.sample(16);
+        if path.len() < 2 {
+            return None;
+        }
+        let reach = self.width / 2.0 + self.falloff;
+        let (lo, hi) = path.iter().fold((Vector2::new(f32::MAX, f32::MAX), Vector2::new(f32::MIN, f32::MIN)), |(lo, hi), p| (lo.inf(&p.xy()), hi.sup(&p.xy())));
+        let pixel = |v: f32, n: usize| ((v * n as f32).max(0.0) as usize).min(n - 1);
+        Some(DirtyRect {
+            min_x: pixel((lo.x - reach / terrain.width).floor(), size[0]),
+            min_y: pixel((lo.y - reach / terrain.length).floor(), size[1]),
+            max_x: pixel((hi.x + reach / terrain.width).ceil(), size[0]),
+            max_y: pixel((hi.y + reach / terrain.length).ceil(), size[1])
+        })
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new("Spline Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut self.name);
+            ui.end_row();
+
+            ui.label("Curve");
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.kind, SplineKind::CatmullRom, "Catmull-Rom");
+                ui.radio_value(&mut self.kind, SplineKind::Bezier, "Bezier");
+            });
+            ui.end_row();
+
+            ui.label("Mode");
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.mode, SplineMode::Road, "Road");
+                ui.radio_value(&mut self.mode, SplineMode::River, "River");
+            });
+            ui.end_row();
+
+            ui.label("Width");
+            ui.add(egui::DragValue::new(&mut self.width).speed(0.02).range(RangeInclusive::new(0.0, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Falloff");
+            ui.add(egui::DragValue::new(&mut self.falloff).speed(0.02).range(RangeInclusive::new(0.0, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Paint Color");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.paint, "");
+                ui.add_enabled_ui(self.paint, |ui| ui.color_edit_button_srgba(&mut self.color));
+            });
+            ui.end_row();
+        });
+
+        let mut remove = None;
+        egui::Grid::new("Spline Points Grid").num_columns(3).show(ui, |ui| {
+            for (i, point) in self.points.iter_mut().enumerate() {
+                ui.label(format!("{}", i + 1));
+                ui.add(egui::DragValue::new(&mut point.elevation).speed(0.02).suffix(" m"));
+                if ui.small_button("Remove").clicked() {
+                    remove = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(i) = remove {
+            self.points.remove(i);
+        }
+    }
+
+    // Path, control points and bezier handles on the canvas
+    pub fn paint(&self, painter: &Painter, rect: Rect, selected: bool) {
+        let to_screen = |uv: Vector2<f32>| rect.min + egui::vec2(uv.x * rect.width(), uv.y * rect.height());
+        let color = if selected { Color32::YELLOW } else { Color32::WHITE };
+
+        let path: Vec<egui::Pos2> = self.sample(16).iter().map(|p| to_screen(p.xy())).collect();
+        painter.add(egui::Shape::line(path, Stroke::new(1.5, color)));
+
+        if !selected {
+            return;
+        }
+        if let SplineKind::Bezier = self.kind {
+            for (i, pair) in self.points.windows(2).enumerate() {
+                // handles connect to the anchor next to them
+                if i % 3 != 1 {
+                    painter.line_segment([to_screen(pair[0].uv), to_screen(pair[1].uv)], Stroke::new(1.0, Color32::GRAY));
+                }
+            }
+        }
+        for (i, point) in self.points.iter().enumerate() {
+            let anchor = self.kind == SplineKind::CatmullRom || i % 3 == 0;
+            painter.circle(to_screen(point.uv), if anchor { 4.0 } else { 3.0 }, if anchor { color } else { Color32::GRAY }, Stroke::new(1.0, Color32::BLACK));
+        }
+    }
+}
+
+
+// Height and color layers for all splines over region of a canvas of the given size, both region sized.
+// below is the terrain under the spline layer over the same region, rivers never raise it. Where splines
+// overlap the one with the larger coverage wins
+pub fn rasterize(terrain: &TerrainSettings, splines: &[Spline], below: &ColorImage, size: [usize; 2], region: DirtyRect) -> (ColorImage, ColorImage) {
+    let (w, h) = (size[0], size[1]);
+    let (rw, rh) = (region.max_x - region.min_x + 1, region.max_y - region.min_y + 1);
+    let mut heights = ColorImage::new([rw, rh], Color32::TRANSPARENT);
+    let mut colors = ColorImage::new([rw, rh], Color32::TRANSPARENT);
+    let mut coverage = vec![0.0_f32; rw * rh];
+
+    let metres = |uv: Vector2<f32>| Vector2::new(uv.x * terrain.width, uv.y * terrain.length);
+
+    for spline in splines {
+        let path = spline.sample(16);
+        if path.len() < 2 {
+            continue;
+        }
+        let (core, reach) = (spline.width / 2.0, spline.width / 2.0 + spline.falloff);
+
+        // distance in metres to the path and the elevation of the closest point on it
+        let mut nearest = vec![(f32::MAX, 0.0_f32); rw * rh];
+        for segment in path.windows(2) {
+            let (a, b) = (segment[0], segment[1]);
+            let (ma, mb) = (metres(a.xy()), metres(b.xy()));
+            let min_x = (((a.x.min(b.x) - reach / terrain.width) * w as f32).floor().max(0.0) as usize).max(region.min_x);
+            let min_y = (((a.y.min(b.y) - reach / terrain.length) * h as f32).floor().max(0.0) as usize).max(region.min_y);
+            let max_x = (((a.x.max(b.x) + reach / terrain.width) * w as f32).ceil().max(0.0) as usize).min(region.max_x);
+            let max_y = (((a.y.max(b.y) + reach / terrain.length) * h as f32).ceil().max(0.0) as usize).min(region.max_y);
+
+            let ab = mb - ma;
+            for y in min_y..=max_y {
+                for x in min_x..=max_x {
+                    let p = metres(Vector2::new((x as f32 + 0.5) / w as f32, (y as f32 + 0.5) / h as f32));
+                    let t = if ab.norm_squared() > 0.0 { ((p - ma).dot(&ab) / ab.norm_squared()).clamp(0.0, 1.0) } else { 0.0 };
+                    let d = (ma + ab * t - p).norm();
+                    let n = &mut nearest[(y - region.min_y) * rw + (x - region.min_x)];
+                    if d < n.0 {
+                        *n = (d, a.z + (b.z - a.z) * t);
+                    }
+                }
+            }
+        }
+
+        for (i, (d, elevation)) in nearest.into_iter().enumerate() {
+            if d > reach {
+                continue;
+            }
+            let t = ((d - core) / spline.falloff.max(1e-6)).clamp(0.0, 1.0);
+            let weight = 1.0 - t * t * (3.0 - 2.0 * t);
+            if weight <= coverage[i] {
+                continue;
+            }
+            coverage[i] = weight;
+
+            let bed = terrain.normalized_height(elevation);
+            let target = match spline.mode {
+                SplineMode::Road => bed,
+                SplineMode::River => bed.min(col_to_height(below.pixels[i])),
+            };
+            heights.pixels[i] = with_alpha(height_to_col(target), weight);
+            colors.pixels[i] = if spline.paint { with_alpha(spline.color, weight) } else { Color32::TRANSPARENT };
+        }
+    }
+
+    (heights, colors)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drawing::layer_height;
+
+    // default 20 x 20 m terrain spanning 0 - 4 m on a 40 x 40 canvas, half a metre per pixel
+    const SIZE: [usize; 2] = [40, 40];
+    const FULL: DirtyRect = DirtyRect { min_x: 0, min_y: 0, max_x: 39, max_y: 39 };
+
+    // straight across the middle row at 2 m, a 2 m wide core with 2 m of falloff on each side
+    fn straight(mode: SplineMode) -> Spline {
+        let mut spline = Spline::new("Test", mode);
+        spline.points = vec![
+            ControlPoint { uv: Vector2::new(0.0, 0.5), elevation: 2.0 },
+            ControlPoint { uv: Vector2::new(1.0, 0.5), elevation: 2.0 },
+        ];
+        (spline.width, spline.falloff) = (2.0, 2.0);
+        spline
+    }
+
+    // pixel rows are 0.5 m apart, row y is |(y + 0.5) / 2 - 10| metres from the path
+    fn column(img: &ColorImage, x: usize) -> Vec<Color32> {
+        (0..SIZE[1]).map(|y| img.pixels[y * SIZE[0] + x]).collect()
+    }
+
+    #[test]
+    fn road_flattens_to_its_elevation_inside_the_core() {
+        let below = ColorImage::new(SIZE, height_to_col(0.2));
+        let (heights, _) = rasterize(&TerrainSettings::default(), &[straight(SplineMode::Road)], &below, SIZE, FULL);
+        for px in &column(&heights, 10)[18..22] {
+            assert_eq!(px.a(), 255);
+            assert!((layer_height(*px) - 0.5).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn falloff_fades_out_at_the_edge() {
+        let below = ColorImage::new(SIZE, height_to_col(0.2));
+        let (heights, _) = rasterize(&TerrainSettings::default(), &[straight(SplineMode::Road)], &below, SIZE, FULL);
+        let alpha: Vec<u8> = column(&heights, 10).iter().map(|px| px.a()).collect();
+
+        // 1.25 m to 2.75 m out the weight falls, from 3.25 m on nothing is drawn
+        assert!(alpha[22..26].windows(2).all(|a| a[1] < a[0]) && alpha[25] > 0 && alpha[22] < 255);
+        assert!(alpha[26..].iter().chain(&alpha[..14]).all(|a| *a == 0));
+        assert_eq!(alpha[13..27].iter().rev().collect::<Vec<_>>(), alpha[13..27].iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn river_never_raises_the_ground() {
+        // low ground on the left, high ground on the right of a river at 2 m, half the height range
+        let below = ColorImage {
+            size: SIZE,
+            pixels: (0..SIZE[0] * SIZE[1]).map(|i| height_to_col(if i % SIZE[0] < 20 { 0.3 } else { 0.8 })).collect()
+        };
+        let (heights, _) = rasterize(&TerrainSettings::default(), &[straight(SplineMode::River)], &below, SIZE, FULL);
+        assert!((layer_height(heights.pixels[20 * SIZE[0] + 5]) - 0.3).abs() < 0.01);
+        assert!((layer_height(heights.pixels[20 * SIZE[0] + 30]) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn region_matches_the_full_canvas() {
+        let terrain = TerrainSettings::default();
+        let below = ColorImage::new(SIZE, height_to_col(0.2));
+        let (full, _) = rasterize(&terrain, &[straight(SplineMode::Road)], &below, SIZE, FULL);
+
+        let region = DirtyRect { min_x: 5, min_y: 15, max_x: 12, max_y: 30 };
+        let below = ColorImage::new([8, 16], height_to_col(0.2));
+        let (part, _) = rasterize(&terrain, &[straight(SplineMode::Road)], &below, SIZE, region);
+        for y in 0..16 {
+            for x in 0..8 {
+                assert_eq!(part.pixels[y * 8 + x], full.pixels[(y + 15) * SIZE[0] + x + 5]);
+            }
+        }
+    }
+}
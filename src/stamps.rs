@@ -0,0 +1,227 @@
+use std::ops::RangeInclusive;
+
+use egui::{ColorImage, Ui};
+use nalgebra::{Rotation2, Vector2};
+
+use crate::{drawing::{col_to_height, HeightBlend}, layers::LayerStack, mask::Mask, terrain::TerrainSettings};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StampKind {
+    Crater,
+    Volcano,
+    Mesa,
+    Dune,
+    Ridge
+}
+
+
+impl StampKind {
+    pub const ALL: [StampKind; 5] = [StampKind::Crater, StampKind::Volcano, StampKind::Mesa, StampKind::Dune, StampKind::Ridge];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StampKind::Crater => "Crater",
+            StampKind::Volcano => "Volcano",
+            StampKind::Mesa => "Mesa",
+            StampKind::Dune => "Dune",
+            StampKind::Ridge => "Ridge",
+        }
+    }
+
+    // Relative height and footprint weight at p, in stamp radii after rotation. Heights are
+    // roughly in [-1, 1] and fade to 0 before the footprint ends
+    pub fn profile(&self, p: Vector2<f32>) -> (f32, f32) {
+        let r = p.norm();
+        let (u, v) = (p.x.abs(), p.y.abs());
+        match self {
+            StampKind::Crater => {
+                let bowl = if r < 0.7 { -(1.0 - (r / 0.7).powi(2)) } else { 0.0 };
+                let rim = 0.4 * (-((r - 0.75) / 0.12).powi(2)).exp();
+                let edge = 1.0 - smoothstep(0.85, 1.0, r);
+                ((bowl + rim) * edge, edge)
+            },
+            StampKind::Volcano => {
+                let cone = |r: f32| (1.0 - r).max(0.0).powf(1.3);
+                let caldera = 0.18;
+                let h = if r < caldera { cone(caldera) - 0.35 * (1.0 - (r / caldera).powi(2)) } else { cone(r) };
+                (h, 1.0 - smoothstep(0.7, 1.0, r))
+            },
+            StampKind::Mesa => {
+                // flat top, a steep cliff and a short talus slope at its foot
+                let h = if r < 0.55 {
+                    1.0
+                } else if r < 0.65 {
+                    1.0 - 0.75 * smoothstep(0.55, 0.65, r)
+                } else {
+                    0.25 * (1.0 - (r - 0.65) / 0.35).max(0.0).powi(2)
+                };
+                (h, 1.0 - smoothstep(0.85, 1.0, r))
+            },
+            StampKind::Dune => {
+                // wind along +x: long windward slope up to the crest, steep lee side behind it
+                let along = (1.0 - p.y * p.y).max(0.0);
+                let crest = 0.3;
+                let cross = if p.x < crest { smoothstep(-1.0, crest, p.x) } else { 1.0 - smoothstep(crest, 0.65, p.x) };
+                (along * cross, (1.0 - smoothstep(0.8, 1.0, v)) * (1.0 - smoothstep(0.85, 1.0, u)))
+            },
+            StampKind::Ridge => {
+                let taper = 1.0 - smoothstep(0.6, 1.0, u);
+                let crest = (1.0 - v / 0.35).max(0.0).powf(1.5);
+                (taper * crest, taper * (1.0 - smoothstep(0.25, 0.45, v)))
+            },
+        }
+    }
+}
+
+
+fn smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = ((x - e0) / (e1 - e0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct StampSettings {
+    pub kind: StampKind,
+    // radius in metres
+    pub size: f32,
+    // peak height in metres, craters dig down by the same amount
+    pub height: f32,
+    // degrees
+    pub rotation: f32,
+    pub blend: HeightBlend
+}
+
+
+impl StampSettings {
+    pub fn default() -> Self {
+        Self {
+            kind: StampKind::Crater,
+            size: 2.0,
+            height: 0.5,
+            rotation: 0.0,
+            blend: HeightBlend::Add
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new("Stamp Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Feature");
+            egui::ComboBox::from_id_salt("Stamp Kind").selected_text(self.kind.name()).show_ui(ui, |ui| {
+                for kind in StampKind::ALL {
+                    ui.selectable_value(&mut self.kind, kind, kind.name());
+                }
+            });
+            ui.end_row();
+
+            ui.label("Size");
+            ui.add(egui::DragValue::new(&mut self.size).speed(0.05).range(RangeInclusive::new(0.05, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Height");
+            ui.add(egui::DragValue::new(&mut self.height).speed(0.02).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Rotation");
+            ui.add(egui::Slider::new(&mut self.rotation, RangeInclusive::new(-180.0, 180.0)).suffix("°"));
+            ui.end_row();
+
+            ui.label("Blend");
+            egui::ComboBox::from_id_salt("Stamp Blend").selected_text(self.blend.name()).show_ui(ui, |ui| {
+                for blend in HeightBlend::ALL {
+                    ui.selectable_value(&mut self.blend, blend, blend.name());
+                }
+            });
+            ui.end_row();
+        });
+        ui.label("Click on the canvas with the Stamp tool to place");
+    }
+
+    // Shapes the flattened heights around uv and writes the change into the selected height layer. Replace, Max
+    // and Min build on the ground under the centre so the feature sits on the terrain it is placed on
+    pub fn place(&self, terrain: &TerrainSettings, layers: &mut LayerStack, heights: &ColorImage, uv: Vector2<f32>, mask: Option<&Mask>) {
+        let size = heights.size;
+        let (w, h) = (size[0] as f32, size[1] as f32);
+        let reach = self.size * std::f32::consts::SQRT_2;
+        let min_x = ((uv.x - reach / terrain.width) * w).floor().max(0.0) as usize;
+        let min_y = ((uv.y - reach / terrain.length) * h).floor().max(0.0) as usize;
+        let max_x = (((uv.x + reach / terrain.width) * w).ceil().max(0.0) as usize).min(size[0] - 1);
+        let max_y = (((uv.y + reach / terrain.length) * h).ceil().max(0.0) as usize).min(size[1] - 1);
+        if min_x > max_x || min_y > max_y {
+            return;
+        }
+
+        let center = ((uv.x * w) as usize).min(size[0] - 1) + ((uv.y * h) as usize).min(size[1] - 1) * size[0];
+        let base = col_to_height(heights.pixels[center]);
+        let scale = self.height / (terrain.max_elevation - terrain.min_elevation).max(1e-6);
+        let rot = Rotation2::new(-self.rotation.to_radians());
+
+        let mut delta = vec![0.0; size[0] * size[1]];
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let offset = Vector2::new(((x as f32 + 0.5) / w - uv.x) * terrain.width, ((y as f32 + 0.5) / h - uv.y) * terrain.length);
+                let (s, footprint) = self.kind.profile(rot * offset / self.size.max(1e-6));
+                let weight = footprint * mask.map_or(1.0, |mask| mask.at(x, y, size));
+                if weight <= 0.0 {
+                    continue;
+                }
+
+                let i = y * size[0] + x;
+                let top = match self.blend {
+                    HeightBlend::Add => s * scale,
+                    _ => base + s * scale,
+                };
+                let ground = col_to_height(heights.pixels[i]);
+                delta[i] = self.blend.apply(ground, top, weight).clamp(0.0, 1.0) - ground;
+            }
+        }
+
+        layers.add_height_delta(&delta);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{drawing::{height_to_col, Drawing}, layers::BlendMode};
+
+    #[test]
+    fn crater_digs_into_an_empty_add_layer() {
+        let terrain = TerrainSettings::default();
+        let mut background = Drawing::new();
+        background.set_image(ColorImage::new([64, 64], height_to_col(0.5)));
+        let mut layers = LayerStack::new(&background);
+        layers.add_layer("Stamps", BlendMode::Add);
+
+        let stamp = StampSettings::default();
+        let uv = Vector2::new(0.5, 0.5);
+        let heights = layers.flatten();
+        stamp.place(&terrain, &mut layers, &heights, uv, None);
+
+        let flat = layers.flatten();
+        let center = col_to_height(flat.pixels[32 * 64 + 32]);
+        assert!(center < 0.5 - 0.5 * stamp.height / terrain.max_elevation, "{center}");
+        // the rim still rises above the ground
+        assert!(flat.pixels.iter().any(|px| col_to_height(*px) > 0.51));
+        assert!(layers.layers.iter().any(|l| l.blend == BlendMode::Subtract));
+    }
+
+    #[test]
+    fn replace_builds_on_the_flattened_ground() {
+        let terrain = TerrainSettings::default();
+        let mut background = Drawing::new();
+        background.set_image(ColorImage::new([64, 64], height_to_col(0.5)));
+        let mut layers = LayerStack::new(&background);
+        layers.add_layer("Stamps", BlendMode::Add);
+
+        let stamp = StampSettings { kind: StampKind::Mesa, blend: HeightBlend::Replace, ..StampSettings::default() };
+        let heights = layers.flatten();
+        stamp.place(&terrain, &mut layers, &heights, Vector2::new(0.5, 0.5), None);
+
+        // the mesa top sits its height above the ground instead of above the empty layer
+        let top = col_to_height(layers.flatten().pixels[32 * 64 + 32]);
+        assert!((top - (0.5 + stamp.height / terrain.max_elevation)).abs() < 0.01, "{top}");
+    }
+}
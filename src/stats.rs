@@ -0,0 +1,132 @@
+use egui::{Color32, ColorImage, Sense, Ui};
+
+use crate::{drawing::col_to_height, terrain::TerrainSettings};
+
+
+pub const HISTOGRAM_BINS: usize = 128;
+
+
+// Summary of the flattened height drawing, elevations are in metres
+pub struct HeightStats {
+    pub histogram: Vec<u32>,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std_dev: f32,
+    // fractions of pixels stuck at the lowest and highest storable height
+    pub at_floor: f32,
+    pub at_ceiling: f32,
+    pub surface_area: f32,
+    pub planar_area: f32
+}
+
+
+impl HeightStats {
+    pub fn compute(settings: &TerrainSettings, img: &ColorImage) -> Self {
+        let (w, h) = (img.width(), img.height());
+        let count = (w * h).max(1) as f64;
+
+        let mut histogram = vec![0; HISTOGRAM_BINS];
+        let (mut lo, mut hi) = (f32::MAX, f32::MIN);
+        let (mut sum, mut sum_sq) = (0.0_f64, 0.0_f64);
+        let (mut floor, mut ceiling) = (0, 0);
+        for px in img.pixels.iter() {
+            let v = col_to_height(*px);
+            histogram[((v * HISTOGRAM_BINS as f32) as usize).min(HISTOGRAM_BINS - 1)] += 1;
+            // the height encoding spreads 765 levels over three channels, sums tell the extremes exactly
+            match px.r() as u32 + px.g() as u32 + px.b() as u32 {
+                0 => floor += 1,
+                765 => ceiling += 1,
+                _ => {},
+            }
+
+            let z = settings.elevation(v);
+            lo = lo.min(z);
+            hi = hi.max(z);
+            sum += z as f64;
+            sum_sq += z as f64 * z as f64;
+        }
+        let mean = sum / count;
+        let variance = (sum_sq / count - mean * mean).max(0.0);
+
+        // every pixel is a flat cell tilted by the local gradient
+        let cell = (settings.width / w as f32) * (settings.length / h as f32);
+        let mut surface_area = 0.0_f64;
+        for y in 0..h {
+            for x in 0..w {
+                surface_area += (cell * (1.0 + settings.gradient(img, x, y).norm_squared()).sqrt()) as f64;
+            }
+        }
+
+        Self {
+            histogram,
+            min: lo,
+            max: hi,
+            mean: mean as f32,
+            std_dev: variance.sqrt() as f32,
+            at_floor: floor as f32 / count as f32,
+            at_ceiling: ceiling as f32 / count as f32,
+            surface_area: surface_area as f32,
+            planar_area: settings.width * settings.length
+        }
+    }
+
+    pub fn ui(&self, ui: &mut Ui, settings: &TerrainSettings) {
+        // bars over the normalized height, the tallest bin fills the plot
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(ui.available_width().min(240.0), 80.0), Sense::hover());
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 0.0, Color32::from_gray(30));
+        let peak = self.histogram.iter().copied().max().unwrap_or(1).max(1) as f32;
+        let bar = rect.width() / HISTOGRAM_BINS as f32;
+        for (i, n) in self.histogram.iter().enumerate() {
+            if *n == 0 {
+                continue;
+            }
+            // square root scale keeps small bins visible next to a flat floor
+            let t = (*n as f32 / peak).sqrt();
+            let x = rect.left() + i as f32 * bar;
+            let color = if i == 0 || i == HISTOGRAM_BINS - 1 { Color32::from_rgb(220, 80, 60) } else { Color32::LIGHT_GRAY };
+            painter.rect_filled(egui::Rect::from_min_max(egui::pos2(x, rect.bottom() - t * rect.height()), egui::pos2(x + bar, rect.bottom())), 0.0, color);
+        }
+        if let Some(pos) = response.hover_pos() {
+            let i = (((pos.x - rect.left()) / bar) as usize).min(HISTOGRAM_BINS - 1);
+            let (a, b) = (settings.elevation(i as f32 / HISTOGRAM_BINS as f32), settings.elevation((i + 1) as f32 / HISTOGRAM_BINS as f32));
+            response.on_hover_text(format!("{:.2} - {:.2} m: {} px", a, b, self.histogram[i]));
+        }
+
+        egui::Grid::new("Height Stats Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Min");
+            ui.label(format!("{:.3} m", self.min));
+            ui.end_row();
+
+            ui.label("Max");
+            ui.label(format!("{:.3} m", self.max));
+            ui.end_row();
+
+            ui.label("Mean");
+            ui.label(format!("{:.3} m", self.mean));
+            ui.end_row();
+
+            ui.label("Std Dev");
+            ui.label(format!("{:.3} m", self.std_dev));
+            ui.end_row();
+
+            ui.label("At Floor");
+            ui.label(format!("{:.2}%", self.at_floor * 100.0));
+            ui.end_row();
+
+            ui.label("At Ceiling");
+            ui.colored_label(if self.at_ceiling > 0.0 { Color32::from_rgb(220, 80, 60) } else { ui.visuals().text_color() }, format!("{:.2}%", self.at_ceiling * 100.0))
+                .on_hover_text("Painting clamps at the top of the range, peaks here are flattened");
+            ui.end_row();
+
+            ui.label("Surface Area");
+            ui.label(format!("{:.2} m²", self.surface_area));
+            ui.end_row();
+
+            ui.label("Planar Area");
+            ui.label(format!("{:.2} m² ({:.3}x)", self.planar_area, self.surface_area / self.planar_area.max(1e-6)));
+            ui.end_row();
+        });
+    }
+}
@@ -0,0 +1,20 @@
+#version 330 core
+
+in vec4 fs_col;
+in vec2 fs_uv;
+in vec3 fs_nor;
+in float fs_shore;
+out vec4 frag_color;
+
+uniform sampler2D u_Color;
+uniform int u_UseColor;
+
+void main() {
+    vec4 col = u_UseColor != 0 ? texture(u_Color, fs_uv) : fs_col;
+    col.rgb = mix(col.rgb, vec3(194.0, 178.0, 128.0) / 255.0, fs_shore);
+
+    vec3 light = normalize(vec3(0.5, 1.0, 0.3));
+    float lambert = 0.35 + 0.65 * max(dot(normalize(fs_nor), light), 0.0);
+
+    frag_color = vec4(col.rgb * lambert, 1.0);
+}
@@ -0,0 +1,196 @@
+use std::ops::RangeInclusive;
+
+use egui::{ColorImage, Ui};
+use nalgebra::{Vector2, Vector3};
+
+use crate::{drawing::col_to_height, water::WaterSettings};
+
+
+// Real world extent of the terrain, everything that turns drawing values into
+// positions (mesh generation, the displacement shader, exporters) goes through here
+#[derive(Clone, Copy, PartialEq)]
+pub struct TerrainSettings {
+    pub width: f32,
+    pub length: f32,
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+    pub exaggeration: f32,
+    pub water: WaterSettings
+}
+
+
+impl TerrainSettings {
+    pub fn new(width: f32, length: f32, min_elevation: f32, max_elevation: f32, exaggeration: f32) -> Self {
+        Self {
+            width,
+            length,
+            min_elevation,
+            max_elevation,
+            exaggeration,
+            water: WaterSettings::default()
+        }
+    }
+
+    pub fn default() -> Self {
+        Self::new(20.0, 20.0, 0.0, 4.0, 1.0)
+    }
+
+    // Elevation in metres for a normalized drawing height in [0, 1]
+    pub fn elevation(&self, h: f32) -> f32 {
+        self.min_elevation + h * (self.max_elevation - self.min_elevation)
+    }
+
+    // Normalized drawing height for an elevation in metres
+    pub fn normalized_height(&self, elevation: f32) -> f32 {
+        let range = self.max_elevation - self.min_elevation;
+        if range <= 0.0 { 0.0 } else { (elevation - self.min_elevation) / range }
+    }
+
+    // Vertical position in the viewport and exported meshes
+    pub fn world_height(&self, h: f32) -> f32 {
+        self.elevation(h) * self.exaggeration
+    }
+
+    // Drawing uv of a world position, the terrain is centered on the origin
+    pub fn world_to_uv(&self, pos: Vector3<f32>) -> Vector2<f32> {
+        Vector2::new(pos.x / self.width + 0.5, pos.z / self.length + 0.5)
+    }
+
+    pub fn uv_to_world(&self, img: &ColorImage, uv: Vector2<f32>) -> Vector3<f32> {
+        Vector3::new((uv.x - 0.5) * self.width, self.world_height(sample_height(img, uv)), (uv.y - 0.5) * self.length)
+    }
+
+    // First intersection of a world space ray with the heightfield in img
+    pub fn raycast(&self, img: &ColorImage, origin: Vector3<f32>, dir: Vector3<f32>) -> Option<Vector3<f32>> {
+        // clip the ray against the bounding box of the terrain first
+        let (lo, hi) = (self.world_height(0.0).min(self.world_height(1.0)), self.world_height(0.0).max(self.world_height(1.0)));
+        let min = Vector3::new(-self.width / 2.0, lo, -self.length / 2.0);
+        let max = Vector3::new(self.width / 2.0, hi, self.length / 2.0);
+
+        let (mut t0, mut t1) = (0.0_f32, f32::MAX);
+        for i in 0..3 {
+            if dir[i].abs() < 1e-8 {
+                if origin[i] < min[i] || origin[i] > max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let (a, b) = ((min[i] - origin[i]) / dir[i], (max[i] - origin[i]) / dir[i]);
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+        }
+        if t0 > t1 {
+            return None;
+        }
+
+        // march in steps of half a texel and refine the crossing by bisection
+        let step = 0.5 * (self.width / img.width() as f32).min(self.length / img.height() as f32);
+        let above = |t: f32| {
+            let p = origin + dir * t;
+            p.y > self.world_height(sample_height(img, self.world_to_uv(p)))
+        };
+
+        if !above(t0) {
+            return Some(origin + dir * t0);
+        }
+
+        let mut prev = t0;
+        let mut t = t0;
+        while t < t1 {
+            t = (t + step).min(t1);
+            if !above(t) {
+                let (mut a, mut b) = (prev, t);
+                for _ in 0..16 {
+                    let mid = 0.5 * (a + b);
+                    if above(mid) { a = mid } else { b = mid }
+                }
+                return Some(origin + dir * b);
+            }
+            prev = t;
+        }
+
+        None
+    }
+
+    // Elevation gradient in metres per metre at a drawing pixel, central differences clamped at the borders
+    pub fn gradient(&self, img: &ColorImage, x: usize, y: usize) -> Vector2<f32> {
+        let (w, h) = (img.width(), img.height());
+        let (x0, x1) = (x.saturating_sub(1), (x + 1).min(w - 1));
+        let (y0, y1) = (y.saturating_sub(1), (y + 1).min(h - 1));
+
+        let range = self.max_elevation - self.min_elevation;
+        let dx = (col_to_height(img[(x1, y)]) - col_to_height(img[(x0, y)])) * range / ((x1 - x0).max(1) as f32 * self.width / w as f32);
+        let dy = (col_to_height(img[(x, y1)]) - col_to_height(img[(x, y0)])) * range / ((y1 - y0).max(1) as f32 * self.length / h as f32);
+
+        Vector2::new(dx, dy)
+    }
+
+    pub fn slope_degrees(&self, img: &ColorImage, x: usize, y: usize) -> f32 {
+        self.gradient(img, x, y).norm().atan().to_degrees()
+    }
+
+    // Elevation in metres of every pixel, row by row
+    pub fn elevations(&self, img: &ColorImage) -> Vec<f32> {
+        img.pixels.iter().map(|px| self.elevation(col_to_height(*px))).collect()
+    }
+
+    pub fn extent(&self) -> f32 {
+        self.width.max(self.length)
+    }
+
+    // Returns true if any value changed
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        let before = *self;
+
+        egui::Grid::new("Terrain Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Width");
+            ui.add(egui::DragValue::new(&mut self.width).range(RangeInclusive::new(1.0, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Length");
+            ui.add(egui::DragValue::new(&mut self.length).range(RangeInclusive::new(1.0, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Min Elevation");
+            ui.add(egui::DragValue::new(&mut self.min_elevation).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Max Elevation");
+            ui.add(egui::DragValue::new(&mut self.max_elevation).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Exaggeration");
+            ui.add(egui::Slider::new(&mut self.exaggeration, RangeInclusive::new(0.1, 10.0)).suffix("x"));
+            ui.end_row();
+        });
+
+        self.max_elevation = self.max_elevation.max(self.min_elevation);
+
+        *self != before
+    }
+}
+
+
+// Bilinear normalized height at a uv in [0, 1], pixel centers sit at half texels
+pub fn sample_height(img: &ColorImage, uv: Vector2<f32>) -> f32 {
+    let (w, h) = (img.width(), img.height());
+    let x = (uv.x * w as f32 - 0.5).clamp(0.0, w as f32 - 1.0);
+    let y = (uv.y * h as f32 - 0.5).clamp(0.0, h as f32 - 1.0);
+
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let top = col_to_height(img[(x0, y0)]) * (1.0 - fx) + col_to_height(img[(x1, y0)]) * fx;
+    let bottom = col_to_height(img[(x0, y1)]) * (1.0 - fx) + col_to_height(img[(x1, y1)]) * fx;
+
+    top * (1.0 - fy) + bottom * fy
+}
+
+// Drawing pixel under a uv, clamped to the image
+pub fn uv_to_pixel(img: &ColorImage, uv: Vector2<f32>) -> Vector2<usize> {
+    Vector2::new(
+        ((uv.x * img.width() as f32) as usize).min(img.width() - 1),
+        ((uv.y * img.height() as f32) as usize).min(img.height() - 1)
+    )
+}
@@ -0,0 +1,49 @@
+#version 330
+
+layout(location = 0) in vec4 vs_pos;
+layout(location = 1) in vec4 vs_col;
+layout(location = 2) in vec2 vs_uv;
+
+out vec4 fs_col;
+out vec2 fs_uv;
+out vec3 fs_nor;
+out float fs_shore;
+
+uniform mat4 u_ViewProj;
+uniform sampler2D u_Height;
+uniform vec2 u_Size;
+uniform vec2 u_Elevation; // min and max elevation
+uniform float u_Exaggeration;
+uniform vec3 u_Shore; // sea level, shore width, enabled
+
+// normalized drawing height, same as col_to_height in drawing.rs
+float height_at(vec2 uv) {
+    vec4 h = textureLod(u_Height, uv, 0.0);
+    return (h.r + h.g + h.b) / 3.0;
+}
+
+// same conversion as TerrainSettings::world_height
+float world_height(float h) {
+    return (u_Elevation.x + h * (u_Elevation.y - u_Elevation.x)) * u_Exaggeration;
+}
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(u_Height, 0));
+
+    float h = height_at(vs_uv);
+
+    // central differences, uv.x runs along world x and uv.y along world z
+    float dx = (world_height(height_at(vs_uv + vec2(texel.x, 0.0))) - world_height(height_at(vs_uv - vec2(texel.x, 0.0)))) / (2.0 * texel.x * u_Size.x);
+    float dz = (world_height(height_at(vs_uv + vec2(0.0, texel.y))) - world_height(height_at(vs_uv - vec2(0.0, texel.y)))) / (2.0 * texel.y * u_Size.y);
+    fs_nor = normalize(vec3(-dx, 1.0, -dz));
+
+    float shade = 0.6 * h + 0.1;
+    fs_col = vec4(shade, shade, shade, 1.0);
+
+    // same tint as WaterSettings::shore_tint, the fragment shader applies it to sampled colors as well
+    float elevation = u_Elevation.x + h * (u_Elevation.y - u_Elevation.x);
+    fs_shore = u_Shore.z > 0.0 && u_Shore.y > 0.0 ? 0.6 * max(1.0 - abs(elevation - u_Shore.x) / u_Shore.y, 0.0) : 0.0;
+    fs_uv = vs_uv;
+
+    gl_Position = u_ViewProj * vec4(vs_pos.x, world_height(h), vs_pos.z, 1.0);
+}
@@ -0,0 +1,95 @@
+use eframe::glow::{self, HasContext as _};
+use egui::ColorImage;
+
+use crate::drawing::DirtyRect;
+
+
+// RGBA8 GL texture mirroring a Drawing, used by the displacement render path
+pub struct GpuTexture {
+    pub texture: glow::Texture,
+    size: [usize; 2]
+}
+
+impl GpuTexture {
+    pub fn new(gl: &glow::Context, img: &ColorImage) -> Self {
+        unsafe {
+            let texture = gl.create_texture().expect("Cannot create texture");
+
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
+            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE as i32);
+            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE as i32);
+            gl.bind_texture(glow::TEXTURE_2D, None);
+
+            let mut x = Self {
+                texture,
+                size: [0, 0]
+            };
+
+            x.upload(gl, img);
+
+            x
+        }
+    }
+
+    // Uploads the whole image, reallocating the storage only if the size changed
+    pub fn upload(&mut self, gl: &glow::Context, img: &ColorImage) {
+        unsafe {
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
+            if self.size == img.size {
+                gl.tex_sub_image_2d(glow::TEXTURE_2D, 0, 0, 0, img.width() as i32, img.height() as i32, glow::RGBA, glow::UNSIGNED_BYTE, glow::PixelUnpackData::Slice(img.as_raw()));
+            } else {
+                gl.tex_image_2d(glow::TEXTURE_2D, 0, glow::RGBA8 as i32, img.width() as i32, img.height() as i32, 0, glow::RGBA, glow::UNSIGNED_BYTE, Some(img.as_raw()));
+                self.size = img.size;
+            }
+            gl.bind_texture(glow::TEXTURE_2D, None);
+        }
+    }
+
+    // Uploads only the pixels inside region, the unpack state lets GL pick the sub rectangle out of the full image
+    pub fn upload_region(&mut self, gl: &glow::Context, img: &ColorImage, region: DirtyRect) {
+        if self.size != img.size {
+            self.upload(gl, img);
+            return;
+        }
+
+        unsafe {
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
+            gl.pixel_store_i32(glow::UNPACK_ROW_LENGTH, img.width() as i32);
+            gl.pixel_store_i32(glow::UNPACK_SKIP_PIXELS, region.min_x as i32);
+            gl.pixel_store_i32(glow::UNPACK_SKIP_ROWS, region.min_y as i32);
+
+            gl.tex_sub_image_2d(
+                glow::TEXTURE_2D, 0,
+                region.min_x as i32, region.min_y as i32,
+                (region.max_x - region.min_x + 1) as i32, (region.max_y - region.min_y + 1) as i32,
+                glow::RGBA, glow::UNSIGNED_BYTE, glow::PixelUnpackData::Slice(img.as_raw())
+            );
+
+            gl.pixel_store_i32(glow::UNPACK_ROW_LENGTH, 0);
+            gl.pixel_store_i32(glow::UNPACK_SKIP_PIXELS, 0);
+            gl.pixel_store_i32(glow::UNPACK_SKIP_ROWS, 0);
+            gl.bind_texture(glow::TEXTURE_2D, None);
+        }
+    }
+
+    pub fn destroy(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_texture(self.texture);
+        }
+    }
+}
+
+
+pub struct TerrainTextures {
+    pub height: GpuTexture,
+    pub colors: GpuTexture
+}
+
+impl TerrainTextures {
+    pub fn destroy(&self, gl: &glow::Context) {
+        self.height.destroy(gl);
+        self.colors.destroy(gl);
+    }
+}
@@ -0,0 +1,256 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, ColorImage, Ui};
+
+use crate::{analysis::{self, AnalysisKind}, drawing::colorimage_from_image, terrain::TerrainSettings};
+
+
+// One layer of the auto texture, rules further down the list paint over earlier ones
+#[derive(Clone)]
+pub struct TextureRule {
+    pub name: String,
+    pub color: Color32,
+    // tiled over the terrain instead of the flat color when set
+    pub material: Option<ColorImage>,
+    pub tile_size: f32,
+    pub height: (f32, f32),
+    pub slope: (f32, f32),
+    // breakup and blend are fractions of the terrain's elevation range for heights and of 90° for slopes
+    pub noise: f32,
+    pub noise_scale: f32,
+    // kept with the rule so reordering or removing rules leaves the other breakup patterns alone
+    pub seed: u32,
+    pub blend: f32
+}
+
+
+impl TextureRule {
+    pub fn new(name: &str, color: Color32, height: (f32, f32), slope: (f32, f32), seed: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            color,
+            material: None,
+            tile_size: 2.0,
+            height,
+            slope,
+            noise: 0.15,
+            noise_scale: 1.0,
+            seed,
+            blend: 0.05
+        }
+    }
+
+    // Sand, grass, rock and snow for the default 0 to 4 m terrain
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::new("Sand", Color32::from_rgb(194, 178, 128), (-10000.0, 0.6), (0.0, 90.0), 0),
+            Self::new("Grass", Color32::from_rgb(70, 120, 50), (0.6, 2.8), (0.0, 30.0), 1),
+            Self::new("Rock", Color32::from_rgb(110, 100, 90), (-10000.0, 10000.0), (35.0, 90.0), 2),
+            Self::new("Snow", Color32::from_rgb(240, 240, 245), (3.0, 10000.0), (0.0, 40.0), 3),
+        ]
+    }
+
+    // A material that fails to load leaves its message in error
+    pub fn ui(&mut self, ui: &mut Ui, id: usize, error: &mut Option<String>) {
+        egui::Grid::new(("Texture Rule Grid", id)).num_columns(2).show(ui, |ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut self.name);
+            ui.end_row();
+
+            ui.label("Color");
+            ui.color_edit_button_srgba(&mut self.color);
+            ui.end_row();
+
+            ui.label("Material");
+            ui.horizontal(|ui| {
+                if ui.button(if self.material.is_some() { "Replace" } else { "Load" }).clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        match colorimage_from_image(path.to_str().unwrap()) {
+                            Ok(img) => {
+                                self.material = Some(img);
+                                *error = None;
+                            },
+                            Err(e) => *error = Some(format!("Failed to load material: {e}")),
+                        }
+                    }
+                }
+                if self.material.is_some() && ui.button("Clear").clicked() {
+                    self.material = None;
+                }
+            });
+            ui.end_row();
+
+            if self.material.is_some() {
+                ui.label("Tile Size");
+                ui.add(egui::DragValue::new(&mut self.tile_size).speed(0.05).range(RangeInclusive::new(0.01, 100000.0)).suffix(" m"));
+                ui.end_row();
+            }
+
+            ui.label("Height");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.height.0).speed(0.05).suffix(" m"));
+                ui.add(egui::DragValue::new(&mut self.height.1).speed(0.05).suffix(" m"));
+            });
+            ui.end_row();
+
+            ui.label("Slope");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.slope.0).speed(0.5).range(RangeInclusive::new(0.0, 90.0)).suffix("°"));
+                ui.add(egui::DragValue::new(&mut self.slope.1).speed(0.5).range(RangeInclusive::new(0.0, 90.0)).suffix("°"));
+            });
+            ui.end_row();
+
+            ui.label("Noise");
+            ui.add(egui::Slider::new(&mut self.noise, RangeInclusive::new(0.0, 1.0)));
+            ui.end_row();
+
+            ui.label("Noise Scale");
+            ui.add(egui::DragValue::new(&mut self.noise_scale).speed(0.05).range(RangeInclusive::new(0.01, 100000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Noise Seed");
+            ui.add(egui::DragValue::new(&mut self.seed));
+            ui.end_row();
+
+            ui.label("Blend");
+            ui.add(egui::Slider::new(&mut self.blend, RangeInclusive::new(0.0, 0.5)));
+            ui.end_row();
+        });
+
+        self.height.1 = self.height.1.max(self.height.0);
+        self.slope.1 = self.slope.1.max(self.slope.0);
+    }
+
+    // Coverage in [0, 1] for an elevation and slope, the breakup noise moves the value across the range borders
+    fn weight(&self, settings: &TerrainSettings, elevation: f32, slope: f32, breakup: f32) -> f32 {
+        let height_scale = settings.max_elevation - settings.min_elevation;
+        let shift = self.noise * (breakup - 0.5);
+        range_weight(elevation + shift * height_scale, self.height, self.blend * height_scale)
+            * range_weight(slope + shift * 90.0, self.slope, self.blend * 90.0)
+    }
+
+    fn color_at(&self, settings: &TerrainSettings, u: f32, v: f32) -> Color32 {
+        match &self.material {
+            Some(material) => {
+                let tx = (u * settings.width / self.tile_size).rem_euclid(1.0);
+                let ty = (v * settings.length / self.tile_size).rem_euclid(1.0);
+                let x = ((tx * material.width() as f32) as usize).min(material.width() - 1);
+                let y = ((ty * material.height() as f32) as usize).min(material.height() - 1);
+                material[(x, y)]
+            },
+            None => self.color,
+        }
+    }
+}
+
+
+// 1 inside the range, smoothly falling to 0 over width on either side
+pub fn range_weight(value: f32, (lo, hi): (f32, f32), width: f32) -> f32 {
+    let width = width.max(1e-6);
+    let outside = (lo - value).max(value - hi).max(0.0);
+    let t = (1.0 - outside / width).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+
+fn hash(x: i32, y: i32, seed: u32) -> f32 {
+    let mut n = (x as u32).wrapping_mul(374761393) ^ (y as u32).wrapping_mul(668265263) ^ seed.wrapping_mul(2246822519);
+    n = (n ^ (n >> 13)).wrapping_mul(1274126177);
+    (n ^ (n >> 16)) as f32 / u32::MAX as f32
+}
+
+
+// Smooth value noise in [0, 1] with three octaves
+pub fn value_noise(x: f32, y: f32, seed: u32) -> f32 {
+    let mut total = 0.0;
+    let mut amplitude = 0.5;
+    let (mut x, mut y) = (x, y);
+
+    for octave in 0..3 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (sx, sy) = (fx * fx * (3.0 - 2.0 * fx), fy * fy * (3.0 - 2.0 * fy));
+        let (ix, iy) = (x0 as i32, y0 as i32);
+        let s = seed.wrapping_add(octave);
+
+        let top = hash(ix, iy, s) * (1.0 - sx) + hash(ix + 1, iy, s) * sx;
+        let bottom = hash(ix, iy + 1, s) * (1.0 - sx) + hash(ix + 1, iy + 1, s) * sx;
+        total += amplitude * (top * (1.0 - sy) + bottom * sy);
+
+        amplitude *= 0.5;
+        x *= 2.0;
+        y *= 2.0;
+    }
+
+    total / 0.875
+}
+
+
+// Rules panel, returns true when the colors should be regenerated
+pub fn rules_ui(ui: &mut Ui, rules: &mut Vec<TextureRule>, error: &mut Option<String>) -> bool {
+    let mut remove = None;
+    let mut swap = None;
+
+    for (i, rule) in rules.iter_mut().enumerate() {
+        egui::CollapsingHeader::new(format!("{}. {}", i + 1, rule.name)).id_salt(("Texture Rule", i)).show(ui, |ui| {
+            rule.ui(ui, i, error);
+            ui.horizontal(|ui| {
+                if ui.small_button("Up").clicked() && i > 0 {
+                    swap = Some(i - 1);
+                }
+                if ui.small_button("Down").clicked() {
+                    swap = Some(i);
+                }
+                if ui.small_button("Remove").clicked() {
+                    remove = Some(i);
+                }
+            });
+        });
+    }
+
+    if let Some(i) = swap.filter(|i| i + 1 < rules.len()) {
+        rules.swap(i, i + 1);
+    }
+    if let Some(i) = remove {
+        rules.remove(i);
+    }
+
+    let mut generate = false;
+    ui.horizontal(|ui| {
+        if ui.button("Add Rule").clicked() {
+            let seed = rules.iter().map(|r| r.seed.wrapping_add(1)).max().unwrap_or(0);
+            rules.push(TextureRule::new("Rule", Color32::GRAY, (-10000.0, 10000.0), (0.0, 90.0), seed));
+        }
+        generate = ui.button("Generate Colors").on_hover_text("Replaces the color drawing, paint over it afterwards").clicked();
+    });
+
+    generate
+}
+
+
+// Color drawing from the height drawing, every rule is blended over the ones before it
+pub fn generate_colors(settings: &TerrainSettings, img: &ColorImage, rules: &[TextureRule]) -> ColorImage {
+    let (w, h) = (img.width(), img.height());
+    let elevations = settings.elevations(img);
+    let slopes = analysis::compute(settings, img, AnalysisKind::Slope);
+
+    let mut pixels = vec![Color32::from_gray(128); w * h];
+    for rule in rules.iter() {
+        for y in 0..h {
+            for x in 0..w {
+                let i = y * w + x;
+                let (u, v) = ((x as f32 + 0.5) / w as f32, (y as f32 + 0.5) / h as f32);
+                let breakup = value_noise(u * settings.width / rule.noise_scale, v * settings.length / rule.noise_scale, rule.seed);
+                let weight = rule.weight(settings, elevations[i], slopes[i], breakup);
+                if weight > 0.0 {
+                    pixels[i] = pixels[i].lerp_to_gamma(rule.color_at(settings, u, v), weight);
+                }
+            }
+        }
+    }
+
+    ColorImage {
+        size: img.size,
+        pixels
+    }
+}
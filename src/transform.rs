@@ -0,0 +1,383 @@
+use egui::{Color32, ColorImage, Ui};
+use nalgebra::{Vector2, Vector4};
+
+use crate::{drawing::cubic_weight, selection::Selection, terrain::TerrainSettings};
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    Bicubic
+}
+
+
+impl ResizeFilter {
+    pub const ALL: [ResizeFilter; 3] = [ResizeFilter::Nearest, ResizeFilter::Bilinear, ResizeFilter::Bicubic];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "Nearest",
+            ResizeFilter::Bilinear => "Bilinear",
+            ResizeFilter::Bicubic => "Bicubic",
+        }
+    }
+}
+
+
+// Whole canvas operation, applied the same way to every height and color layer
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CanvasTransform {
+    // clockwise
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    // top left corner and size in pixels
+    Crop { min: [usize; 2], size: [usize; 2] },
+    Resize { size: [usize; 2], filter: ResizeFilter },
+    // pixels, whatever leaves one edge comes back in on the other
+    Offset { x: i64, y: i64 }
+}
+
+
+impl CanvasTransform {
+    pub fn output_size(&self, size: [usize; 2]) -> [usize; 2] {
+        match self {
+            CanvasTransform::Rotate90 | CanvasTransform::Rotate270 => [size[1], size[0]],
+            CanvasTransform::Crop { size, .. } | CanvasTransform::Resize { size, .. } => *size,
+            _ => size,
+        }
+    }
+
+    // Pixel of the input that ends up at (x, y) of the output, resizing goes through sample instead
+    fn source(&self, x: usize, y: usize, size: [usize; 2]) -> (usize, usize) {
+        let [w, h] = size;
+        match *self {
+            CanvasTransform::Rotate90 => (y, h - 1 - x),
+            CanvasTransform::Rotate180 => (w - 1 - x, h - 1 - y),
+            CanvasTransform::Rotate270 => (w - 1 - y, x),
+            CanvasTransform::FlipHorizontal => (w - 1 - x, y),
+            CanvasTransform::FlipVertical => (x, h - 1 - y),
+            CanvasTransform::Crop { min, .. } => ((min[0] + x).min(w - 1), (min[1] + y).min(h - 1)),
+            CanvasTransform::Resize { size: out, .. } => (((x as f32 + 0.5) * w as f32 / out[0] as f32) as usize, ((y as f32 + 0.5) * h as f32 / out[1] as f32) as usize),
+            CanvasTransform::Offset { x: dx, y: dy } => ((x as i64 - dx).rem_euclid(w as i64) as usize, (y as i64 - dy).rem_euclid(h as i64) as usize),
+        }
+    }
+
+    pub fn apply(&self, img: &ColorImage) -> ColorImage {
+        let out = self.output_size(img.size);
+        let pixels = match self {
+            CanvasTransform::Resize { filter: ResizeFilter::Bilinear | ResizeFilter::Bicubic, .. } => {
+                let (sx, sy) = (img.width() as f32 / out[0] as f32, img.height() as f32 / out[1] as f32);
+                (0..out[0] * out[1]).map(|i| {
+                    let (x, y) = (i % out[0], i / out[0]);
+                    sample(img, (x as f32 + 0.5) * sx - 0.5, (y as f32 + 0.5) * sy - 0.5, matches!(self, CanvasTransform::Resize { filter: ResizeFilter::Bicubic, .. }))
+                }).collect()
+            },
+            _ => self.map_pixels(&img.pixels, img.size),
+        };
+        ColorImage { size: out, pixels }
+    }
+
+    // Moves row major values of a size[0] x size[1] grid, resizing picks the nearest value
+    pub fn map_pixels<T: Copy>(&self, values: &[T], size: [usize; 2]) -> Vec<T> {
+        let out = self.output_size(size);
+        (0..out[0] * out[1]).map(|i| {
+            let (x, y) = self.source(i % out[0], i / out[0], size);
+            values[y.min(size[1] - 1) * size[0] + x.min(size[0] - 1)]
+        }).collect()
+    }
+
+    // Where a canvas position ends up. Offsets are not wrapped so paths drawn over the seam keep their shape
+    pub fn map_uv(&self, uv: Vector2<f32>, size: [usize; 2]) -> Vector2<f32> {
+        match *self {
+            CanvasTransform::Rotate90 => Vector2::new(1.0 - uv.y, uv.x),
+            CanvasTransform::Rotate180 => Vector2::new(1.0 - uv.x, 1.0 - uv.y),
+            CanvasTransform::Rotate270 => Vector2::new(uv.y, 1.0 - uv.x),
+            CanvasTransform::FlipHorizontal => Vector2::new(1.0 - uv.x, uv.y),
+            CanvasTransform::FlipVertical => Vector2::new(uv.x, 1.0 - uv.y),
+            CanvasTransform::Crop { min, size: out } => Vector2::new(
+                (uv.x * size[0] as f32 - min[0] as f32) / out[0] as f32,
+                (uv.y * size[1] as f32 - min[1] as f32) / out[1] as f32
+            ),
+            CanvasTransform::Resize { .. } => uv,
+            CanvasTransform::Offset { x, y } => uv + Vector2::new(x as f32 / size[0] as f32, y as f32 / size[1] as f32),
+        }
+    }
+
+    // Where a pixel ends up, None when it is cropped away
+    pub fn map_pixel(&self, x: usize, y: usize, size: [usize; 2]) -> Option<(usize, usize)> {
+        let out = self.output_size(size);
+        let (x, y) = match *self {
+            CanvasTransform::Crop { min, .. } => (x.checked_sub(min[0])?, y.checked_sub(min[1])?),
+            CanvasTransform::Offset { x: dx, y: dy } => ((x as i64 + dx).rem_euclid(size[0] as i64) as usize, (y as i64 + dy).rem_euclid(size[1] as i64) as usize),
+            _ => {
+                let uv = self.map_uv(Vector2::new((x as f32 + 0.5) / size[0] as f32, (y as f32 + 0.5) / size[1] as f32), size);
+                ((uv.x * out[0] as f32).max(0.0) as usize, (uv.y * out[1] as f32).max(0.0) as usize)
+            },
+        };
+        (x < out[0] && y < out[1]).then_some((x, y))
+    }
+
+    // Keeps the size of a pixel on the ground: rotating swaps the sides and cropping shrinks them
+    pub fn apply_terrain(&self, terrain: &mut TerrainSettings, size: [usize; 2]) {
+        match self {
+            CanvasTransform::Rotate90 | CanvasTransform::Rotate270 => (terrain.width, terrain.length) = (terrain.length, terrain.width),
+            CanvasTransform::Crop { size: out, .. } => {
+                terrain.width *= out[0] as f32 / size[0] as f32;
+                terrain.length *= out[1] as f32 / size[1] as f32;
+            },
+            _ => {},
+        }
+    }
+}
+
+
+// Interpolates premultiplied rgba at a continuous pixel position so transparent layer pixels do not bleed
+fn sample(img: &ColorImage, src_x: f32, src_y: f32, bicubic: bool) -> Color32 {
+    let (w, h) = (img.width() as i64, img.height() as i64);
+    let pixel = |x: i64, y: i64| {
+        let px = img.pixels[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize];
+        Vector4::new(px.r() as f32, px.g() as f32, px.b() as f32, px.a() as f32)
+    };
+    let (x0, y0) = (src_x.floor(), src_y.floor());
+    let (fx, fy) = (src_x - x0, src_y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let mut result = Vector4::zeros();
+    if bicubic {
+        for j in -1..3 {
+            for i in -1..3 {
+                let weight = cubic_weight((i as f32 - fx).abs()) * cubic_weight((j as f32 - fy).abs());
+                result += pixel(x0 + i, y0 + j) * weight;
+            }
+        }
+    } else {
+        let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1, y0) * fx;
+        let bottom = pixel(x0, y0 + 1) * (1.0 - fx) + pixel(x0 + 1, y0 + 1) * fx;
+        result = top * (1.0 - fy) + bottom * fy;
+    }
+
+    // bicubic overshoot can leave a channel above the coverage, which premultiplied colors cannot hold
+    let a = result.w.round().clamp(0.0, 255.0);
+    let channel = |c: f32| c.round().clamp(0.0, a) as u8;
+    Color32::from_rgba_premultiplied(channel(result.x), channel(result.y), channel(result.z), a as u8)
+}
+
+
+pub struct TransformSettings {
+    pub crop_min: [usize; 2],
+    pub crop_size: [usize; 2],
+    pub resize: [usize; 2],
+    pub keep_aspect: bool,
+    pub filter: ResizeFilter,
+    pub offset: [i64; 2]
+}
+
+
+impl TransformSettings {
+    pub fn default() -> Self {
+        Self {
+            crop_min: [0, 0],
+            crop_size: [512, 512],
+            resize: [512, 512],
+            keep_aspect: true,
+            filter: ResizeFilter::Bicubic,
+            offset: [0, 0]
+        }
+    }
+
+    // Returns the transform of the button that was clicked, size is the current canvas size in pixels
+    pub fn ui(&mut self, ui: &mut Ui, size: [usize; 2], selection: Option<&Selection>) -> Option<CanvasTransform> {
+        let mut transform = None;
+        ui.label(format!("Canvas {} x {} px", size[0], size[1]));
+
+        ui.horizontal(|ui| {
+            if ui.button("Rotate 90° CW").clicked() {
+                transform = Some(CanvasTransform::Rotate90);
+            }
+            if ui.button("Rotate 90° CCW").clicked() {
+                transform = Some(CanvasTransform::Rotate270);
+            }
+            if ui.button("Rotate 180°").clicked() {
+                transform = Some(CanvasTransform::Rotate180);
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Flip Horizontal").clicked() {
+                transform = Some(CanvasTransform::FlipHorizontal);
+            }
+            if ui.button("Flip Vertical").clicked() {
+                transform = Some(CanvasTransform::FlipVertical);
+            }
+        });
+        ui.separator();
+
+        egui::Grid::new("Canvas Transform Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Crop Corner");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.crop_min[0]).range(0..=size[0] - 1).suffix(" px"));
+                ui.add(egui::DragValue::new(&mut self.crop_min[1]).range(0..=size[1] - 1).suffix(" px"));
+            });
+            ui.end_row();
+
+            ui.label("Crop Size");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.crop_size[0]).range(1..=size[0] - self.crop_min[0].min(size[0] - 1)).suffix(" px"));
+                ui.add(egui::DragValue::new(&mut self.crop_size[1]).range(1..=size[1] - self.crop_min[1].min(size[1] - 1)).suffix(" px"));
+            });
+            ui.end_row();
+        });
+        ui.horizontal(|ui| {
+            if ui.add_enabled(selection.is_some(), egui::Button::new("From Selection")).clicked() {
+                if let Some((min, max)) = selection.map(|s| s.bounds()) {
+                    let to_px = |v: f32, n: usize| ((v.clamp(0.0, 1.0) * n as f32).round() as usize).min(n);
+                    self.crop_min = [to_px(min.x, size[0]).min(size[0] - 1), to_px(min.y, size[1]).min(size[1] - 1)];
+                    self.crop_size = [
+                        (to_px(max.x, size[0]) - self.crop_min[0].min(to_px(max.x, size[0]))).max(1),
+                        (to_px(max.y, size[1]) - self.crop_min[1].min(to_px(max.y, size[1]))).max(1)
+                    ];
+                }
+            }
+            if ui.button("Crop").on_hover_text("The terrain shrinks with the canvas so the ground scale stays the same").clicked() {
+                let min = [self.crop_min[0].min(size[0] - 1), self.crop_min[1].min(size[1] - 1)];
+                let crop = [self.crop_size[0].clamp(1, size[0] - min[0]), self.crop_size[1].clamp(1, size[1] - min[1])];
+                transform = Some(CanvasTransform::Crop { min, size: crop });
+            }
+        });
+        ui.separator();
+
+        egui::Grid::new("Canvas Resize Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Size");
+            ui.horizontal(|ui| {
+                let before = self.resize;
+                ui.add(egui::DragValue::new(&mut self.resize[0]).range(8..=8192).suffix(" px"));
+                ui.add(egui::DragValue::new(&mut self.resize[1]).range(8..=8192).suffix(" px"));
+                if self.keep_aspect && self.resize[0] != before[0] {
+                    self.resize[1] = ((self.resize[0] * size[1]) as f32 / size[0] as f32).round().max(8.0) as usize;
+                } else if self.keep_aspect && self.resize[1] != before[1] {
+                    self.resize[0] = ((self.resize[1] * size[0]) as f32 / size[1] as f32).round().max(8.0) as usize;
+                }
+            });
+            ui.end_row();
+
+            ui.label("Keep Aspect");
+            ui.checkbox(&mut self.keep_aspect, "");
+            ui.end_row();
+
+            ui.label("Filter");
+            egui::ComboBox::from_id_salt("Resize Filter").selected_text(self.filter.name()).show_ui(ui, |ui| {
+                for filter in ResizeFilter::ALL {
+                    ui.selectable_value(&mut self.filter, filter, filter.name());
+                }
+            });
+            ui.end_row();
+        });
+        if ui.add_enabled(self.resize != size, egui::Button::new("Resize")).on_hover_text("The terrain keeps its size, only the resolution changes").clicked() {
+            transform = Some(CanvasTransform::Resize { size: self.resize, filter: self.filter });
+        }
+        ui.separator();
+
+        egui::Grid::new("Canvas Offset Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Offset");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.offset[0]).range(-(size[0] as i64)..=size[0] as i64).suffix(" px"));
+                ui.add(egui::DragValue::new(&mut self.offset[1]).range(-(size[1] as i64)..=size[1] as i64).suffix(" px"));
+            });
+            ui.end_row();
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Half").on_hover_text("Brings the edges to the middle to check that the terrain tiles").clicked() {
+                self.offset = [size[0] as i64 / 2, size[1] as i64 / 2];
+            }
+            if ui.add_enabled(self.offset != [0, 0], egui::Button::new("Offset")).clicked() {
+                transform = Some(CanvasTransform::Offset { x: self.offset[0], y: self.offset[1] });
+            }
+        });
+
+        transform
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: [usize; 2] = [5, 3];
+
+    fn values() -> Vec<usize> {
+        (0..SIZE[0] * SIZE[1]).collect()
+    }
+
+    fn all() -> Vec<CanvasTransform> {
+        vec![
+            CanvasTransform::Rotate90,
+            CanvasTransform::Rotate180,
+            CanvasTransform::Rotate270,
+            CanvasTransform::FlipHorizontal,
+            CanvasTransform::FlipVertical,
+            CanvasTransform::Crop { min: [1, 1], size: [3, 2] },
+            CanvasTransform::Resize { size: [10, 6], filter: ResizeFilter::Nearest },
+            CanvasTransform::Offset { x: 2, y: -1 },
+        ]
+    }
+
+    #[test]
+    fn four_quarter_turns_are_identity() {
+        let mut v = values();
+        let mut size = SIZE;
+        for _ in 0..4 {
+            v = CanvasTransform::Rotate90.map_pixels(&v, size);
+            size = CanvasTransform::Rotate90.output_size(size);
+        }
+        assert_eq!((v, size), (values(), SIZE));
+
+        let turned = CanvasTransform::Rotate90.map_pixels(&values(), SIZE);
+        assert_eq!(CanvasTransform::Rotate270.map_pixels(&turned, [3, 5]), values());
+        for flip in [CanvasTransform::Rotate180, CanvasTransform::FlipHorizontal, CanvasTransform::FlipVertical] {
+            assert_eq!(flip.map_pixels(&flip.map_pixels(&values(), SIZE), SIZE), values());
+        }
+    }
+
+    #[test]
+    fn offset_wraps_around_the_edges() {
+        let moved = CanvasTransform::Offset { x: 2, y: -1 }.map_pixels(&values(), SIZE);
+        // the top left pixel moves right by two and wraps from the top row to the bottom one
+        assert_eq!(moved[2 * SIZE[0] + 2], 0);
+        // the second row comes up to the top
+        assert_eq!(moved[SIZE[0] - 1], SIZE[0] + 2);
+
+        let whole = CanvasTransform::Offset { x: SIZE[0] as i64, y: -(SIZE[1] as i64) };
+        assert_eq!(whole.map_pixels(&values(), SIZE), values());
+    }
+
+    #[test]
+    fn map_pixel_matches_map_pixels() {
+        for transform in all() {
+            let out = transform.output_size(SIZE);
+            let moved = transform.map_pixels(&values(), SIZE);
+            for (i, value) in values().into_iter().enumerate() {
+                let (x, y) = (i % SIZE[0], i / SIZE[0]);
+                match (transform, transform.map_pixel(x, y, SIZE)) {
+                    (_, Some((ox, oy))) => assert_eq!(moved[oy * out[0] + ox], value),
+                    (CanvasTransform::Crop { min, size }, None) => assert!(x < min[0] || y < min[1] || x >= min[0] + size[0] || y >= min[1] + size[1]),
+                    _ => panic!("pixel ({x}, {y}) lost"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_resize_to_the_same_size_is_identity() {
+        let img = ColorImage {
+            size: SIZE,
+            pixels: values().into_iter().map(|v| Color32::from_gray((v * 17) as u8)).collect()
+        };
+        for filter in [ResizeFilter::Bilinear, ResizeFilter::Bicubic] {
+            assert!(CanvasTransform::Resize { size: SIZE, filter }.apply(&img) == img);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::ops::RangeInclusive;
+
+use egui::{Color32, Ui};
+use nalgebra::{Vector2, Vector3};
+
+use crate::{drawing, mesh::Geometry, terrain::TerrainSettings};
+
+
+const SHORE_COLOR: Color32 = Color32::from_rgb(194, 178, 128);
+
+
+// What exporters do with terrain below the sea level
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SeaLevelExport {
+    Keep,
+    Clamp,
+    Cut
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct WaterSettings {
+    pub enabled: bool,
+    pub sea_level: f32,
+    pub color: Color32,
+    pub opacity: f32,
+    pub shore_width: f32,
+    pub export: SeaLevelExport
+}
+
+
+impl WaterSettings {
+    pub fn default() -> Self {
+        Self {
+            enabled: false,
+            sea_level: 1.0,
+            color: Color32::from_rgb(40, 100, 170),
+            opacity: 0.6,
+            shore_width: 0.15,
+            export: SeaLevelExport::Keep
+        }
+    }
+
+    // Blends color towards sand for terrain within shore_width metres of the sea level
+    pub fn shore_tint(&self, color: Color32, elevation: f32) -> Color32 {
+        if !self.enabled || self.shore_width <= 0.0 {
+            return color;
+        }
+
+        let d = (elevation - self.sea_level).abs();
+        if d >= self.shore_width {
+            return color;
+        }
+
+        color.lerp_to_gamma(SHORE_COLOR, 0.6 * (1.0 - d / self.shore_width))
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.enabled, "Show Water");
+
+        egui::Grid::new("Water Settings Grid").num_columns(2).show(ui, |ui| {
+            ui.label("Sea Level");
+            ui.add(egui::DragValue::new(&mut self.sea_level).speed(0.05).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Shore Width");
+            ui.add(egui::DragValue::new(&mut self.shore_width).speed(0.01).range(RangeInclusive::new(0.0, 10000.0)).suffix(" m"));
+            ui.end_row();
+
+            ui.label("Color");
+            ui.color_edit_button_srgba(&mut self.color);
+            ui.end_row();
+
+            ui.label("Opacity");
+            ui.add(egui::Slider::new(&mut self.opacity, RangeInclusive::new(0.0, 1.0)));
+            ui.end_row();
+        });
+
+        ui.label("Below sea level on export");
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.export, SeaLevelExport::Keep, "Keep");
+            ui.radio_value(&mut self.export, SeaLevelExport::Clamp, "Clamp");
+            ui.radio_value(&mut self.export, SeaLevelExport::Cut, "Cut");
+        });
+    }
+}
+
+
+// Single translucent quad covering the terrain at the sea level
+pub fn generate_water_plane(settings: &TerrainSettings) -> Geometry {
+    let (w, l) = (settings.width / 2.0, settings.length / 2.0);
+    let y = settings.water.sea_level * settings.exaggeration;
+    let color = drawing::with_alpha(settings.water.color, settings.water.opacity);
+
+    Geometry {
+        positions: vec![
+            Vector3::new(-w, y, -l),
+            Vector3::new(w, y, -l),
+            Vector3::new(w, y, l),
+            Vector3::new(-w, y, l)
+        ],
+        indicies: vec![0, 1, 2, 0, 2, 3],
+        uvs: vec![
+            Vector2::new(0.0, 0.0),
+            Vector2::new(1.0, 0.0),
+            Vector2::new(1.0, 1.0),
+            Vector2::new(0.0, 1.0)
+        ],
+        colors: vec![color; 4]
+    }
+}
+
+
+// Fraction of the heightmap below and above the sea level
+pub fn water_coverage(settings: &TerrainSettings, img: &egui::ColorImage) -> (f32, f32) {
+    let under = img.pixels.iter().filter(|px| settings.elevation(drawing::col_to_height(**px)) < settings.water.sea_level).count();
+    let total = img.pixels.len().max(1) as f32;
+    (under as f32 / total, (img.pixels.len() - under) as f32 / total)
+}
+
+
+// Applies the export mode to heights in [0, 1]. Clamp raises everything under water to the sea level,
+// Cut drops it to the bottom so it can be keyed out
+pub fn apply_sea_level(settings: &TerrainSettings, heights: &mut [f32]) {
+    let sea = settings.normalized_height(settings.water.sea_level);
+
+    match settings.water.export {
+        SeaLevelExport::Keep => {},
+        SeaLevelExport::Clamp => heights.iter_mut().for_each(|h| *h = h.max(sea)),
+        SeaLevelExport::Cut => heights.iter_mut().filter(|h| **h < sea).for_each(|h| *h = 0.0),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // default terrain spans 0 - 4 m, a 1 m sea sits at a quarter of the height range
+    fn heights() -> Vec<f32> {
+        vec![0.0, 0.1, 0.25, 0.5, 1.0, 0.2]
+    }
+
+    #[test]
+    fn sea_level_keeps_clamps_and_cuts() {
+        let mut settings = TerrainSettings::default();
+        settings.water.sea_level = 1.0;
+
+        let mut kept = heights();
+        apply_sea_level(&settings, &mut kept);
+        assert_eq!(kept, heights());
+
+        settings.water.export = SeaLevelExport::Clamp;
+        let mut clamped = heights();
+        apply_sea_level(&settings, &mut clamped);
+        assert_eq!(clamped, vec![0.25, 0.25, 0.25, 0.5, 1.0, 0.25]);
+
+        settings.water.export = SeaLevelExport::Cut;
+        let mut cut = heights();
+        apply_sea_level(&settings, &mut cut);
+        assert_eq!(cut, vec![0.0, 0.0, 0.25, 0.5, 1.0, 0.0]);
+    }
+}